@@ -0,0 +1,76 @@
+//! Single-core scanning throughput.
+//!
+//! Run with `cargo bench`; results are reported in bytes per second.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use s3grep::line_matches;
+use s3grep::scan::{LineScanner, Matcher, READ_BUFFER};
+
+/// Generates roughly `size` bytes of access-log-like lines, one in 1000 containing "ERROR".
+fn sample_log(size: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size + 256);
+    let mut i = 0;
+    while data.len() < size {
+        let level = if i % 1000 == 0 { "ERROR" } else { "INFO" };
+        data.extend_from_slice(
+            format!(
+                "2024-01-01T00:00:{:02}Z {level} request_id={i:08x} GET /api/v1/items/{} 200 {}ms\n",
+                i % 60,
+                i % 977,
+                i % 250
+            )
+            .as_bytes(),
+        );
+        i += 1;
+    }
+    data
+}
+
+/// The previous approach: copy each line, convert it to a String and test it.
+fn per_line(data: &[u8], pattern: &str, case_sensitive: bool) -> usize {
+    let mut matches = 0;
+    let mut line = Vec::new();
+    for chunk in data.chunks(READ_BUFFER) {
+        for &byte in chunk {
+            if byte == b'\n' {
+                if line_matches(&String::from_utf8_lossy(&line), pattern, case_sensitive) {
+                    matches += 1;
+                }
+                line.clear();
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+    matches
+}
+
+fn scanner(data: &[u8], pattern: &str, case_sensitive: bool) -> usize {
+    let mut matches = 0;
+    let mut scanner = LineScanner::new(Matcher::new(pattern, case_sensitive));
+    for chunk in data.chunks(READ_BUFFER) {
+        scanner.feed(chunk, |_, _| matches += 1);
+    }
+    scanner.finish(|_, _| matches += 1);
+    matches
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let data = sample_log(16 * 1024 * 1024);
+    let mut group = c.benchmark_group("scan");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(20);
+
+    for (name, case_sensitive) in [("case-sensitive", true), ("case-insensitive", false)] {
+        group.bench_with_input(BenchmarkId::new("per-line", name), &data, |b, data| {
+            b.iter(|| per_line(black_box(data), "ERROR", case_sensitive))
+        });
+        group.bench_with_input(BenchmarkId::new("scanner", name), &data, |b, data| {
+            b.iter(|| scanner(black_box(data), "ERROR", case_sensitive))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
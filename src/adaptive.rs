@@ -0,0 +1,267 @@
+//! Adaptive concurrency control.
+//!
+//! A [`Controller`] looks at the aggregate throughput, throttling responses and
+//! request latency seen over each interval and picks a new concurrency limit,
+//! which a [`Limiter`] then enforces on the object searches. Concurrency grows
+//! while throughput keeps improving and is cut back on `SlowDown` responses or
+//! latency spikes.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Throughput must improve by this fraction for concurrency to keep growing.
+const GROWTH_THRESHOLD: f64 = 0.05;
+
+/// A mean latency this many times the best seen so far counts as a spike.
+const LATENCY_SPIKE: f64 = 3.0;
+
+/// What was observed over one interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    /// Bytes processed per second
+    pub bytes_per_sec: f64,
+    /// Number of throttling (503 `SlowDown`) responses
+    pub throttled: u64,
+    /// Mean time to the response headers, if any responses arrived
+    pub latency: Option<Duration>,
+}
+
+/// Chooses the concurrency limit from interval samples (AIMD-style).
+#[derive(Debug, Clone)]
+pub struct Controller {
+    limit: usize,
+    min: usize,
+    max: usize,
+    last_throughput: f64,
+    best_latency: Option<Duration>,
+}
+
+impl Controller {
+    /// Creates a controller starting at `initial`, kept within `min..=max`.
+    pub fn new(initial: usize, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Controller {
+            limit: initial.clamp(min, max),
+            min,
+            max,
+            last_throughput: 0.0,
+            best_latency: None,
+        }
+    }
+
+    /// The current concurrency limit.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /**
+        Adjusts the limit after an interval and returns the new limit.
+
+        Throttling halves the limit and a latency spike cuts it by a quarter.
+        Otherwise the limit grows by a quarter (at least one) as long as
+        throughput improved on the previous interval, and holds when it did not.
+
+        # Examples
+
+        ```
+        use s3grep::adaptive::{Controller, Sample};
+        let mut controller = Controller::new(8, 1, 64);
+        let sample = |bytes_per_sec, throttled| Sample { bytes_per_sec, throttled, latency: None };
+        assert_eq!(controller.adjust(sample(100.0, 0)), 10);
+        assert_eq!(controller.adjust(sample(150.0, 0)), 12);
+        assert_eq!(controller.adjust(sample(150.0, 0)), 12);
+        assert_eq!(controller.adjust(sample(150.0, 3)), 6);
+        ```
+    */
+    pub fn adjust(&mut self, sample: Sample) -> usize {
+        let spike = match (sample.latency, self.best_latency) {
+            (Some(latency), Some(best)) => {
+                latency.as_secs_f64() > best.as_secs_f64() * LATENCY_SPIKE
+            }
+            _ => false,
+        };
+        if let Some(latency) = sample.latency {
+            self.best_latency = Some(self.best_latency.map_or(latency, |best| best.min(latency)));
+        }
+
+        self.limit = if sample.throttled > 0 {
+            self.limit / 2
+        } else if spike {
+            self.limit - self.limit / 4
+        } else if sample.bytes_per_sec > self.last_throughput * (1.0 + GROWTH_THRESHOLD) {
+            self.limit + (self.limit / 4).max(1)
+        } else {
+            self.limit
+        }
+        .clamp(self.min, self.max);
+
+        self.last_throughput = sample.bytes_per_sec;
+        self.limit
+    }
+}
+
+/// Throttling and latency signals collected from S3 responses.
+#[derive(Debug, Default)]
+pub struct Signals {
+    throttled: AtomicU64,
+    responses: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+impl Signals {
+    /// Records a response with its time to headers and whether it was throttled.
+    pub fn record(&self, latency: Duration, throttled: bool) {
+        if throttled {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        self.latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the throttle count and mean latency since the last call, and resets them.
+    pub fn take(&self) -> (u64, Option<Duration>) {
+        let throttled = self.throttled.swap(0, Ordering::Relaxed);
+        let responses = self.responses.swap(0, Ordering::Relaxed);
+        let latency_micros = self.latency_micros.swap(0, Ordering::Relaxed);
+        let latency = (responses > 0).then(|| Duration::from_micros(latency_micros / responses));
+        (throttled, latency)
+    }
+}
+
+/// A semaphore whose number of permits can be changed while permits are held.
+///
+/// Shrinking below the number of permits in use takes effect as they are returned.
+#[derive(Debug)]
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    limit: AtomicUsize,
+    /// Permits to retire when they are returned
+    debt: Arc<AtomicUsize>,
+}
+
+/// A permit from a [`Limiter`], returned when dropped.
+#[derive(Debug)]
+pub struct LimiterPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    debt: Arc<AtomicUsize>,
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        let retire = self
+            .debt
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok();
+        if let (true, Some(permit)) = (retire, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+impl Limiter {
+    /// Creates a limiter allowing `limit` permits at once.
+    pub fn new(limit: usize) -> Self {
+        Limiter {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit: AtomicUsize::new(limit),
+            debt: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Waits for a permit.
+    pub async fn acquire(&self) -> LimiterPermit {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("limiter semaphore is never closed");
+        LimiterPermit {
+            permit: Some(permit),
+            debt: self.debt.clone(),
+        }
+    }
+
+    /// Changes the number of permits.
+    pub fn set_limit(&self, limit: usize) {
+        let current = self.limit.swap(limit, Ordering::AcqRel);
+        if limit > current {
+            // Cancel pending retirements before adding new permits
+            let mut grow = limit - current;
+            let cancelled = self
+                .debt
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |debt| {
+                    Some(debt.saturating_sub(grow))
+                })
+                .unwrap_or_default()
+                .min(grow);
+            grow -= cancelled;
+            self.semaphore.add_permits(grow);
+        } else if limit < current {
+            let shrink = current - limit;
+            let forgotten = self.semaphore.forget_permits(shrink);
+            self.debt.fetch_add(shrink - forgotten, Ordering::AcqRel);
+        }
+    }
+
+    /// Number of permits currently free.
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_controller_backs_off_on_latency_spike() {
+        let mut controller = Controller::new(16, 2, 32);
+        let sample = |latency_ms| Sample {
+            bytes_per_sec: 100.0,
+            throttled: 0,
+            latency: Some(Duration::from_millis(latency_ms)),
+        };
+        assert_eq!(controller.adjust(sample(20)), 20);
+        assert_eq!(controller.adjust(sample(100)), 15);
+        assert_eq!(controller.adjust(sample(20)), 15);
+
+        let mut controller = Controller::new(3, 2, 32);
+        let throttled = Sample {
+            throttled: 1,
+            ..Sample::default()
+        };
+        assert_eq!(controller.adjust(throttled), 2);
+        assert_eq!(controller.limit(), 2);
+    }
+
+    #[test]
+    fn test_signals_take_resets() {
+        let signals = Signals::default();
+        signals.record(Duration::from_millis(10), false);
+        signals.record(Duration::from_millis(30), true);
+        assert_eq!(signals.take(), (1, Some(Duration::from_millis(20))));
+        assert_eq!(signals.take(), (0, None));
+    }
+
+    #[tokio::test]
+    async fn test_limiter_shrinks_as_permits_return() {
+        let limiter = Limiter::new(2);
+        let first = limiter.acquire().await;
+        let second = limiter.acquire().await;
+        limiter.set_limit(1);
+        drop(first);
+        assert_eq!(limiter.available(), 0);
+        drop(second);
+        assert_eq!(limiter.available(), 1);
+
+        limiter.set_limit(3);
+        assert_eq!(limiter.available(), 3);
+    }
+}
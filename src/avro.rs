@@ -0,0 +1,867 @@
+//! Decoding of Avro object container files.
+//!
+//! Records are decoded with the writer schema embedded in the file header and
+//! rendered as JSON so they can be searched like any other text. Data is fed to
+//! the decoder in chunks as it is downloaded; each data block is decoded once
+//! all of its bytes have arrived. Blocks larger than a limit are skipped as they
+//! stream past rather than buffered.
+
+use crate::line_matches;
+use serde_json::{Map, Number, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+/// Magic bytes at the start of every Avro object container file.
+pub const MAGIC: &[u8; 4] = b"Obj\x01";
+
+const SYNC_SIZE: usize = 16;
+
+/// Most items of types that take no bytes, such as `null`, decoded from one block.
+const MAX_EMPTY_ITEMS: usize = 1 << 20;
+
+/// An Avro schema, as needed for decoding.
+#[derive(Debug, Clone, PartialEq)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, Schema)>),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed(usize),
+    /// A reference to a previously defined named type.
+    Named(String),
+}
+
+/// Named types defined in a schema, keyed by both short and full name.
+type Names = HashMap<String, Schema>;
+
+fn parse_schema(
+    json: &Value,
+    namespace: Option<&str>,
+    names: &mut Names,
+) -> Result<Schema, String> {
+    match json {
+        Value::String(name) => Ok(match name.as_str() {
+            "null" => Schema::Null,
+            "boolean" => Schema::Boolean,
+            "int" => Schema::Int,
+            "long" => Schema::Long,
+            "float" => Schema::Float,
+            "double" => Schema::Double,
+            "bytes" => Schema::Bytes,
+            "string" => Schema::String,
+            other => Schema::Named(other.to_string()),
+        }),
+        Value::Array(branches) => Ok(Schema::Union(
+            branches
+                .iter()
+                .map(|branch| parse_schema(branch, namespace, names))
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::Object(object) => {
+            let kind = object
+                .get("type")
+                .ok_or_else(|| "schema is missing \"type\"".to_string())?;
+            let name = object.get("name").and_then(Value::as_str);
+            let namespace = object
+                .get("namespace")
+                .and_then(Value::as_str)
+                .or(namespace);
+
+            let schema = match kind.as_str() {
+                Some("record") | Some("error") => {
+                    // Register the name first so that recursive references resolve
+                    if let Some(name) = name {
+                        register(names, name, namespace, Schema::Record(Vec::new()));
+                    }
+                    let fields = object
+                        .get("fields")
+                        .and_then(Value::as_array)
+                        .ok_or_else(|| "record schema is missing \"fields\"".to_string())?
+                        .iter()
+                        .map(|field| {
+                            let field_name = field
+                                .get("name")
+                                .and_then(Value::as_str)
+                                .ok_or_else(|| "record field is missing \"name\"".to_string())?;
+                            let field_type = field
+                                .get("type")
+                                .ok_or_else(|| format!("field {field_name} is missing \"type\""))?;
+                            Ok((
+                                field_name.to_string(),
+                                parse_schema(field_type, namespace, names)?,
+                            ))
+                        })
+                        .collect::<Result<_, String>>()?;
+                    Schema::Record(fields)
+                }
+                Some("enum") => Schema::Enum(
+                    object
+                        .get("symbols")
+                        .and_then(Value::as_array)
+                        .ok_or_else(|| "enum schema is missing \"symbols\"".to_string())?
+                        .iter()
+                        .map(|symbol| symbol.as_str().unwrap_or_default().to_string())
+                        .collect(),
+                ),
+                Some("array") => Schema::Array(Box::new(parse_schema(
+                    object
+                        .get("items")
+                        .ok_or("array schema is missing \"items\"")?,
+                    namespace,
+                    names,
+                )?)),
+                Some("map") => Schema::Map(Box::new(parse_schema(
+                    object
+                        .get("values")
+                        .ok_or("map schema is missing \"values\"")?,
+                    namespace,
+                    names,
+                )?)),
+                Some("fixed") => Schema::Fixed(
+                    object
+                        .get("size")
+                        .and_then(Value::as_u64)
+                        .ok_or("fixed schema is missing \"size\"")? as usize,
+                ),
+                // Primitive types with attributes, e.g. logical types
+                _ => parse_schema(kind, namespace, names)?,
+            };
+
+            if let Some(name) = name {
+                register(names, name, namespace, schema.clone());
+            }
+            Ok(schema)
+        }
+        other => Err(format!("invalid schema: {other}")),
+    }
+}
+
+/// Returns the fewest bytes a value of `schema` takes, 0 for recursive types that never end.
+fn min_size<'a>(schema: &'a Schema, names: &'a Names, visiting: &mut Vec<&'a str>) -> usize {
+    match schema {
+        Schema::Null => 0,
+        Schema::Float => 4,
+        Schema::Double => 8,
+        Schema::Fixed(size) => *size,
+        Schema::Record(fields) => fields.iter().fold(0, |total, (_, schema)| {
+            total.saturating_add(min_size(schema, names, visiting))
+        }),
+        Schema::Named(name) => match names.get(name) {
+            Some(schema) if !visiting.contains(&name.as_str()) => {
+                visiting.push(name);
+                let size = min_size(schema, names, visiting);
+                visiting.pop();
+                size
+            }
+            _ => 0,
+        },
+        _ => 1,
+    }
+}
+
+fn register(names: &mut Names, name: &str, namespace: Option<&str>, schema: Schema) {
+    let short = name.rsplit('.').next().unwrap_or(name);
+    names.insert(short.to_string(), schema.clone());
+    match namespace {
+        Some(namespace) if !name.contains('.') => {
+            names.insert(format!("{namespace}.{name}"), schema);
+        }
+        _ => {
+            names.insert(name.to_string(), schema);
+        }
+    }
+}
+
+/// Why decoding stopped.
+#[derive(Debug)]
+enum DecodeError {
+    /// More bytes are needed.
+    Incomplete,
+    /// The data is not valid for the schema.
+    Invalid(String),
+}
+
+impl From<String> for DecodeError {
+    fn from(message: String) -> Self {
+        DecodeError::Invalid(message)
+    }
+}
+
+/// A read position within a byte slice.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// Items read so far that took no bytes
+    empty_items: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(n).ok_or(DecodeError::Incomplete)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(DecodeError::Incomplete)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn long(&mut self) -> Result<i64, DecodeError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(DecodeError::Invalid("varint is too long".to_string()))
+    }
+
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.long()?)
+            .map_err(|_| DecodeError::Invalid("negative length".to_string()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    /// Reads the item count of an array or map block.
+    fn block_count(&mut self) -> Result<usize, DecodeError> {
+        let count = self.long()?;
+        if count < 0 {
+            // A negative count is followed by the block size in bytes
+            self.long()?;
+        }
+        Ok(count.unsigned_abs() as usize)
+    }
+
+    /**
+        Checks that `count` items of `item_size` bytes each fit in the rest of
+        the buffer, so that a corrupt count cannot make decoding loop and
+        allocate without end. Items that take no bytes are capped in number instead.
+    */
+    fn check_count(&mut self, count: usize, item_size: usize) -> Result<(), DecodeError> {
+        if item_size > 0 {
+            if count.saturating_mul(item_size) > self.buf.len() - self.pos {
+                return Err(DecodeError::Invalid(format!(
+                    "item count {count} is larger than its block"
+                )));
+            }
+        } else {
+            self.empty_items = self.empty_items.saturating_add(count);
+            if self.empty_items > MAX_EMPTY_ITEMS {
+                return Err(DecodeError::Invalid(format!(
+                    "more than {MAX_EMPTY_ITEMS} empty items in a block"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, schema: &Schema, names: &Names) -> Result<Value, DecodeError> {
+        Ok(match schema {
+            Schema::Null => Value::Null,
+            Schema::Boolean => Value::Bool(self.take(1)?[0] != 0),
+            Schema::Int | Schema::Long => Value::from(self.long()?),
+            Schema::Float => {
+                let bytes: [u8; 4] = self.take(4)?.try_into().unwrap_or_default();
+                float(f64::from(f32::from_le_bytes(bytes)))
+            }
+            Schema::Double => {
+                let bytes: [u8; 8] = self.take(8)?.try_into().unwrap_or_default();
+                float(f64::from_le_bytes(bytes))
+            }
+            Schema::Bytes | Schema::String => {
+                Value::String(String::from_utf8_lossy(self.bytes()?).to_string())
+            }
+            Schema::Fixed(size) => {
+                Value::String(String::from_utf8_lossy(self.take(*size)?).to_string())
+            }
+            Schema::Enum(symbols) => {
+                let index = self.len()?;
+                Value::String(
+                    symbols
+                        .get(index)
+                        .ok_or_else(|| format!("enum index {index} out of range"))?
+                        .clone(),
+                )
+            }
+            Schema::Record(fields) => {
+                let mut object = Map::new();
+                for (name, schema) in fields {
+                    object.insert(name.clone(), self.value(schema, names)?);
+                }
+                Value::Object(object)
+            }
+            Schema::Array(items) => {
+                let mut values = Vec::new();
+                loop {
+                    let count = self.block_count()?;
+                    if count == 0 {
+                        break;
+                    }
+                    self.check_count(count, min_size(items, names, &mut Vec::new()))?;
+                    for _ in 0..count {
+                        values.push(self.value(items, names)?);
+                    }
+                }
+                Value::Array(values)
+            }
+            Schema::Map(values) => {
+                let mut object = Map::new();
+                loop {
+                    let count = self.block_count()?;
+                    if count == 0 {
+                        break;
+                    }
+                    // Each key takes at least its length byte
+                    let item_size = min_size(values, names, &mut Vec::new()).saturating_add(1);
+                    self.check_count(count, item_size)?;
+                    for _ in 0..count {
+                        let key = String::from_utf8_lossy(self.bytes()?).to_string();
+                        object.insert(key, self.value(values, names)?);
+                    }
+                }
+                Value::Object(object)
+            }
+            Schema::Union(branches) => {
+                let index = self.len()?;
+                let branch = branches
+                    .get(index)
+                    .ok_or_else(|| format!("union index {index} out of range"))?;
+                self.value(branch, names)?
+            }
+            Schema::Named(name) => {
+                let schema = names
+                    .get(name)
+                    .ok_or_else(|| format!("unknown type {name}"))?;
+                self.value(schema, names)?
+            }
+        })
+    }
+}
+
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+/// Compression codec of the data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Null,
+    Deflate,
+    Snappy,
+    Zstandard,
+}
+
+impl Codec {
+    /// Decompresses a data block, or returns `None` if it decompresses to more
+    /// than `limit` bytes.
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Option<Cow<'_, [u8]>>, String> {
+        fn read_limited(reader: impl Read, limit: usize) -> io::Result<Option<Vec<u8>>> {
+            let mut out = Vec::new();
+            reader
+                .take(limit.saturating_add(1) as u64)
+                .read_to_end(&mut out)?;
+            Ok((out.len() <= limit).then_some(out))
+        }
+
+        Ok(match self {
+            Codec::Null => Some(Cow::Borrowed(data)),
+            Codec::Deflate => read_limited(flate2::read::DeflateDecoder::new(data), limit)
+                .map_err(|e| format!("deflate: {e}"))?
+                .map(Cow::Owned),
+            Codec::Snappy => {
+                // The compressed data is followed by a 4-byte CRC32 of the uncompressed data
+                let data = data.get(..data.len().saturating_sub(4)).unwrap_or_default();
+                let len = snap::raw::decompress_len(data).map_err(|e| format!("snappy: {e}"))?;
+                if len > limit {
+                    return Ok(None);
+                }
+                Some(Cow::Owned(
+                    snap::raw::Decoder::new()
+                        .decompress_vec(data)
+                        .map_err(|e| format!("snappy: {e}"))?,
+                ))
+            }
+            Codec::Zstandard => zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| read_limited(decoder, limit))
+                .map_err(|e| format!("zstandard: {e}"))?
+                .map(Cow::Owned),
+        })
+    }
+}
+
+/// The parsed file header.
+#[derive(Debug)]
+struct Header {
+    schema: Schema,
+    names: Names,
+    codec: Codec,
+    sync: [u8; SYNC_SIZE],
+}
+
+/// What reading a data block found.
+enum Block {
+    /// The given number of records were decoded
+    Decoded(usize),
+    /// The block is larger than the limit; its records and the bytes after its
+    /// size, sync marker included, are to be skipped
+    TooLarge { records: usize, bytes: usize },
+}
+
+/// Incremental decoder for an Avro object container file.
+#[derive(Debug, Default)]
+pub struct ContainerDecoder {
+    buffer: Vec<u8>,
+    header: Option<Header>,
+    /// Largest block kept in memory, compressed and decompressed together (0 for no limit)
+    max_block: usize,
+    /// Bytes of a skipped block still to be discarded as they are fed
+    skip: usize,
+    /// Index of the next record in the file
+    next_record: usize,
+    long_blocks: usize,
+}
+
+impl ContainerDecoder {
+    /// Creates a decoder expecting the start of a container file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+        Limits the memory a data block may use to `max` bytes (0 for no limit).
+
+        Larger blocks are skipped along with their records, and a header larger
+        than `max` is an error.
+    */
+    pub fn with_max_block_size(mut self, max: usize) -> Self {
+        self.max_block = max;
+        self
+    }
+
+    /// Number of data blocks skipped for being larger than the limit.
+    pub fn long_blocks(&self) -> usize {
+        self.long_blocks
+    }
+
+    /// Appends downloaded bytes to the decoder's buffer.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let skipped = self.skip.min(bytes.len());
+        self.skip -= skipped;
+        self.buffer.extend_from_slice(&bytes[skipped..]);
+    }
+
+    /**
+        Decodes every complete data block currently buffered.
+
+        # Returns
+
+        The decoded records as JSON values with their 0-based index in the
+        file, in file order. Bytes belonging to an incomplete block are kept
+        until more data is fed.
+    */
+    pub fn decode_available(&mut self) -> Result<Vec<(usize, Value)>, String> {
+        let limit = if self.max_block == 0 {
+            usize::MAX
+        } else {
+            self.max_block
+        };
+        let mut records = Vec::new();
+        let mut consumed = 0;
+
+        loop {
+            let mut cursor = Cursor {
+                buf: &self.buffer,
+                pos: consumed,
+                empty_items: 0,
+            };
+            let result = match &self.header {
+                None => Self::read_header(&mut cursor).map(|header| {
+                    self.header = Some(header);
+                    Block::Decoded(0)
+                }),
+                Some(header) => {
+                    Self::read_block(&mut cursor, header, limit, self.next_record, &mut records)
+                }
+            };
+            match result {
+                Ok(Block::Decoded(count)) => {
+                    consumed = cursor.pos;
+                    self.next_record += count;
+                }
+                Ok(Block::TooLarge { records, bytes }) => {
+                    self.long_blocks += 1;
+                    self.next_record += records;
+                    let buffered = (self.buffer.len() - cursor.pos).min(bytes);
+                    self.skip = bytes - buffered;
+                    consumed = cursor.pos + buffered;
+                }
+                Err(DecodeError::Incomplete) => {
+                    if self.header.is_none() && self.buffer.len() > limit {
+                        return Err(format!("Avro header is larger than {limit} bytes"));
+                    }
+                    break;
+                }
+                Err(DecodeError::Invalid(message)) => return Err(message),
+            }
+            if consumed == self.buffer.len() {
+                break;
+            }
+        }
+
+        self.buffer.drain(..consumed);
+        Ok(records)
+    }
+
+    /// Checks that the data ended on a block boundary.
+    pub fn finish(&self) -> Result<(), String> {
+        match (&self.header, self.buffer.is_empty() && self.skip == 0) {
+            (Some(_), true) => Ok(()),
+            (None, _) => Err("truncated Avro header".to_string()),
+            (Some(_), false) => Err("truncated Avro data block".to_string()),
+        }
+    }
+
+    fn read_header(cursor: &mut Cursor<'_>) -> Result<Header, DecodeError> {
+        if cursor.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::Invalid(
+                "not an Avro container file".to_string(),
+            ));
+        }
+
+        let mut metadata = HashMap::new();
+        loop {
+            let count = cursor.block_count()?;
+            if count == 0 {
+                break;
+            }
+            for _ in 0..count {
+                let key = String::from_utf8_lossy(cursor.bytes()?).to_string();
+                metadata.insert(key, cursor.bytes()?.to_vec());
+            }
+        }
+        let sync = cursor.take(SYNC_SIZE)?.try_into().unwrap_or_default();
+
+        let schema = metadata
+            .get("avro.schema")
+            .ok_or_else(|| "missing avro.schema in header".to_string())?;
+        let schema: Value =
+            serde_json::from_slice(schema).map_err(|e| format!("invalid avro.schema: {e}"))?;
+        let mut names = Names::new();
+        let schema = parse_schema(&schema, None, &mut names)?;
+
+        let codec = match metadata.get("avro.codec").map(Vec::as_slice) {
+            None | Some(b"null") => Codec::Null,
+            Some(b"deflate") => Codec::Deflate,
+            Some(b"snappy") => Codec::Snappy,
+            Some(b"zstandard") => Codec::Zstandard,
+            Some(other) => {
+                return Err(DecodeError::Invalid(format!(
+                    "unsupported codec {}",
+                    String::from_utf8_lossy(other)
+                )))
+            }
+        };
+
+        Ok(Header {
+            schema,
+            names,
+            codec,
+            sync,
+        })
+    }
+
+    fn read_block(
+        cursor: &mut Cursor<'_>,
+        header: &Header,
+        limit: usize,
+        first_record: usize,
+        records: &mut Vec<(usize, Value)>,
+    ) -> Result<Block, DecodeError> {
+        let count = cursor.len()?;
+        let len = cursor.len()?;
+        if len > limit {
+            return Ok(Block::TooLarge {
+                records: count,
+                bytes: len.saturating_add(SYNC_SIZE),
+            });
+        }
+        let data = cursor.take(len)?;
+        if cursor.take(SYNC_SIZE)? != header.sync {
+            return Err(DecodeError::Invalid("sync marker mismatch".to_string()));
+        }
+
+        // The compressed block is still buffered while it is decompressed
+        let Some(data) = header.codec.decompress(data, limit - len)? else {
+            return Ok(Block::TooLarge {
+                records: count,
+                bytes: 0,
+            });
+        };
+        let mut block = Cursor {
+            buf: &data,
+            pos: 0,
+            empty_items: 0,
+        };
+        block.check_count(
+            count,
+            min_size(&header.schema, &header.names, &mut Vec::new()),
+        )?;
+        for index in first_record..first_record + count {
+            match block.value(&header.schema, &header.names) {
+                Ok(value) => records.push((index, value)),
+                Err(DecodeError::Incomplete) => {
+                    return Err(DecodeError::Invalid(
+                        "record extends past its block".to_string(),
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Block::Decoded(count))
+    }
+}
+
+/**
+    Returns true if a decoded record contains the pattern.
+
+    # Arguments
+
+    * `record` - The decoded record.
+    * `fields` - Fields to search, as dotted paths into nested records; if empty,
+      the JSON rendering of the whole record is searched.
+    * `pattern` - The pattern to search for.
+    * `case_sensitive` - If true, the search is case sensitive.
+*/
+pub fn record_matches(
+    record: &Value,
+    fields: &[String],
+    pattern: &str,
+    case_sensitive: bool,
+) -> bool {
+    if fields.is_empty() {
+        return line_matches(&record.to_string(), pattern, case_sensitive);
+    }
+
+    fields.iter().any(|field| {
+        let value = field
+            .split('.')
+            .try_fold(record, |value, part| value.get(part));
+        match value {
+            Some(Value::Null) | None => false,
+            Some(Value::String(s)) => line_matches(s, pattern, case_sensitive),
+            Some(other) => line_matches(&other.to_string(), pattern, case_sensitive),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long(value: i64, out: &mut Vec<u8>) {
+        let mut n = ((value << 1) ^ (value >> 63)) as u64;
+        loop {
+            if n < 0x80 {
+                out.push(n as u8);
+                break;
+            }
+            out.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+    }
+
+    fn bytes(value: &[u8], out: &mut Vec<u8>) {
+        long(value.len() as i64, out);
+        out.extend_from_slice(value);
+    }
+
+    fn container(schema: &str, count: i64, data: &[u8]) -> Vec<u8> {
+        container_blocks(schema, &[(count, data)])
+    }
+
+    fn container_blocks(schema: &str, blocks: &[(i64, &[u8])]) -> Vec<u8> {
+        let sync = [7u8; SYNC_SIZE];
+        let mut file = MAGIC.to_vec();
+        long(1, &mut file);
+        bytes(b"avro.schema", &mut file);
+        bytes(schema.as_bytes(), &mut file);
+        long(0, &mut file);
+        file.extend_from_slice(&sync);
+        for (count, data) in blocks {
+            long(*count, &mut file);
+            bytes(data, &mut file);
+            file.extend_from_slice(&sync);
+        }
+        file
+    }
+
+    #[test]
+    fn test_decode_records() {
+        let schema = r#"{"type":"record","name":"Event","fields":[
+            {"name":"id","type":"long"},
+            {"name":"level","type":{"type":"enum","name":"Level","symbols":["INFO","ERROR"]}},
+            {"name":"msg","type":["null","string"]},
+            {"name":"tags","type":{"type":"array","items":"string"}}]}"#;
+        let mut data = Vec::new();
+        // {"id": 1, "level": "INFO", "msg": null, "tags": []}
+        long(1, &mut data);
+        long(0, &mut data);
+        long(0, &mut data);
+        long(0, &mut data);
+        // {"id": -2, "level": "ERROR", "msg": "disk full", "tags": ["a"]}
+        long(-2, &mut data);
+        long(1, &mut data);
+        long(1, &mut data);
+        bytes(b"disk full", &mut data);
+        long(1, &mut data);
+        bytes(b"a", &mut data);
+        long(0, &mut data);
+        let file = container(schema, 2, &data);
+
+        // Feed one byte at a time to exercise incomplete headers and blocks
+        let mut decoder = ContainerDecoder::new();
+        let mut records = Vec::new();
+        for byte in &file {
+            decoder.feed(std::slice::from_ref(byte));
+            records.extend(decoder.decode_available().unwrap());
+        }
+        decoder.finish().unwrap();
+
+        let rendered: Vec<String> = records.iter().map(|(_, r)| r.to_string()).collect();
+        assert_eq!(
+            rendered,
+            vec![
+                r#"{"id":1,"level":"INFO","msg":null,"tags":[]}"#,
+                r#"{"id":-2,"level":"ERROR","msg":"disk full","tags":["a"]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursive_schema() {
+        let schema = r#"{"type":"record","name":"Node","namespace":"x","fields":[
+            {"name":"v","type":"int"},{"name":"next","type":["null","x.Node"]}]}"#;
+        let mut data = Vec::new();
+        long(1, &mut data);
+        long(1, &mut data);
+        long(2, &mut data);
+        long(0, &mut data);
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(&container(schema, 1, &data));
+        let records = decoder.decode_available().unwrap();
+        assert_eq!(
+            records[0].1.to_string(),
+            r#"{"v":1,"next":{"v":2,"next":null}}"#
+        );
+    }
+
+    #[test]
+    fn test_large_blocks_are_skipped() {
+        let mut small = Vec::new();
+        bytes(b"ok", &mut small);
+        let mut large = Vec::new();
+        bytes(&[b'x'; 60], &mut large);
+        bytes(&[b'y'; 60], &mut large);
+        let file = container_blocks(r#""string""#, &[(2, &large), (1, &small)]);
+
+        for chunk in [1, 7, file.len()] {
+            let mut decoder = ContainerDecoder::new().with_max_block_size(100);
+            let mut records = Vec::new();
+            for bytes in file.chunks(chunk) {
+                decoder.feed(bytes);
+                records.extend(decoder.decode_available().unwrap());
+            }
+            decoder.finish().unwrap();
+            // The skipped records still count towards the index of later ones
+            assert_eq!(records, vec![(2, Value::from("ok"))]);
+            assert_eq!(decoder.long_blocks(), 1);
+        }
+
+        // A header is not buffered past the limit either
+        let mut decoder = ContainerDecoder::new().with_max_block_size(10);
+        let result = file.iter().try_for_each(|byte| {
+            decoder.feed(std::slice::from_ref(byte));
+            decoder.decode_available().map(drop)
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_record_matches() {
+        let record: Value =
+            serde_json::from_str(r#"{"level":"ERROR","ctx":{"host":"web-1"},"msg":null}"#).unwrap();
+        assert!(record_matches(&record, &[], "error", false));
+        assert!(record_matches(
+            &record,
+            &["ctx.host".to_string()],
+            "web",
+            true
+        ));
+        assert!(!record_matches(
+            &record,
+            &["level".to_string()],
+            "web",
+            true
+        ));
+        assert!(!record_matches(&record, &["msg".to_string()], "null", true));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(b"PAR1....");
+        assert!(decoder.decode_available().is_err());
+
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(MAGIC);
+        assert!(decoder.decode_available().unwrap().is_empty());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_corrupt_counts_are_rejected() {
+        // An array block claiming more longs than it has bytes
+        let mut data = Vec::new();
+        long(i64::MAX, &mut data);
+        long(1, &mut data);
+        long(0, &mut data);
+        let file = container(r#"{"type":"array","items":"long"}"#, 1, &data);
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(&file);
+        assert!(decoder.decode_available().is_err());
+
+        // Nulls take no bytes, so their number is capped instead
+        let mut data = Vec::new();
+        long(i64::MAX, &mut data);
+        long(0, &mut data);
+        for schema in [
+            r#"{"type":"array","items":"null"}"#,
+            r#"{"type":"map","values":"null"}"#,
+        ] {
+            let mut decoder = ContainerDecoder::new();
+            decoder.feed(&container(schema, 1, &data));
+            assert!(decoder.decode_available().is_err());
+        }
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(&container(r#""null""#, i64::MAX, &[]));
+        assert!(decoder.decode_available().is_err());
+
+        // Empty records up to the cap still decode
+        let mut decoder = ContainerDecoder::new();
+        decoder.feed(&container(r#""null""#, 3, &[]));
+        assert_eq!(decoder.decode_available().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,337 @@
+//! Bloom filter sidecars for exact token lookups.
+//!
+//! A sidecar is a small object stored next to a log object, at its key plus
+//! [`SIDECAR_SUFFIX`], holding a bloom filter of the tokens in the object. A
+//! token is a maximal run of ASCII letters, digits, `_` and non-ASCII bytes,
+//! ASCII-lowercased, so `req-7f3a9c` is the two tokens `req` and `7f3a9c`.
+//! Searches can fetch the sidecar first and skip the object when the filter
+//! rules out one of the pattern's tokens.
+//!
+//! # Format
+//!
+//! All integers are little endian.
+//!
+//! | Offset | Size | Field |
+//! |--------|------|-------|
+//! | 0 | 4 | Magic bytes `S3GB` |
+//! | 4 | 1 | Format version, currently 1 |
+//! | 5 | 1 | `k`, the number of bit positions per token |
+//! | 6 | 8 | `m`, the number of bits in the filter; 0 means every token may be present |
+//! | 14 | 2 | Length `n` of the ETag |
+//! | 16 | `n` | ETag of the object the filter was built from, without quotes |
+//! | 16 + `n` | `ceil(m / 8)` | The filter bits; bit `i` is bit `i % 8` of byte `i / 8` |
+//!
+//! A token's hash `h1` is the 64-bit FNV-1a hash of its bytes, and
+//! `h2 = splitmix64(h1) | 1`. Its bit positions are `(h1 + i * h2) mod m` for
+//! `i` in `0..k`, with wrapping arithmetic. A sidecar whose ETag differs from
+//! the object's is stale and must not be used.
+
+use std::collections::HashSet;
+
+/// Suffix appended to an object's key to name its sidecar.
+pub const SIDECAR_SUFFIX: &str = ".s3grep-bloom";
+
+/// Identifies sidecar objects.
+const MAGIC: &[u8; 4] = b"S3GB";
+
+/// Version of the sidecar format.
+const VERSION: u8 = 1;
+
+/// Bit positions per token; with [`BITS_PER_TOKEN`] this gives about 1% false positives.
+const HASHES: u8 = 7;
+
+/// Filter bits per distinct token.
+const BITS_PER_TOKEN: f64 = 9.6;
+
+/// Objects with more distinct tokens than this get a filter that matches everything,
+/// bounding the memory used to build it.
+pub const MAX_TOKENS: usize = 1 << 22;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Returns the sidecar key for an object key.
+pub fn sidecar_key(key: &str) -> String {
+    format!("{key}{SIDECAR_SUFFIX}")
+}
+
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || !byte.is_ascii()
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Hashes the tokens of a text fed to it in pieces.
+#[derive(Debug, Default)]
+pub struct TokenHasher {
+    /// Hash of the token in progress
+    current: Option<u64>,
+}
+
+impl TokenHasher {
+    /// Adds the next piece of the text, calling `emit` with the hash of each completed token.
+    pub fn feed(&mut self, bytes: &[u8], mut emit: impl FnMut(u64)) {
+        for &byte in bytes {
+            if is_token_byte(byte) {
+                let hash = self.current.unwrap_or(FNV_OFFSET);
+                self.current =
+                    Some((hash ^ byte.to_ascii_lowercase() as u64).wrapping_mul(FNV_PRIME));
+            } else if let Some(hash) = self.current.take() {
+                emit(hash);
+            }
+        }
+    }
+
+    /// Ends the text, emitting its last token.
+    pub fn finish(&mut self, mut emit: impl FnMut(u64)) {
+        if let Some(hash) = self.current.take() {
+            emit(hash);
+        }
+    }
+}
+
+/**
+    Returns the token hashes that must all be in an object's filter for it to
+    contain `pattern`.
+
+    Searches match substrings, so the pattern's first token may be the end of a
+    longer token in the text, and its last token the start of one. Only tokens
+    with a non-token byte on both sides within the pattern are required.
+
+    Returns `None` if no such tokens remain, or for case insensitive non-ASCII
+    patterns, whose case folding the filter does not share.
+
+    # Examples
+
+    ```
+    use s3grep::bloom::pattern_tokens;
+    assert_eq!(pattern_tokens("=req-7f3a9c ", true).unwrap().len(), 2);
+    assert_eq!(pattern_tokens("id=req-7f3a9c", true).unwrap().len(), 1);
+    assert_eq!(pattern_tokens(" Timeout ", false), pattern_tokens(" timeout ", true));
+    assert!(pattern_tokens("req-7f3a9c", true).is_none());
+    assert!(pattern_tokens("--", true).is_none());
+    assert!(pattern_tokens(" café ", false).is_none());
+    ```
+*/
+pub fn pattern_tokens(pattern: &str, case_sensitive: bool) -> Option<Vec<u64>> {
+    if !case_sensitive && !pattern.is_ascii() {
+        return None;
+    }
+    let bytes = pattern.as_bytes();
+    let mut tokens = Vec::new();
+    let mut hasher = TokenHasher::default();
+    hasher.feed(bytes, |hash| tokens.push(hash));
+    hasher.finish(|hash| tokens.push(hash));
+    if bytes.first().is_some_and(|&byte| is_token_byte(byte)) && !tokens.is_empty() {
+        tokens.remove(0);
+    }
+    if bytes.last().is_some_and(|&byte| is_token_byte(byte)) {
+        tokens.pop();
+    }
+    (!tokens.is_empty()).then_some(tokens)
+}
+
+/// A bloom filter of token hashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    hashes: u8,
+    len: u64,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for the given distinct token hashes.
+    pub fn new(tokens: &HashSet<u64>) -> Self {
+        let len = ((tokens.len() as f64 * BITS_PER_TOKEN).ceil() as u64).max(64);
+        let mut filter = BloomFilter {
+            hashes: HASHES,
+            len,
+            bits: vec![0; len.div_ceil(8) as usize],
+        };
+        for &token in tokens {
+            for bit in filter.positions(token) {
+                filter.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Creates a filter that may contain every token.
+    pub fn everything() -> Self {
+        BloomFilter {
+            hashes: HASHES,
+            len: 0,
+            bits: Vec::new(),
+        }
+    }
+
+    /// Returns false only if the token was not added to the filter.
+    pub fn may_contain(&self, token: u64) -> bool {
+        self.len == 0
+            || self
+                .positions(token)
+                .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    fn positions(&self, token: u64) -> impl Iterator<Item = u64> {
+        let (hashes, len) = (self.hashes as u64, self.len);
+        let step = splitmix64(token) | 1;
+        (0..hashes).map(move |i| token.wrapping_add(i.wrapping_mul(step)) % len)
+    }
+}
+
+/// A bloom filter together with the ETag of the object it was built from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub etag: String,
+    pub filter: BloomFilter,
+}
+
+impl Sidecar {
+    /// Returns true if the sidecar was built from this version of its object.
+    pub fn is_fresh(&self, etag: &str) -> bool {
+        self.etag == etag.trim_matches('"')
+    }
+
+    /// Returns false only if the object does not contain every one of `tokens`.
+    pub fn may_contain_all(&self, tokens: &[u64]) -> bool {
+        tokens.iter().all(|&token| self.filter.may_contain(token))
+    }
+
+    /// Serializes the sidecar in the format described in the module docs.
+    pub fn encode(&self) -> Vec<u8> {
+        let etag = self.etag.as_bytes();
+        let mut bytes = Vec::with_capacity(16 + etag.len() + self.filter.bits.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.filter.hashes);
+        bytes.extend_from_slice(&self.filter.len.to_le_bytes());
+        bytes.extend_from_slice(&(etag.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(etag);
+        bytes.extend_from_slice(&self.filter.bits);
+        bytes
+    }
+
+    /**
+        Parses a sidecar.
+
+        # Examples
+
+        ```
+        use s3grep::bloom::{pattern_tokens, Sidecar, SidecarBuilder};
+        let mut builder = SidecarBuilder::default();
+        builder.feed(b"2024-01-01 request_id=req-7f3a9c status=500\n");
+        let bytes = builder.finish("\"abc123\"").encode();
+
+        let sidecar = Sidecar::decode(&bytes).unwrap();
+        assert!(sidecar.is_fresh("\"abc123\""));
+        assert!(sidecar.may_contain_all(&pattern_tokens("=REQ-7F3A9C ", false).unwrap()));
+        assert!(!sidecar.may_contain_all(&pattern_tokens("=req-000000 ", true).unwrap()));
+        assert!(Sidecar::decode(&bytes[..20]).is_err());
+        ```
+    */
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let invalid = || "invalid bloom filter sidecar".to_string();
+        let header = bytes.get(..16).ok_or_else(invalid)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(invalid());
+        }
+        let hashes = header[5];
+        let len = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let etag_len = u16::from_le_bytes(header[14..16].try_into().unwrap()) as usize;
+        let etag = bytes.get(16..16 + etag_len).ok_or_else(invalid)?;
+        let bits = &bytes[16 + etag_len..];
+        if bits.len() as u64 != len.div_ceil(8) {
+            return Err(invalid());
+        }
+        Ok(Sidecar {
+            etag: String::from_utf8(etag.to_vec()).map_err(|_| invalid())?,
+            filter: BloomFilter {
+                hashes,
+                len,
+                bits: bits.to_vec(),
+            },
+        })
+    }
+}
+
+/// Builds a sidecar from an object's text fed to it in pieces.
+#[derive(Debug, Default)]
+pub struct SidecarBuilder {
+    hasher: TokenHasher,
+    tokens: HashSet<u64>,
+    overflow: bool,
+}
+
+impl SidecarBuilder {
+    /// Adds the next piece of the text.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let (tokens, overflow) = (&mut self.tokens, &mut self.overflow);
+        self.hasher.feed(bytes, |token| {
+            if !*overflow {
+                tokens.insert(token);
+                *overflow = tokens.len() > MAX_TOKENS;
+            }
+        });
+        if self.overflow {
+            self.tokens = HashSet::new();
+        }
+    }
+
+    /// Returns the sidecar for the text fed so far, built from the object with `etag`.
+    pub fn finish(mut self, etag: &str) -> Sidecar {
+        let tokens = &mut self.tokens;
+        self.hasher.finish(|token| {
+            tokens.insert(token);
+        });
+        let filter = if self.overflow || self.tokens.len() > MAX_TOKENS {
+            BloomFilter::everything()
+        } else {
+            BloomFilter::new(&self.tokens)
+        };
+        Sidecar {
+            etag: etag.trim_matches('"').to_string(),
+            filter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_split_across_pieces() {
+        let mut builder = SidecarBuilder::default();
+        builder.feed(b"trace=1-5e1b41");
+        builder.feed(b"51-5ac6c58f");
+        let sidecar = builder.finish("etag");
+        assert!(sidecar.may_contain_all(&pattern_tokens("=1-5e1b4151-5ac6c58f", true).unwrap()));
+        assert!(!sidecar.is_fresh("\"other\""));
+
+        // The pattern's outer tokens may be parts of longer tokens in the text
+        assert!(pattern_tokens("7f3a", true).is_none());
+        assert!(pattern_tokens("req-7f3a", true).is_none());
+        let mut builder = SidecarBuilder::default();
+        builder.feed(b"id=myreq-7f3a9c ");
+        let sidecar = builder.finish("etag");
+        assert!(!sidecar.may_contain_all(&pattern_tokens("-abc-", true).unwrap()));
+        assert!(sidecar.may_contain_all(&pattern_tokens("eq-7f3a9c ", true).unwrap()));
+    }
+
+    #[test]
+    fn test_everything_filter_matches_any_token() {
+        let sidecar = Sidecar {
+            etag: "etag".to_string(),
+            filter: BloomFilter::everything(),
+        };
+        let decoded = Sidecar::decode(&sidecar.encode()).unwrap();
+        assert_eq!(decoded, sidecar);
+        assert!(decoded.may_contain_all(&pattern_tokens(" anything ", true).unwrap()));
+    }
+}
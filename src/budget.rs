@@ -0,0 +1,134 @@
+//! A global memory budget for in-flight buffers.
+//!
+//! Searches reserve the memory they may buffer (read buffers, partial lines,
+//! fetched Parquet column chunks) before they start, and wait while the budget
+//! is used up. The budget also tracks the peak amount reserved for `--stats`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Shared budget of bytes that searches can reserve.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    semaphore: Arc<Semaphore>,
+    limit: Option<u64>,
+    in_use: AtomicU64,
+    peak: AtomicU64,
+}
+
+/// Memory reserved from a [`MemoryBudget`], released when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    _permit: OwnedSemaphorePermit,
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `limit` bytes, or an unlimited one that only tracks usage.
+    pub fn new(limit: Option<u64>) -> Arc<Self> {
+        let permits = limit.map_or(Semaphore::MAX_PERMITS, |limit| {
+            (limit as usize).clamp(1, Semaphore::MAX_PERMITS)
+        });
+        Arc::new(MemoryBudget {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            limit,
+            in_use: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+        })
+    }
+
+    /// Waits until `bytes` can be reserved.
+    ///
+    /// A request larger than the whole budget is reduced to the budget, so it
+    /// runs once nothing else holds memory rather than waiting forever.
+    pub async fn reserve(self: &Arc<Self>, bytes: u64) -> Reservation {
+        let permits = self.permits(bytes);
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .expect("budget semaphore is never closed");
+        self.track(permit, permits as u64)
+    }
+
+    /**
+        Reserves `bytes` if they are available right now.
+
+        # Examples
+
+        ```
+        use s3grep::budget::MemoryBudget;
+        let budget = MemoryBudget::new(Some(100));
+        let reservation = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(60).is_none());
+        drop(reservation);
+        assert!(budget.try_reserve(60).is_some());
+        assert_eq!(budget.peak(), 60);
+        ```
+    */
+    pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+        let permits = self.permits(bytes);
+        let permit = self
+            .semaphore
+            .clone()
+            .try_acquire_many_owned(permits)
+            .ok()?;
+        Some(self.track(permit, permits as u64))
+    }
+
+    /// The configured limit, if any.
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// The most memory reserved at any one time.
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn permits(&self, bytes: u64) -> u32 {
+        let bytes = match self.limit {
+            Some(limit) => bytes.min(limit.max(1)),
+            None => bytes,
+        };
+        bytes.min(u32::MAX as u64) as u32
+    }
+
+    fn track(self: &Arc<Self>, permit: OwnedSemaphorePermit, bytes: u64) -> Reservation {
+        let in_use = self.in_use.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        Reservation {
+            _permit: permit,
+            budget: self.clone(),
+            bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_oversized_request_is_capped() {
+        let budget = MemoryBudget::new(Some(10));
+        let reservation = budget.reserve(1_000).await;
+        assert!(budget.try_reserve(1).is_none());
+        drop(reservation);
+        assert_eq!(budget.peak(), 10);
+
+        let unlimited = MemoryBudget::new(None);
+        let _a = unlimited.reserve(1 << 30).await;
+        let _b = unlimited.reserve(1 << 30).await;
+        assert_eq!(unlimited.peak(), 2 << 30);
+    }
+}
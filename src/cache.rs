@@ -0,0 +1,401 @@
+//! A local on-disk cache of object bodies.
+//!
+//! Entries are keyed by bucket, key and ETag, so an entry is fresh exactly when
+//! its ETag matches the one in the listing and no extra request is needed to
+//! validate it. Bodies are stored as downloaded (still compressed for `.gz`
+//! objects) under `<dir>/<hash of bucket and key>/<hash of ETag>`, and the
+//! least recently used entries are evicted to stay under a size cap.
+//!
+//! Entries are opened on a blocking thread, and written, committed and evicted
+//! on one fed by the reader that downloads the object, so disk I/O never stalls
+//! the runtime.
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+
+/// Used to give concurrent writes of the same entry distinct temporary files.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Chunks of a body queued for writing before its entry is given up as too
+/// slow to store, rather than holding back the search.
+const WRITE_QUEUE: usize = 64;
+
+/// An on-disk cache of object bodies with an LRU size cap.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Approximate size of all entries, corrected whenever entries are evicted
+    size: Mutex<u64>,
+}
+
+impl Cache {
+    /// Opens (creating if needed) a cache in `dir` holding at most `max_size` bytes.
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let size = entries(&dir)?.iter().map(|entry| entry.size).sum();
+        Ok(Cache {
+            dir,
+            max_size,
+            size: Mutex::new(size),
+        })
+    }
+
+    /// The largest object that will be cached.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Opens a fresh entry, marking it as recently used.
+    ///
+    /// Returns `None` if there is no entry or it cannot be opened. An open entry
+    /// stays readable even if it is evicted while it is read.
+    pub async fn get(&self, bucket: &str, key: &str, etag: &str) -> Option<tokio::fs::File> {
+        let path = self.entry_path(bucket, key, etag);
+        let file = tokio::task::spawn_blocking(move || {
+            let file = File::open(&path).ok()?;
+            // The modification time records the last use for LRU eviction. It is
+            // best-effort so that a read-only cache still serves its entries.
+            let _ = file.set_modified(SystemTime::now());
+            Some(file)
+        })
+        .await
+        .ok()??;
+        Some(tokio::fs::File::from_std(file))
+    }
+
+    /// Wraps a body reader so that everything read through it is stored in the
+    /// cache once the reader reaches the end.
+    ///
+    /// Must be called from within a Tokio runtime, which runs the writes.
+    pub fn writer<R>(
+        self: &Arc<Self>,
+        bucket: &str,
+        key: &str,
+        etag: &str,
+        inner: R,
+    ) -> CachingReader<R> {
+        let (chunks, queue) = mpsc::channel(WRITE_QUEUE);
+        let cache = self.clone();
+        let path = self.entry_path(bucket, key, etag);
+        tokio::task::spawn_blocking(move || store(&cache, &path, queue));
+        CachingReader {
+            inner,
+            entry: Some(chunks),
+            written: 0,
+            max_size: self.max_size,
+            committing: None,
+        }
+    }
+
+    /// Deletes the least recently used entries until the cache fits its cap.
+    pub fn evict(&self) -> io::Result<()> {
+        let mut size = self.size.lock().unwrap_or_else(|e| e.into_inner());
+        if *size <= self.max_size {
+            return Ok(());
+        }
+        let mut entries = entries(&self.dir)?;
+        entries.sort_by_key(|entry| entry.used);
+        *size = entries.iter().map(|entry| entry.size).sum();
+        for entry in entries {
+            if *size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&entry.path).is_ok() {
+                *size -= entry.size;
+                if let Some(parent) = entry.path.parent() {
+                    let _ = fs::remove_dir(parent);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, bucket: &str, key: &str, etag: &str) -> PathBuf {
+        entry_path(&self.dir, bucket, key, etag)
+    }
+
+    /// Moves a completed entry into place, replacing older versions of the object.
+    fn commit(&self, temp: &Path, path: &Path, written: u64) -> io::Result<()> {
+        fs::rename(temp, path)?;
+        remove_other_versions(path)?;
+        *self.size.lock().unwrap_or_else(|e| e.into_inner()) += written;
+        self.evict()
+    }
+}
+
+/// Path of the entry for one version of an object:
+/// `<dir>/<hash of bucket and key>/<hash of ETag>`.
+pub(crate) fn entry_path(dir: &Path, bucket: &str, key: &str, etag: &str) -> PathBuf {
+    let object = Sha256::new()
+        .chain_update(bucket)
+        .chain_update([0])
+        .chain_update(key)
+        .finalize();
+    let etag = Sha256::digest(etag.trim_matches('"'));
+    dir.join(hex::encode(object)).join(hex::encode(etag))
+}
+
+/// Path of a temporary file to write an entry to before renaming it into place.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Removes the entries for other versions of the object that `path` belongs to.
+pub(crate) fn remove_other_versions(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        for old in fs::read_dir(parent)? {
+            let old = old?.path();
+            // Temporary files have an extension and may belong to another writer
+            if old != path && old.extension().is_none() {
+                let _ = fs::remove_file(old);
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    used: SystemTime,
+}
+
+/// Lists the committed entries in a cache directory.
+fn entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for object in fs::read_dir(dir)? {
+        let object = object?;
+        if !object.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(object.path())? {
+            let file = file?;
+            let path = file.path();
+            // Temporary files have an extension; committed entries do not
+            if path.extension().is_some() {
+                continue;
+            }
+            let metadata = file.metadata()?;
+            entries.push(Entry {
+                path,
+                size: metadata.len(),
+                used: metadata.modified()?,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// What a [`CachingReader`] sends to the thread writing its entry.
+enum Chunk {
+    /// Bytes of the body, in order
+    Data(Vec<u8>),
+    /// The end of the body; the sender is dropped once the entry is committed
+    End(oneshot::Sender<()>),
+}
+
+/// Writes the chunks of a body to a temporary file and commits it at the end.
+///
+/// The temporary file is removed if the reader goes away before the end or
+/// anything fails.
+fn store(cache: &Cache, path: &Path, mut queue: mpsc::Receiver<Chunk>) {
+    let temp = temp_path(path);
+    let result = (|| {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = BufWriter::new(File::create(&temp)?);
+        let mut written = 0;
+        while let Some(chunk) = queue.blocking_recv() {
+            match chunk {
+                Chunk::Data(data) => {
+                    file.write_all(&data)?;
+                    written += data.len() as u64;
+                }
+                Chunk::End(_committed) => {
+                    file.flush()?;
+                    drop(file);
+                    return cache.commit(&temp, path, written);
+                }
+            }
+        }
+        Err(io::Error::other("body was not read to the end"))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+}
+
+/// A reader that copies what it reads into a cache entry.
+///
+/// The entry is committed when the inner reader reaches the end, and the end
+/// is only reported once it has been. It is discarded if reading stops early,
+/// fails, or the copy cannot keep up or be written.
+pub struct CachingReader<R> {
+    inner: R,
+    entry: Option<mpsc::Sender<Chunk>>,
+    written: u64,
+    max_size: u64,
+    /// Set once the end was sent, until the entry is committed
+    committing: Option<oneshot::Receiver<()>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CachingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(committing) = &mut self.committing {
+            // Failing to commit only means the object is not cached
+            let _ = ready!(Pin::new(committing).poll(cx));
+            self.committing = None;
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        let Some(entry) = self.entry.take() else {
+            return Poll::Ready(result);
+        };
+        if result.is_err() {
+            return Poll::Ready(result);
+        }
+
+        let read = &buf.filled()[filled..];
+        if read.is_empty() {
+            let (committed, committing) = oneshot::channel();
+            if entry.try_send(Chunk::End(committed)).is_ok() {
+                self.committing = Some(committing);
+                return self.poll_read(cx, buf);
+            }
+        } else if self.written + read.len() as u64 <= self.max_size
+            && entry.try_send(Chunk::Data(read.to_vec())).is_ok()
+        {
+            self.written += read.len() as u64;
+            self.entry = Some(entry);
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s3grep-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn store(cache: &Arc<Cache>, key: &str, etag: &str, body: &[u8]) {
+        let mut reader = cache.writer("bucket", key, etag, body);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, body);
+    }
+
+    #[tokio::test]
+    async fn test_entries_are_keyed_by_etag() {
+        let dir = temp_dir("etag");
+        let cache = Arc::new(Cache::new(&dir, 1024).unwrap());
+        assert!(cache.get("bucket", "a.log", "\"v1\"").await.is_none());
+
+        store(&cache, "a.log", "\"v1\"", b"first").await;
+        let mut entry = cache.get("bucket", "a.log", "\"v1\"").await.unwrap();
+        let mut body = Vec::new();
+        entry.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"first");
+
+        // A new version replaces the old one
+        store(&cache, "a.log", "\"v2\"", b"second").await;
+        assert!(cache.get("bucket", "a.log", "\"v1\"").await.is_none());
+        assert!(cache.get("bucket", "a.log", "\"v2\"").await.is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_are_evicted() {
+        let dir = temp_dir("lru");
+        let cache = Arc::new(Cache::new(&dir, 10).unwrap());
+        store(&cache, "a", "1", b"aaaa").await;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store(&cache, "b", "1", b"bbbb").await;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(cache.get("bucket", "a", "1").await.is_some());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store(&cache, "c", "1", b"cccc").await;
+
+        assert!(cache.get("bucket", "a", "1").await.is_some());
+        assert!(cache.get("bucket", "b", "1").await.is_none());
+        assert!(cache.get("bucket", "c", "1").await.is_some());
+
+        // Objects larger than the whole cache are not stored
+        store(&cache, "d", "1", b"ddddddddddddddd").await;
+        assert!(cache.get("bucket", "d", "1").await.is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_size_limit_counts_existing_entries() {
+        let dir = temp_dir("reopen");
+        let cache = Arc::new(Cache::new(&dir, 1024).unwrap());
+        store(&cache, "a", "1", b"aaaa").await;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store(&cache, "b", "1", b"bbbb").await;
+
+        // Reopening with a smaller cap keeps only what fits, newest first
+        let cache = Cache::new(&dir, 6).unwrap();
+        cache.evict().unwrap();
+        assert!(cache.get("bucket", "a", "1").await.is_none());
+        assert!(cache.get("bucket", "b", "1").await.is_some());
+        assert_eq!(*cache.size.lock().unwrap(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_reads_are_not_stored() {
+        let dir = temp_dir("partial");
+        let cache = Arc::new(Cache::new(&dir, 1024).unwrap());
+        let mut reader = cache.writer("bucket", "a", "1", &b"aaaabbbb"[..]);
+        let mut start = [0; 4];
+        reader.read_exact(&mut start).await.unwrap();
+        drop(reader);
+
+        // The writer removes its temporary file once it sees the reader is gone
+        for _ in 0..100 {
+            if entries_and_temp_files(&dir) == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(entries_and_temp_files(&dir), 0);
+        assert!(cache.get("bucket", "a", "1").await.is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn entries_and_temp_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|object| fs::read_dir(object.unwrap().path()).unwrap().count())
+            .sum()
+    }
+}
@@ -0,0 +1,381 @@
+//! Checkpoints for resuming long searches.
+//!
+//! ListObjectsV2 returns keys in order, so progress is recorded as a key that
+//! every object up to has finished, plus the keys after it that finished out of
+//! order. Objects that failed count as finished, so progress keeps moving past
+//! them, but they are saved in their own set and are not completed: a resumed
+//! search lists again from before the first of them and retries them. The
+//! options that decide what matches are saved too, since a search resumed with
+//! different ones would give results that belong to neither.
+//!
+//! Checkpoints are saved as JSON:
+//!
+//! ```json
+//! {"version":3,"bucket":"my-bucket","prefix":"logs/","pattern":"timeout",
+//!  "options":{"case-sensitive":false,"format":null,"where":[]},
+//!  "start_after":"logs/2024-01-04.log","completed":["logs/2024-01-06.log"],
+//!  "failed":["logs/2024-01-02.log"]}
+//! ```
+
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Version of the checkpoint file format.
+const VERSION: u64 = 3;
+
+/// Oldest version that can still be read; version 2 had no failed keys.
+const MIN_VERSION: u64 = 2;
+
+/// Progress of a search over a bucket and prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub bucket: String,
+    pub prefix: String,
+    pub pattern: String,
+    /// Options that decide what matches, keyed by flag name
+    pub options: Map<String, Value>,
+    /// Every key up to and including this one has finished
+    start_after: Option<String>,
+    /// Finished keys after `start_after`
+    completed: BTreeSet<String>,
+    /// Keys that failed and must be searched again
+    failed: BTreeSet<String>,
+    /// Listed keys that have not been searched yet (not saved)
+    pending: BTreeSet<String>,
+}
+
+impl Checkpoint {
+    /// Creates an empty checkpoint for a search.
+    pub fn new(bucket: &str, prefix: &str, pattern: &str) -> Self {
+        Checkpoint {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            pattern: pattern.to_string(),
+            options: Map::new(),
+            start_after: None,
+            completed: BTreeSet::new(),
+            failed: BTreeSet::new(),
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// Records the options that decide what matches, keyed by flag name.
+    pub fn with_options(mut self, options: Map<String, Value>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /**
+        Returns the names of the options whose values differ from `options`.
+
+        # Examples
+
+        ```
+        use s3grep::checkpoint::Checkpoint;
+        use serde_json::json;
+        let options = |case_sensitive: bool| {
+            json!({"case-sensitive": case_sensitive, "format": "alb"})
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+        let checkpoint = Checkpoint::new("bucket", "", "error").with_options(options(false));
+        assert!(checkpoint.different_options(&options(false)).is_empty());
+        assert_eq!(checkpoint.different_options(&options(true)), ["case-sensitive"]);
+        ```
+    */
+    pub fn different_options(&self, options: &Map<String, Value>) -> Vec<String> {
+        let names: BTreeSet<&String> = self
+            .options
+            .keys()
+            .chain(options.keys())
+            .filter(|name| self.options.get(*name) != options.get(*name))
+            .collect();
+        names.into_iter().cloned().collect()
+    }
+
+    /// The key to start listing after when resuming, before the first failed key.
+    pub fn start_after(&self) -> Option<&str> {
+        match self.failed.first() {
+            // A key without its last character sorts just before it
+            Some(failed)
+                if self
+                    .start_after
+                    .as_ref()
+                    .is_some_and(|after| failed <= after) =>
+            {
+                let before = &failed[..failed.char_indices().last().map_or(0, |(i, _)| i)];
+                (!before.is_empty()).then_some(before)
+            }
+            _ => self.start_after.as_deref(),
+        }
+    }
+
+    /// Returns true if the object has already been searched.
+    pub fn is_completed(&self, key: &str) -> bool {
+        !self.failed.contains(key)
+            && (self
+                .start_after
+                .as_deref()
+                .is_some_and(|after| key <= after)
+                || self.completed.contains(key))
+    }
+
+    /// Records that a key was listed, in listing order.
+    pub fn listed(&mut self, key: &str) {
+        self.pending.insert(key.to_string());
+    }
+
+    /**
+        Records that an object has been searched.
+
+        # Examples
+
+        ```
+        use s3grep::checkpoint::Checkpoint;
+        let mut checkpoint = Checkpoint::new("bucket", "", "error");
+        for key in ["a", "b", "c"] {
+            checkpoint.listed(key);
+        }
+        checkpoint.complete("b");
+        assert_eq!(checkpoint.start_after(), None);
+        checkpoint.complete("a");
+        assert_eq!(checkpoint.start_after(), Some("b"));
+        assert!(!checkpoint.is_completed("c"));
+        ```
+    */
+    pub fn complete(&mut self, key: &str) {
+        self.failed.remove(key);
+        self.finish(key);
+    }
+
+    /**
+        Records that searching an object failed, so a resumed search retries it.
+
+        # Examples
+
+        ```
+        use s3grep::checkpoint::Checkpoint;
+        let mut checkpoint = Checkpoint::new("bucket", "", "error");
+        for key in ["a", "b1", "b2", "c"] {
+            checkpoint.listed(key);
+        }
+        checkpoint.complete("a");
+        checkpoint.fail("b1");
+        checkpoint.complete("b2");
+        assert!(checkpoint.is_completed("b2"));
+        assert!(!checkpoint.is_completed("b1"));
+        // Resuming lists again from before the failed key
+        assert_eq!(checkpoint.start_after(), Some("b"));
+        ```
+    */
+    pub fn fail(&mut self, key: &str) {
+        self.failed.insert(key.to_string());
+        self.finish(key);
+    }
+
+    fn finish(&mut self, key: &str) {
+        self.pending.remove(key);
+        // Failed keys listed again on resume may be before the saved progress
+        if self.start_after.as_deref().is_none_or(|after| key > after) {
+            self.completed.insert(key.to_string());
+        }
+        // Keys are listed in order, so every key before the first pending one
+        // has been listed, and those not pending are done
+        while let Some(first) = self.completed.first() {
+            if self.pending.first().is_some_and(|pending| pending < first) {
+                break;
+            }
+            self.start_after = self.completed.pop_first();
+        }
+    }
+
+    /// Serializes the saved part of the checkpoint.
+    pub fn to_json(&self) -> String {
+        json!({
+            "version": VERSION,
+            "bucket": self.bucket,
+            "prefix": self.prefix,
+            "pattern": self.pattern,
+            "options": self.options,
+            "start_after": self.start_after,
+            "completed": self.completed,
+            "failed": self.failed,
+        })
+        .to_string()
+    }
+
+    /// Parses a checkpoint saved with [`Checkpoint::to_json`].
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|e| format!("invalid checkpoint: {e}"))?;
+        if !value["version"]
+            .as_u64()
+            .is_some_and(|version| (MIN_VERSION..=VERSION).contains(&version))
+        {
+            return Err(
+                "unsupported checkpoint version (saved by another version of s3grep)".to_string(),
+            );
+        }
+        let string = |field: &str| {
+            value[field]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("invalid checkpoint: missing {field}"))
+        };
+        let keys = |field: &str, value: &Value| {
+            value
+                .as_array()
+                .ok_or_else(|| format!("invalid checkpoint: missing {field}"))?
+                .iter()
+                .map(|key| key.as_str().map(str::to_string))
+                .collect::<Option<BTreeSet<_>>>()
+                .ok_or_else(|| format!("invalid checkpoint: {field} keys must be strings"))
+        };
+        let completed = keys("completed", &value["completed"])?;
+        let failed = match &value["failed"] {
+            Value::Null => BTreeSet::new(),
+            failed => keys("failed", failed)?,
+        };
+        Ok(Checkpoint {
+            bucket: string("bucket")?,
+            prefix: string("prefix")?,
+            pattern: string("pattern")?,
+            options: value["options"]
+                .as_object()
+                .cloned()
+                .ok_or("invalid checkpoint: missing options")?,
+            start_after: value["start_after"].as_str().map(str::to_string),
+            completed,
+            failed,
+            pending: BTreeSet::new(),
+        })
+    }
+
+    /// Loads a checkpoint from a file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read checkpoint {}: {e}", path.display()))?;
+        Self::from_json(&text)
+    }
+}
+
+/// A checkpoint that is saved to a file as objects complete, at most once per interval.
+#[derive(Debug)]
+pub struct CheckpointFile {
+    path: PathBuf,
+    interval: Duration,
+    state: Mutex<(Checkpoint, Instant)>,
+}
+
+impl CheckpointFile {
+    /// Creates a checkpoint file at `path` starting from `checkpoint`.
+    pub fn new(path: impl Into<PathBuf>, checkpoint: Checkpoint, interval: Duration) -> Self {
+        CheckpointFile {
+            path: path.into(),
+            interval,
+            state: Mutex::new((checkpoint, Instant::now())),
+        }
+    }
+
+    /// Runs `f` on the checkpoint.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Checkpoint) -> T) -> T {
+        f(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()).0)
+    }
+
+    /// Records that an object has been searched, saving the checkpoint if it is due.
+    pub fn complete(&self, key: &str) -> io::Result<()> {
+        self.update(|checkpoint| checkpoint.complete(key))
+    }
+
+    /// Records that searching an object failed, saving the checkpoint if it is due.
+    pub fn fail(&self, key: &str) -> io::Result<()> {
+        self.update(|checkpoint| checkpoint.fail(key))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Checkpoint)) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut state.0);
+        if state.1.elapsed() >= self.interval {
+            save(&self.path, &state.0)?;
+            state.1 = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Saves the checkpoint now.
+    pub fn save(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        save(&self.path, &state.0)?;
+        state.1 = Instant::now();
+        Ok(())
+    }
+}
+
+/// Writes the checkpoint to a temporary file and renames it, so a crash never
+/// leaves a partial checkpoint behind.
+fn save(path: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, checkpoint.to_json())?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_keeps_progress() {
+        let options = json!({"case-sensitive": true, "where": ["elb_status_code>=500"]});
+        let mut checkpoint = Checkpoint::new("bucket", "logs/", "timeout")
+            .with_options(options.as_object().unwrap().clone());
+        for key in ["logs/a", "logs/b", "logs/c", "logs/d"] {
+            checkpoint.listed(key);
+        }
+        checkpoint.complete("logs/a");
+        checkpoint.complete("logs/c");
+
+        let restored = Checkpoint::from_json(&checkpoint.to_json()).unwrap();
+        assert_eq!(restored.start_after(), Some("logs/a"));
+        assert!(restored.is_completed("logs/a"));
+        assert!(!restored.is_completed("logs/b"));
+        assert!(restored.is_completed("logs/c"));
+        assert!(!restored.is_completed("logs/d"));
+        assert_eq!(restored.pattern, "timeout");
+        assert_eq!(restored.options, checkpoint.options);
+
+        assert!(Checkpoint::from_json("{\"version\":1}").is_err());
+        assert!(Checkpoint::from_json("{\"version\":2}").is_err());
+    }
+
+    #[test]
+    fn test_failed_keys_do_not_hold_back_progress() {
+        let mut checkpoint = Checkpoint::new("bucket", "logs/", "timeout");
+        for key in ["logs/a", "logs/b", "logs/c"] {
+            checkpoint.listed(key);
+        }
+        checkpoint.fail("logs/a");
+        checkpoint.complete("logs/b");
+        checkpoint.complete("logs/c");
+        assert!(checkpoint.completed.is_empty());
+        assert!(checkpoint.pending.is_empty());
+
+        // Resuming lists the failed key again and skips the rest
+        let mut restored = Checkpoint::from_json(&checkpoint.to_json()).unwrap();
+        assert_eq!(restored.start_after(), Some("logs/"));
+        assert!(!restored.is_completed("logs/a"));
+        assert!(restored.is_completed("logs/b"));
+        assert!(restored.is_completed("logs/c"));
+
+        restored.listed("logs/a");
+        restored.complete("logs/a");
+        assert!(restored.failed.is_empty());
+        assert!(restored.completed.is_empty());
+        assert_eq!(restored.start_after(), Some("logs/c"));
+    }
+}
@@ -0,0 +1,386 @@
+//! Column-aware searching of CSV and TSV objects.
+//!
+//! Unlike the line-oriented search, records are split with a small streaming
+//! parser that understands quoted fields, so delimiters and newlines inside
+//! quotes do not break a row apart.
+
+use crate::line_matches;
+use crate::scan::LongLines;
+use std::fmt;
+use std::str::FromStr;
+
+/// Selects the column to search, either by header name or by 1-based index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(0) => Err("column indexes start at 1".to_string()),
+            Ok(index) => Ok(Column::Index(index)),
+            Err(_) => Ok(Column::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Name(name) => f.write_str(name),
+            Column::Index(index) => write!(f, "{index}"),
+        }
+    }
+}
+
+impl Column {
+    /// Resolves the column against a header row, returning a 0-based position.
+    pub fn position(&self, header: &[String]) -> Option<usize> {
+        match self {
+            Column::Name(name) => header.iter().position(|h| h == name),
+            Column::Index(index) => (*index <= header.len()).then(|| index - 1),
+        }
+    }
+}
+
+/// A parsed record and the (1-based) line number it starts on.
+pub type Row = (usize, Vec<String>);
+
+/// Streaming parser that splits delimited data into records.
+///
+/// Bytes are fed in arbitrary chunks; complete records are returned as soon as
+/// their terminating newline is seen.
+#[derive(Debug)]
+pub struct RecordReader {
+    delimiter: u8,
+    field: Vec<u8>,
+    record: Vec<String>,
+    in_quotes: bool,
+    quote_closed: bool,
+    line: usize,
+    record_start: usize,
+    /// Bytes of field data kept for the current record
+    record_len: usize,
+    /// True if the current record has grown past `max_record_length`
+    overflow: bool,
+    max_record_length: usize,
+    long_record_action: LongLines,
+    long_records: usize,
+}
+
+impl RecordReader {
+    /// Creates a reader for the given field delimiter (`b','` or `b'\t'`).
+    pub fn new(delimiter: u8) -> Self {
+        RecordReader {
+            delimiter,
+            field: Vec::new(),
+            record: Vec::new(),
+            in_quotes: false,
+            quote_closed: false,
+            line: 1,
+            record_start: 1,
+            record_len: 0,
+            overflow: false,
+            max_record_length: usize::MAX,
+            long_record_action: LongLines::Truncate,
+            long_records: 0,
+        }
+    }
+
+    /**
+        Limits the field data kept for a record to `max` bytes (0 for no limit).
+
+        Longer records, such as everything after an unclosed quote, keep only
+        their first `max` bytes of fields, or are skipped entirely.
+
+        # Examples
+
+        ```
+        use s3grep::delimited::RecordReader;
+        use s3grep::scan::LongLines;
+        let mut reader = RecordReader::new(b',').with_max_record_length(4, LongLines::Skip);
+        let mut rows = Vec::new();
+        reader.feed(b"a,b\n\"unclosed,quote\nc,d\n", &mut rows);
+        assert_eq!(rows, vec![(1, vec!["a".to_string(), "b".to_string()])]);
+        assert_eq!(reader.finish(), None);
+        assert_eq!(reader.long_records(), 1);
+        ```
+    */
+    pub fn with_max_record_length(mut self, max: usize, action: LongLines) -> Self {
+        self.max_record_length = if max == 0 { usize::MAX } else { max };
+        self.long_record_action = action;
+        self
+    }
+
+    /// Number of records longer than the maximum record length seen so far.
+    pub fn long_records(&self) -> usize {
+        self.long_records
+    }
+
+    /// Feeds a chunk of bytes, appending any completed records to `rows`.
+    pub fn feed(&mut self, bytes: &[u8], rows: &mut Vec<Row>) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.line += 1;
+            }
+
+            if self.in_quotes {
+                if byte == b'"' {
+                    self.in_quotes = false;
+                    self.quote_closed = true;
+                } else {
+                    self.push(byte);
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' if self.quote_closed => {
+                    // An escaped quote ("") inside a quoted field
+                    self.push(b'"');
+                    self.in_quotes = true;
+                    self.quote_closed = false;
+                }
+                b'"' if self.field.is_empty() => self.in_quotes = true,
+                b'\n' => {
+                    if self.field.last() == Some(&b'\r') && !self.quote_closed {
+                        self.field.pop();
+                    }
+                    self.end_field();
+                    rows.extend(self.take_record());
+                }
+                _ if byte == self.delimiter => self.end_field(),
+                b'\r' if self.quote_closed => {}
+                _ => {
+                    self.quote_closed = false;
+                    self.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Returns the final record if the data did not end with a newline.
+    pub fn finish(&mut self) -> Option<Row> {
+        if self.field.is_empty() && self.record.is_empty() && !self.quote_closed && !self.overflow {
+            return None;
+        }
+        self.end_field();
+        self.take_record()
+    }
+
+    /// Adds a byte to the current field unless the record is already full.
+    fn push(&mut self, byte: u8) {
+        if self.record_len < self.max_record_length {
+            self.field.push(byte);
+            self.record_len += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn end_field(&mut self) {
+        let field = String::from_utf8_lossy(&self.field).to_string();
+        self.record.push(field);
+        self.field.clear();
+        self.quote_closed = false;
+    }
+
+    /// Ends the current record, returning it unless it is a long one to skip.
+    fn take_record(&mut self) -> Option<Row> {
+        let start = self.record_start;
+        self.record_start = self.line;
+        let record = std::mem::take(&mut self.record);
+        self.record_len = 0;
+        let skip = self.overflow && self.long_record_action == LongLines::Skip;
+        if self.overflow {
+            self.long_records += 1;
+            self.overflow = false;
+        }
+        (!skip).then_some((start, record))
+    }
+}
+
+/// A CSV/TSV query: the delimiter and an optional column to restrict matching to.
+#[derive(Debug, Clone)]
+pub struct DelimitedQuery {
+    pub delimiter: u8,
+    pub column: Option<Column>,
+}
+
+impl DelimitedQuery {
+    /**
+        Resolves the selected column against the header row of an object.
+
+        Returns the column's 0-based position, `None` if every column is
+        searched, or an error if the column is not in the header.
+
+        # Examples
+
+        ```
+        use s3grep::delimited::{Column, DelimitedQuery};
+        let query = DelimitedQuery { delimiter: b',', column: Some(Column::Name("level".into())) };
+        let header = vec!["level".to_string(), "msg".to_string()];
+        assert_eq!(query.resolve(&header), Ok(Some(0)));
+        assert!(query.resolve(&["lvl".to_string()]).is_err());
+        ```
+    */
+    pub fn resolve(&self, header: &[String]) -> Result<Option<usize>, String> {
+        match &self.column {
+            Some(column) => column
+                .position(header)
+                .map(Some)
+                .ok_or_else(|| format!("column {column} is not in the header")),
+            None => Ok(None),
+        }
+    }
+
+    /**
+        Tests a data row against the pattern.
+
+        # Arguments
+
+        * `header` - The header row of the object.
+        * `row` - The data row to test.
+        * `column` - The position of the column to search, from
+          [`resolve`](Self::resolve); if `None`, every column is searched.
+        * `pattern` - The pattern to search for.
+        * `case_sensitive` - If true, the search is case sensitive.
+
+        # Returns
+
+        The row rendered as a JSON object keyed by column name if it matches,
+        `None` otherwise.
+
+        # Examples
+
+        ```
+        use s3grep::delimited::{Column, DelimitedQuery};
+        let query = DelimitedQuery { delimiter: b',', column: Some(Column::Name("level".into())) };
+        let header = vec!["level".to_string(), "msg".to_string()];
+        let row = vec!["ERROR".to_string(), "disk full".to_string()];
+        let column = query.resolve(&header).unwrap();
+        assert_eq!(
+            query.matches(&header, &row, column, "error", false).as_deref(),
+            Some(r#"{"level":"ERROR","msg":"disk full"}"#)
+        );
+        assert!(query.matches(&header, &row, column, "disk", false).is_none());
+        ```
+    */
+    pub fn matches(
+        &self,
+        header: &[String],
+        row: &[String],
+        column: Option<usize>,
+        pattern: &str,
+        case_sensitive: bool,
+    ) -> Option<String> {
+        let matched = match column {
+            Some(position) => row
+                .get(position)
+                .is_some_and(|value| line_matches(value, pattern, case_sensitive)),
+            None => row
+                .iter()
+                .any(|value| line_matches(value, pattern, case_sensitive)),
+        };
+
+        matched.then(|| render_row(header, row))
+    }
+}
+
+/// Renders a row as a JSON object keyed by header name, in column order.
+///
+/// Columns without a header entry are keyed by their 1-based index.
+pub fn render_row(header: &[String], row: &[String]) -> String {
+    let fields: Vec<String> = row
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let name = header
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| (i + 1).to_string());
+            format!(
+                "{}:{}",
+                serde_json::Value::String(name),
+                serde_json::Value::String(value.clone())
+            )
+        })
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8], delimiter: u8, chunk: usize) -> Vec<Row> {
+        let mut reader = RecordReader::new(delimiter);
+        let mut rows = Vec::new();
+        for bytes in data.chunks(chunk) {
+            reader.feed(bytes, &mut rows);
+        }
+        rows.extend(reader.finish());
+        rows
+    }
+
+    #[test]
+    fn test_quoted_fields_and_embedded_newlines() {
+        let data = b"id,msg\r\n1,\"hello, \"\"world\"\"\"\r\n2,\"multi\nline\"\n3,plain";
+        for chunk in [1, 3, data.len()] {
+            let rows = parse(data, b',', chunk);
+            assert_eq!(
+                rows,
+                vec![
+                    (1, vec!["id".to_string(), "msg".to_string()]),
+                    (2, vec!["1".to_string(), "hello, \"world\"".to_string()]),
+                    (3, vec!["2".to_string(), "multi\nline".to_string()]),
+                    (5, vec!["3".to_string(), "plain".to_string()]),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_records_are_truncated() {
+        let data = b"id,msg\n1,\"abcdefgh\n2,x\n";
+        for chunk in [1, 3, data.len()] {
+            let mut reader = RecordReader::new(b',').with_max_record_length(5, LongLines::Truncate);
+            let mut rows = Vec::new();
+            for bytes in data.chunks(chunk) {
+                reader.feed(bytes, &mut rows);
+            }
+            rows.extend(reader.finish());
+            // The unclosed quote runs to the end of the data
+            assert_eq!(
+                rows,
+                vec![
+                    (1, vec!["id".to_string(), "msg".to_string()]),
+                    (2, vec!["1".to_string(), "abcd".to_string()]),
+                ]
+            );
+            assert_eq!(reader.long_records(), 1);
+        }
+    }
+
+    #[test]
+    fn test_tsv_and_empty_fields() {
+        let rows = parse(b"a\t\tc\n", b'\t', 4);
+        assert_eq!(
+            rows,
+            vec![(1, vec!["a".to_string(), String::new(), "c".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_column_position() {
+        let header = vec!["a".to_string(), "b".to_string()];
+        assert_eq!("b".parse::<Column>().unwrap().position(&header), Some(1));
+        assert_eq!("1".parse::<Column>().unwrap().position(&header), Some(0));
+        assert_eq!("3".parse::<Column>().unwrap().position(&header), None);
+        assert!("0".parse::<Column>().is_err());
+    }
+}
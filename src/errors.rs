@@ -0,0 +1,253 @@
+//! Classifying and counting the errors of a search.
+//!
+//! Errors from individual objects do not stop a search, so they are counted by
+//! kind and summarized at the end, and decide the exit status.
+
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
+use aws_sdk_s3::operation::select_object_content::SelectObjectContentError;
+use aws_sdk_s3::types::error::SelectObjectContentEventStreamError;
+use aws_smithy_types::event_stream::RawMessage;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+/// What went wrong, broadly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    /// Permission to list or read was denied
+    AccessDenied,
+    /// The object disappeared between listing and reading
+    NoSuchKey,
+    /// The object could not be decompressed or decoded
+    Decode,
+    /// A request or read timed out
+    Timeout,
+    /// Anything else
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::AccessDenied => "AccessDenied",
+            ErrorKind::NoSuchKey => "NoSuchKey",
+            ErrorKind::Decode => "decode error",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Other => "other",
+        })
+    }
+}
+
+/**
+    Classifies an error by looking through it and its sources.
+
+    # Examples
+
+    ```
+    use s3grep::errors::{classify, ErrorKind};
+    use std::io;
+    let error = io::Error::new(io::ErrorKind::InvalidData, "corrupt deflate stream");
+    assert_eq!(classify(&error), ErrorKind::Decode);
+    let error = io::Error::new(io::ErrorKind::TimedOut, "read timed out");
+    assert_eq!(classify(&error), ErrorKind::Timeout);
+    ```
+*/
+pub fn classify(error: &(dyn Error + 'static)) -> ErrorKind {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(kind) = classify_one(error) {
+            return kind;
+        }
+        current = error.source();
+    }
+    ErrorKind::Other
+}
+
+fn classify_one(error: &(dyn Error + 'static)) -> Option<ErrorKind> {
+    macro_rules! sdk_errors {
+        ($($operation:ty),*) => {
+            $(
+                if let Some(error) = error.downcast_ref::<SdkError<$operation, HttpResponse>>() {
+                    return sdk_kind(error);
+                }
+            )*
+        };
+    }
+    sdk_errors!(
+        GetObjectError,
+        HeadObjectError,
+        ListObjectsV2Error,
+        SelectObjectContentError
+    );
+
+    if let Some(error) = error.downcast_ref::<io::Error>() {
+        return match error.kind() {
+            io::ErrorKind::TimedOut => Some(ErrorKind::Timeout),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Some(ErrorKind::Decode),
+            // A custom error's source() skips the wrapped error itself
+            _ => error.get_ref().map(|inner| classify(inner)),
+        };
+    }
+    if error.is::<parquet::errors::ParquetError>() || error.is::<serde_json::Error>() {
+        return Some(ErrorKind::Decode);
+    }
+    None
+}
+
+fn sdk_kind<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> Option<ErrorKind> {
+    match error {
+        SdkError::TimeoutError(_) => Some(ErrorKind::Timeout),
+        SdkError::DispatchFailure(failure) if failure.is_timeout() => Some(ErrorKind::Timeout),
+        SdkError::ServiceError(_) => match error.code() {
+            Some("AccessDenied") => Some(ErrorKind::AccessDenied),
+            Some("NoSuchKey") | Some("NotFound") => Some(ErrorKind::NoSuchKey),
+            _ => Some(ErrorKind::Other),
+        },
+        _ => None,
+    }
+}
+
+/// S3 Select error codes for objects, formats and expressions it cannot handle.
+const SELECT_UNSUPPORTED: &[&str] = &[
+    "InvalidRequest",
+    "NotImplemented",
+    "MethodNotAllowed",
+    "InvalidCompressionFormat",
+    "InvalidDataSource",
+    "InvalidFileHeaderInfo",
+    "InvalidQuoteFields",
+    "InvalidTextEncoding",
+    "CSVParsingError",
+    "JSONParsingError",
+    "OverMaxColumn",
+    "OverMaxRecordSize",
+];
+
+/**
+    Returns true if S3 Select rejected an object, its format or the expression,
+    so the object can still be searched with a regular download.
+
+    Anything else, such as AccessDenied, throttling or a timeout, would most
+    likely fail the download too, or is worth reporting rather than quietly
+    paying to read the object a second time.
+*/
+pub fn select_unsupported(error: &(dyn Error + 'static)) -> bool {
+    fn unsupported<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> bool {
+        matches!(error, SdkError::ServiceError(_))
+            && error.code().is_some_and(|code| {
+                SELECT_UNSUPPORTED.contains(&code)
+                    || code.starts_with("Unsupported")
+                    || code.starts_with("ParseUnsupported")
+            })
+    }
+    if let Some(error) = error.downcast_ref::<SdkError<SelectObjectContentError, HttpResponse>>() {
+        return unsupported(error);
+    }
+    if let Some(error) =
+        error.downcast_ref::<SdkError<SelectObjectContentEventStreamError, RawMessage>>()
+    {
+        return unsupported(error);
+    }
+    false
+}
+
+/// Counts of errors by kind, shared by concurrent searches.
+#[derive(Debug, Default)]
+pub struct ErrorCounts {
+    counts: Mutex<BTreeMap<ErrorKind, u64>>,
+}
+
+impl ErrorCounts {
+    /// Counts an error of the given kind.
+    pub fn record(&self, kind: ErrorKind) {
+        *self
+            .counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(kind)
+            .or_default() += 1;
+    }
+
+    /// The total number of errors.
+    pub fn total(&self) -> u64 {
+        self.counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .sum()
+    }
+}
+
+impl fmt::Display for ErrorCounts {
+    /// Formats a summary such as `3 errors (2 AccessDenied, 1 timeout)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let total: u64 = counts.values().sum();
+        write!(f, "{total} error{}", if total == 1 { "" } else { "s" })?;
+        let kinds: Vec<_> = counts
+            .iter()
+            .map(|(kind, count)| format!("{count} {kind}"))
+            .collect();
+        if !kinds.is_empty() {
+            write!(f, " ({})", kinds.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_looks_inside_wrapped_errors() {
+        let inner = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let outer = io::Error::other(inner);
+        assert_eq!(classify(&outer), ErrorKind::Timeout);
+
+        let error: Box<dyn Error> = "unexpected".into();
+        assert_eq!(classify(error.as_ref()), ErrorKind::Other);
+    }
+
+    fn select_error(code: &str) -> SdkError<SelectObjectContentError, HttpResponse> {
+        use aws_sdk_s3::error::ErrorMetadata;
+        use aws_smithy_types::body::SdkBody;
+        SdkError::service_error(
+            SelectObjectContentError::generic(ErrorMetadata::builder().code(code).build()),
+            HttpResponse::new(400.try_into().unwrap(), SdkBody::empty()),
+        )
+    }
+
+    #[test]
+    fn test_select_falls_back_only_when_unsupported() {
+        for code in [
+            "InvalidRequest",
+            "UnsupportedSqlOperation",
+            "CSVParsingError",
+        ] {
+            assert!(select_unsupported(&select_error(code)), "{code}");
+        }
+        for code in ["AccessDenied", "SlowDown", "InternalError"] {
+            assert!(!select_unsupported(&select_error(code)), "{code}");
+        }
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        assert!(!select_unsupported(&timeout));
+    }
+
+    #[test]
+    fn test_summary_counts_by_kind() {
+        let counts = ErrorCounts::default();
+        assert_eq!(counts.to_string(), "0 errors");
+        counts.record(ErrorKind::Timeout);
+        counts.record(ErrorKind::AccessDenied);
+        counts.record(ErrorKind::AccessDenied);
+        assert_eq!(counts.total(), 3);
+        assert_eq!(counts.to_string(), "3 errors (2 AccessDenied, 1 timeout)");
+    }
+}
@@ -0,0 +1,628 @@
+//! Parsers for AWS service log formats.
+//!
+//! Each supported format splits a log line into named fields so that lines can
+//! be filtered with `--where` expressions and printed as selected columns.
+//! Field names follow the column names used by the Athena tables that AWS
+//! documents for each log type.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+const ALB_FIELDS: &[&str] = &[
+    "type",
+    "time",
+    "elb",
+    "client_ip",
+    "client_port",
+    "target_ip",
+    "target_port",
+    "request_processing_time",
+    "target_processing_time",
+    "response_processing_time",
+    "elb_status_code",
+    "target_status_code",
+    "received_bytes",
+    "sent_bytes",
+    "request_verb",
+    "request_url",
+    "request_proto",
+    "user_agent",
+    "ssl_cipher",
+    "ssl_protocol",
+    "target_group_arn",
+    "trace_id",
+    "domain_name",
+    "chosen_cert_arn",
+    "matched_rule_priority",
+    "request_creation_time",
+    "actions_executed",
+    "redirect_url",
+    "lambda_error_reason",
+    "target_port_list",
+    "target_status_code_list",
+    "classification",
+    "classification_reason",
+    "conn_trace_id",
+];
+
+const CLB_FIELDS: &[&str] = &[
+    "time",
+    "elb",
+    "client_ip",
+    "client_port",
+    "backend_ip",
+    "backend_port",
+    "request_processing_time",
+    "backend_processing_time",
+    "response_processing_time",
+    "elb_status_code",
+    "backend_status_code",
+    "received_bytes",
+    "sent_bytes",
+    "request_verb",
+    "request_url",
+    "request_proto",
+    "user_agent",
+    "ssl_cipher",
+    "ssl_protocol",
+];
+
+const CLOUDFRONT_FIELDS: &[&str] = &[
+    "date",
+    "time",
+    "location",
+    "bytes",
+    "request_ip",
+    "method",
+    "host",
+    "uri",
+    "status",
+    "referrer",
+    "user_agent",
+    "query_string",
+    "cookie",
+    "result_type",
+    "request_id",
+    "host_header",
+    "request_protocol",
+    "request_bytes",
+    "time_taken",
+    "xforwarded_for",
+    "ssl_protocol",
+    "ssl_cipher",
+    "response_result_type",
+    "http_version",
+    "fle_status",
+    "fle_encrypted_fields",
+    "c_port",
+    "time_to_first_byte",
+    "x_edge_detailed_result_type",
+    "sc_content_type",
+    "sc_content_len",
+    "sc_range_start",
+    "sc_range_end",
+];
+
+const S3_ACCESS_FIELDS: &[&str] = &[
+    "bucket_owner",
+    "bucket",
+    "request_datetime",
+    "remote_ip",
+    "requester",
+    "request_id",
+    "operation",
+    "key",
+    "request_uri",
+    "http_status",
+    "error_code",
+    "bytes_sent",
+    "object_size",
+    "total_time",
+    "turn_around_time",
+    "referrer",
+    "user_agent",
+    "version_id",
+    "host_id",
+    "signature_version",
+    "cipher_suite",
+    "authentication_type",
+    "host_header",
+    "tls_version",
+    "access_point_arn",
+    "acl_required",
+];
+
+const VPC_FLOW_FIELDS: &[&str] = &[
+    "version",
+    "account_id",
+    "interface_id",
+    "srcaddr",
+    "dstaddr",
+    "srcport",
+    "dstport",
+    "protocol",
+    "packets",
+    "bytes",
+    "start",
+    "end",
+    "action",
+    "log_status",
+];
+
+/// A supported AWS log format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Application Load Balancer access logs.
+    Alb,
+    /// Classic Load Balancer access logs.
+    Clb,
+    /// CloudFront standard (tab separated) access logs.
+    CloudFront,
+    /// S3 server access logs.
+    S3Access,
+    /// VPC Flow Logs in the default (version 2) format.
+    VpcFlow,
+    /// AWS WAF logs (one JSON document per line).
+    Waf,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "alb" => Ok(LogFormat::Alb),
+            "clb" | "elb" => Ok(LogFormat::Clb),
+            "cloudfront" => Ok(LogFormat::CloudFront),
+            "s3-access" => Ok(LogFormat::S3Access),
+            "vpc-flow" => Ok(LogFormat::VpcFlow),
+            "waf" => Ok(LogFormat::Waf),
+            other => Err(format!(
+                "unknown format '{other}' (expected alb, clb, cloudfront, s3-access, vpc-flow or waf)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Alb => "alb",
+            LogFormat::Clb => "clb",
+            LogFormat::CloudFront => "cloudfront",
+            LogFormat::S3Access => "s3-access",
+            LogFormat::VpcFlow => "vpc-flow",
+            LogFormat::Waf => "waf",
+        })
+    }
+}
+
+impl LogFormat {
+    /// Parses a single log line into a [`Record`].
+    ///
+    /// Returns `None` for header/comment lines and lines that cannot be parsed.
+    pub fn parse_line(&self, line: &str) -> Option<Record> {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            return None;
+        }
+        match self {
+            LogFormat::Alb => {
+                let tokens = tokenize(line);
+                // type time elb client:port target:port ... "request" ...
+                let mut values = Vec::with_capacity(ALB_FIELDS.len());
+                let mut tokens = tokens.into_iter();
+                values.extend(tokens.by_ref().take(3));
+                push_host_port(&mut values, tokens.next());
+                push_host_port(&mut values, tokens.next());
+                values.extend(tokens.by_ref().take(7));
+                push_request(&mut values, tokens.next());
+                values.extend(tokens);
+                Some(Record::from_values(ALB_FIELDS, values))
+            }
+            LogFormat::Clb => {
+                let tokens = tokenize(line);
+                let mut values = Vec::with_capacity(CLB_FIELDS.len());
+                let mut tokens = tokens.into_iter();
+                values.extend(tokens.by_ref().take(2));
+                push_host_port(&mut values, tokens.next());
+                push_host_port(&mut values, tokens.next());
+                values.extend(tokens.by_ref().take(7));
+                push_request(&mut values, tokens.next());
+                values.extend(tokens);
+                Some(Record::from_values(CLB_FIELDS, values))
+            }
+            LogFormat::CloudFront => {
+                if line.starts_with('#') {
+                    return None;
+                }
+                let values = line.split('\t').map(str::to_string).collect();
+                Some(Record::from_values(CLOUDFRONT_FIELDS, values))
+            }
+            LogFormat::S3Access => Some(Record::from_values(S3_ACCESS_FIELDS, tokenize(line))),
+            LogFormat::VpcFlow => {
+                if line.starts_with("version ") {
+                    return None;
+                }
+                Some(Record::from_values(VPC_FLOW_FIELDS, tokenize(line)))
+            }
+            LogFormat::Waf => {
+                let value: serde_json::Value = serde_json::from_str(line).ok()?;
+                let mut fields = Vec::new();
+                flatten_json("", &value, &mut fields);
+                Some(Record { fields })
+            }
+        }
+    }
+}
+
+/// A log line split into named fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    fields: Vec<(String, String)>,
+}
+
+impl Record {
+    fn from_values(names: &[&str], values: Vec<String>) -> Self {
+        Record {
+            fields: names
+                .iter()
+                .zip(values)
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+
+    /// Returns the value of the named field, if present.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Splits a space separated log line, honouring `"quoted"` and `[bracketed]` fields.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+        match c {
+            '"' => {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                token.push(escaped);
+                            }
+                        }
+                        '"' => break,
+                        _ => token.push(c),
+                    }
+                }
+            }
+            '[' => {
+                chars.next();
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    token.push(c);
+                }
+            }
+            _ => {
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+            }
+        }
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Splits an `ip:port` token into two values; `-` yields two `-` values.
+fn push_host_port(values: &mut Vec<String>, token: Option<String>) {
+    let Some(token) = token else { return };
+    match token.rsplit_once(':') {
+        Some((host, port)) => {
+            values.push(host.to_string());
+            values.push(port.to_string());
+        }
+        None => {
+            values.push(token.clone());
+            values.push(token);
+        }
+    }
+}
+
+/// Splits a `"VERB URL PROTO"` request token into its three parts.
+fn push_request(values: &mut Vec<String>, token: Option<String>) {
+    let Some(token) = token else { return };
+    let mut parts = token.splitn(3, ' ');
+    for _ in 0..3 {
+        values.push(parts.next().unwrap_or("-").to_string());
+    }
+}
+
+/// Flattens nested JSON objects into `parent.child` fields.
+fn flatten_json(prefix: &str, value: &serde_json::Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten_json(&name, value, fields);
+            }
+        }
+        serde_json::Value::String(s) => fields.push((prefix.to_string(), s.clone())),
+        other => fields.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+/// Comparison operator in a `--where` expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+/// A `--where` filter such as `elb_status_code>=500` or `client_ip=10.0.0.0/8`.
+///
+/// Supported operators are `=`, `!=`, `>`, `>=`, `<`, `<=` and `~` (substring).
+/// Values are compared numerically when both sides are numbers, as CIDR ranges
+/// when the filter value is a CIDR block and the field is an IP address, and
+/// as strings otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    field: String,
+    op: Op,
+    value: String,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')))
+            .ok_or_else(|| format!("invalid filter '{s}': missing operator"))?;
+        let (field, rest) = s.split_at(split);
+        if field.is_empty() {
+            return Err(format!("invalid filter '{s}': missing field name"));
+        }
+
+        let (op, len) = match rest.as_bytes() {
+            [b'!', b'=', ..] => (Op::Ne, 2),
+            [b'>', b'=', ..] => (Op::Ge, 2),
+            [b'<', b'=', ..] => (Op::Le, 2),
+            [b'=', ..] => (Op::Eq, 1),
+            [b'>', ..] => (Op::Gt, 1),
+            [b'<', ..] => (Op::Lt, 1),
+            [b'~', ..] => (Op::Contains, 1),
+            _ => return Err(format!("invalid filter '{s}': unknown operator")),
+        };
+
+        Ok(Filter {
+            field: field.to_string(),
+            op,
+            value: rest[len..].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Contains => "~",
+        };
+        write!(f, "{}{}{}", self.field, op, self.value)
+    }
+}
+
+impl Filter {
+    /// Returns true if the record satisfies this filter.
+    ///
+    /// Records that do not contain the field never match.
+    pub fn matches(&self, record: &Record) -> bool {
+        let Some(actual) = record.get(&self.field) else {
+            return false;
+        };
+
+        match self.op {
+            Op::Contains => actual.contains(&self.value),
+            Op::Eq | Op::Ne => {
+                let equal = match cidr_contains(&self.value, actual) {
+                    Some(contained) => contained,
+                    None => compare(actual, &self.value) == Ordering::Equal,
+                };
+                equal == (self.op == Op::Eq)
+            }
+            Op::Gt => compare(actual, &self.value) == Ordering::Greater,
+            Op::Ge => compare(actual, &self.value) != Ordering::Less,
+            Op::Lt => compare(actual, &self.value) == Ordering::Less,
+            Op::Le => compare(actual, &self.value) != Ordering::Greater,
+        }
+    }
+}
+
+/// Compares numerically when both values are numbers, lexically otherwise.
+fn compare(actual: &str, expected: &str) -> Ordering {
+    match (actual.parse::<f64>(), expected.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => actual.cmp(expected),
+    }
+}
+
+/// Returns `Some(true)` if `ip` falls within `cidr`, or `None` if either
+/// value is not an address of the same family.
+fn cidr_contains(cidr: &str, ip: &str) -> Option<bool> {
+    let (network, bits) = cidr.split_once('/')?;
+    let network: IpAddr = network.parse().ok()?;
+    let bits: u32 = bits.parse().ok()?;
+    let ip: IpAddr = ip.parse().ok()?;
+
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            Some(u32::from(network) & mask == u32::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            Some(u128::from(network) & mask == u128::from(ip) & mask)
+        }
+        _ => None,
+    }
+}
+
+/// A parsed-log query: the format, `--where` filters and output columns.
+#[derive(Debug, Clone)]
+pub struct FormatQuery {
+    pub format: LogFormat,
+    pub filters: Vec<Filter>,
+    pub fields: Vec<String>,
+}
+
+impl FormatQuery {
+    /**
+        Applies the query to a log line.
+
+        # Arguments
+
+        * `line` - The raw log line.
+
+        # Returns
+
+        `None` if the line cannot be parsed or fails a filter. Otherwise the text to
+        print: the selected fields joined by tabs, or the original line when no
+        fields were selected.
+
+        # Examples
+
+        ```
+        use s3grep::formats::{FormatQuery, LogFormat};
+        let query = FormatQuery {
+            format: LogFormat::VpcFlow,
+            filters: vec!["action=REJECT".parse().unwrap()],
+            fields: vec!["srcaddr".to_string(), "dstport".to_string()],
+        };
+        let line = "2 123456789010 eni-1235b8ca 172.31.9.69 172.31.9.12 49761 3389 6 20 4249 1418530010 1418530070 REJECT OK";
+        assert_eq!(query.apply(line).as_deref(), Some("172.31.9.69\t3389"));
+        ```
+    */
+    pub fn apply(&self, line: &str) -> Option<String> {
+        let record = self.format.parse_line(line)?;
+        if !self.filters.iter().all(|filter| filter.matches(&record)) {
+            return None;
+        }
+
+        if self.fields.is_empty() {
+            Some(line.to_string())
+        } else {
+            Some(
+                self.fields
+                    .iter()
+                    .map(|field| record.get(field).unwrap_or("-"))
+                    .collect::<Vec<_>>()
+                    .join("\t"),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALB_LINE: &str = r#"https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 502 200 0 57 "GET https://www.example.com:443/ HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012" 1 2018-07-02T22:22:48.364000Z "authenticate,forward" "-" "-" "10.0.0.1:80" "200" "-" "-" TID_1234"#;
+
+    #[test]
+    fn test_parse_alb_line() {
+        let record = LogFormat::Alb.parse_line(ALB_LINE).unwrap();
+        assert_eq!(record.get("client_ip"), Some("192.168.131.39"));
+        assert_eq!(record.get("target_port"), Some("80"));
+        assert_eq!(record.get("elb_status_code"), Some("502"));
+        assert_eq!(record.get("request_verb"), Some("GET"));
+        assert_eq!(record.get("user_agent"), Some("curl/7.46.0"));
+        assert_eq!(record.get("conn_trace_id"), Some("TID_1234"));
+    }
+
+    #[test]
+    fn test_parse_s3_access_line() {
+        let line = r#"79a5 awsexamplebucket1 [06/Feb/2019:00:00:38 +0000] 192.0.2.3 79a5 3E57427F3EXAMPLE REST.GET.VERSIONING - "GET /awsexamplebucket1?versioning HTTP/1.1" 200 - 113 - 7 - "-" "S3Console/0.4" -"#;
+        let record = LogFormat::S3Access.parse_line(line).unwrap();
+        assert_eq!(
+            record.get("request_datetime"),
+            Some("06/Feb/2019:00:00:38 +0000")
+        );
+        assert_eq!(record.get("operation"), Some("REST.GET.VERSIONING"));
+        assert_eq!(record.get("http_status"), Some("200"));
+        assert_eq!(record.get("user_agent"), Some("S3Console/0.4"));
+    }
+
+    #[test]
+    fn test_skip_header_lines() {
+        assert!(LogFormat::CloudFront.parse_line("#Version: 1.0").is_none());
+        assert!(LogFormat::VpcFlow
+            .parse_line("version account-id interface-id srcaddr")
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_waf_line() {
+        let line = r#"{"action":"BLOCK","httpRequest":{"clientIp":"1.2.3.4","country":"US"}}"#;
+        let record = LogFormat::Waf.parse_line(line).unwrap();
+        assert_eq!(record.get("action"), Some("BLOCK"));
+        assert_eq!(record.get("httpRequest.clientIp"), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn test_filters() {
+        let record = LogFormat::Alb.parse_line(ALB_LINE).unwrap();
+        let matches = |f: &str| f.parse::<Filter>().unwrap().matches(&record);
+
+        assert!(matches("elb_status_code>=500"));
+        assert!(!matches("elb_status_code<500"));
+        assert!(matches("client_ip=192.168.0.0/16"));
+        assert!(!matches("client_ip=10.0.0.0/8"));
+        assert!(matches("client_ip!=10.0.0.0/8"));
+        assert!(matches("user_agent~curl"));
+        assert!(!matches("no_such_field=1"));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert!("elb_status_code".parse::<Filter>().is_err());
+        assert!("=500".parse::<Filter>().is_err());
+        assert!("a^b".parse::<Filter>().is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! A persistent trigram index of objects.
+//!
+//! For each object the index stores the set of byte trigrams in its text,
+//! ASCII-lowercased so that one index serves both case sensitive and
+//! insensitive searches. A pattern can only match an object containing every
+//! trigram of the pattern, so a search can skip objects whose entry lacks one
+//! of them without downloading anything. Entries are keyed by bucket, key and
+//! ETag in the same layout as the object cache, so an entry is never used for
+//! a different version of its object.
+
+use crate::cache::{entry_path, remove_other_versions, temp_path};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Identifies index entry files.
+const MAGIC: &[u8; 4] = b"S3GI";
+
+/// Version of the entry format.
+const VERSION: u8 = 1;
+
+/// Objects with more distinct trigrams than this are stored as possibly
+/// matching anything, since nearly every pattern would pass their filter.
+pub const MAX_TRIGRAMS: usize = 1 << 20;
+
+/// Number of possible trigrams of bytes.
+const TRIGRAM_SPACE: usize = 1 << 24;
+
+/// What the index knows about one version of an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectIndex {
+    /// The object may match any pattern (too many trigrams, or not plain text)
+    Any,
+    /// The sorted, distinct trigrams of the object's text
+    Trigrams(Vec<u32>),
+}
+
+impl ObjectIndex {
+    /// Returns false only if the object cannot contain all of `trigrams`.
+    pub fn may_contain(&self, trigrams: &[u32]) -> bool {
+        match self {
+            ObjectIndex::Any => true,
+            ObjectIndex::Trigrams(own) => trigrams.iter().all(|t| own.binary_search(t).is_ok()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        match self {
+            ObjectIndex::Any => bytes.push(0),
+            ObjectIndex::Trigrams(trigrams) => {
+                bytes.push(1);
+                write_varint(&mut bytes, trigrams.len() as u32);
+                // Deltas between sorted trigrams are small and encode in a byte or two
+                let mut last = 0;
+                for &trigram in trigrams {
+                    write_varint(&mut bytes, trigram - last);
+                    last = trigram;
+                }
+            }
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid index entry");
+        let rest = bytes
+            .strip_prefix(MAGIC.as_slice())
+            .and_then(|rest| rest.strip_prefix(&[VERSION]))
+            .ok_or_else(invalid)?;
+        match rest.split_first() {
+            Some((0, _)) => Ok(ObjectIndex::Any),
+            Some((1, mut rest)) => {
+                let count = read_varint(&mut rest).ok_or_else(invalid)?;
+                let mut trigrams = Vec::with_capacity((count as usize).min(MAX_TRIGRAMS));
+                let mut last = 0u32;
+                for _ in 0..count {
+                    last = last
+                        .checked_add(read_varint(&mut rest).ok_or_else(invalid)?)
+                        .ok_or_else(invalid)?;
+                    trigrams.push(last);
+                }
+                Ok(ObjectIndex::Trigrams(trigrams))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Collects the trigrams of a text fed to it in pieces.
+#[derive(Debug)]
+pub struct TrigramBuilder {
+    /// One bit per possible trigram
+    seen: Vec<u64>,
+    count: usize,
+    /// The last three bytes, lowercased
+    window: u32,
+    /// Bytes fed so far, up to 2
+    filled: usize,
+}
+
+impl Default for TrigramBuilder {
+    fn default() -> Self {
+        TrigramBuilder {
+            seen: vec![0; TRIGRAM_SPACE / 64],
+            count: 0,
+            window: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl TrigramBuilder {
+    /// Adds the next piece of the text.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.window = ((self.window << 8) | byte.to_ascii_lowercase() as u32) & 0xff_ffff;
+            if self.filled < 2 {
+                self.filled += 1;
+                continue;
+            }
+            let (word, bit) = (self.window as usize / 64, self.window % 64);
+            if self.seen[word] & (1 << bit) == 0 {
+                self.seen[word] |= 1 << bit;
+                self.count += 1;
+            }
+        }
+    }
+
+    /**
+        Returns the index entry for the text fed so far.
+
+        # Examples
+
+        ```
+        use s3grep::index::{trigrams, ObjectIndex, TrigramBuilder};
+        let mut builder = TrigramBuilder::default();
+        builder.feed(b"conn");
+        builder.feed(b"ection TIMEOUT");
+        let entry = builder.finish();
+        assert!(entry.may_contain(&trigrams("Timeout", false).unwrap()));
+        assert!(entry.may_contain(&trigrams("connect", true).unwrap()));
+        assert!(!entry.may_contain(&trigrams("refused", false).unwrap()));
+        ```
+    */
+    pub fn finish(self) -> ObjectIndex {
+        if self.count > MAX_TRIGRAMS {
+            return ObjectIndex::Any;
+        }
+        let mut trigrams = Vec::with_capacity(self.count);
+        for (i, &word) in self.seen.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                trigrams.push((i * 64) as u32 + word.trailing_zeros());
+                word &= word - 1;
+            }
+        }
+        ObjectIndex::Trigrams(trigrams)
+    }
+}
+
+/**
+    Returns the trigrams an object must contain to match `pattern`.
+
+    Returns `None` when the index cannot rule objects out: for patterns shorter
+    than three bytes, and for case insensitive non-ASCII patterns, whose case
+    folding can change bytes the index does not fold.
+
+    # Examples
+
+    ```
+    use s3grep::index::trigrams;
+    assert_eq!(trigrams("abcd", true).unwrap().len(), 2);
+    assert_eq!(trigrams("ERROR", false), trigrams("error", true));
+    assert!(trigrams("ab", true).is_none());
+    assert!(trigrams("café", false).is_none());
+    ```
+*/
+pub fn trigrams(pattern: &str, case_sensitive: bool) -> Option<Vec<u32>> {
+    if pattern.len() < 3 || (!case_sensitive && !pattern.is_ascii()) {
+        return None;
+    }
+    let mut trigrams: Vec<u32> = pattern
+        .as_bytes()
+        .windows(3)
+        .map(|w| {
+            w.iter()
+                .fold(0, |t, b| (t << 8) | b.to_ascii_lowercase() as u32)
+        })
+        .collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    Some(trigrams)
+}
+
+/// A directory of object index entries.
+#[derive(Debug)]
+pub struct Index {
+    dir: PathBuf,
+}
+
+impl Index {
+    /// Opens (creating if needed) an index in `dir`.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Index { dir })
+    }
+
+    /// Returns the entry for this version of the object, if it has been indexed.
+    pub fn get(&self, bucket: &str, key: &str, etag: &str) -> io::Result<Option<ObjectIndex>> {
+        match fs::read(self.entry_path(bucket, key, etag)) {
+            Ok(bytes) => ObjectIndex::decode(&bytes).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns true if this version of the object has been indexed.
+    pub fn contains(&self, bucket: &str, key: &str, etag: &str) -> bool {
+        self.entry_path(bucket, key, etag).is_file()
+    }
+
+    /// Stores the entry for this version of the object, replacing older versions.
+    pub fn insert(
+        &self,
+        bucket: &str,
+        key: &str,
+        etag: &str,
+        entry: &ObjectIndex,
+    ) -> io::Result<()> {
+        let path = self.entry_path(bucket, key, etag);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = temp_path(&path);
+        fs::write(&temp, entry.encode())
+            .and_then(|_| fs::rename(&temp, &path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })?;
+        remove_other_versions(&path)
+    }
+
+    fn entry_path(&self, bucket: &str, key: &str, etag: &str) -> PathBuf {
+        entry_path(&self.dir, bucket, key, etag)
+    }
+}
+
+/// An index together with the trigrams of the pattern being searched for.
+#[derive(Debug)]
+pub struct IndexQuery {
+    index: Index,
+    trigrams: Vec<u32>,
+}
+
+impl IndexQuery {
+    /// Creates a query, or returns `None` if the index cannot help with `pattern`.
+    pub fn new(index: Index, pattern: &str, case_sensitive: bool) -> Option<Self> {
+        let trigrams = trigrams(pattern, case_sensitive)?;
+        Some(IndexQuery { index, trigrams })
+    }
+
+    /// Returns false only if the index shows this version of the object cannot match.
+    ///
+    /// Objects that are not indexed, or whose entry cannot be read, may match.
+    pub fn may_match(&self, bucket: &str, key: &str, etag: &str) -> bool {
+        match self.index.get(bucket, key, etag) {
+            Ok(Some(entry)) => entry.may_contain(&self.trigrams),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_round_trip_and_replace_versions() {
+        let dir = std::env::temp_dir().join(format!("s3grep-index-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let index = Index::open(&dir).unwrap();
+
+        let mut builder = TrigramBuilder::default();
+        builder.feed(b"GET /health 200\nPOST /login 500\n");
+        let entry = builder.finish();
+        index.insert("bucket", "a.log", "\"v1\"", &entry).unwrap();
+        assert_eq!(index.get("bucket", "a.log", "\"v1\"").unwrap(), Some(entry));
+
+        index
+            .insert("bucket", "a.log", "\"v2\"", &ObjectIndex::Any)
+            .unwrap();
+        assert!(!index.contains("bucket", "a.log", "\"v1\""));
+        assert_eq!(
+            index.get("bucket", "a.log", "\"v2\"").unwrap(),
+            Some(ObjectIndex::Any)
+        );
+
+        let query = IndexQuery::new(Index::open(&dir).unwrap(), "login", false).unwrap();
+        assert!(query.may_match("bucket", "a.log", "\"v2\""));
+        assert!(query.may_match("bucket", "b.log", "\"v1\""));
+        index
+            .insert("bucket", "b.log", "\"v1\"", &ObjectIndex::Trigrams(vec![]))
+            .unwrap();
+        assert!(!query.may_match("bucket", "b.log", "\"v1\""));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_entries() {
+        let bytes = ObjectIndex::Trigrams(vec![1, 300, 70_000, 0xff_ffff]).encode();
+        assert!(ObjectIndex::decode(&bytes).is_ok());
+        assert!(ObjectIndex::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ObjectIndex::decode(b"S3GI").is_err());
+    }
+}
@@ -1,20 +1,102 @@
 use aws_sdk_s3::config::interceptors::{
-    BeforeSerializationInterceptorContextRef, FinalizerInterceptorContextRef,
+    BeforeDeserializationInterceptorContextRef, BeforeSerializationInterceptorContextRef,
+    BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef,
 };
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::operation::get_object::GetObjectInput;
+use aws_sdk_s3::operation::head_object::HeadObjectInput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Input;
+use aws_sdk_s3::operation::put_object::PutObjectInput;
+use aws_sdk_s3::operation::select_object_content::SelectObjectContentInput;
 use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::interceptors::context::Input;
 use aws_smithy_runtime_api::client::interceptors::Intercept;
+use aws_smithy_runtime_api::client::orchestrator::Metadata;
 use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
 use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+use indicatif::MultiProgress;
+use s3grep::adaptive::Signals;
+use s3grep::stats::{Latencies, RequestCounts};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Instant;
 
-/// An interceptor for monitoring network requests and responses.
+/// Where `--trace-http` writes request records, and their latencies so far.
+pub(crate) struct HttpTrace {
+    out: Mutex<Box<dyn Write + Send>>,
+    latencies: Latencies,
+    /// Progress bars drawn on the same terminal, hidden while a record is written
+    progress: OnceLock<MultiProgress>,
+}
+
+impl HttpTrace {
+    /// Creates a trace writing one JSON object per line to `out`.
+    pub(crate) fn new(out: Box<dyn Write + Send>) -> Self {
+        HttpTrace {
+            out: Mutex::new(out),
+            latencies: Latencies::default(),
+            progress: OnceLock::new(),
+        }
+    }
+
+    /// Hides `progress` while writing each record, for traces written to the terminal.
+    pub(crate) fn suspend(&self, progress: MultiProgress) {
+        let _ = self.progress.set(progress);
+    }
+
+    /// Flushes the records written so far.
+    pub(crate) fn flush(&self) -> std::io::Result<()> {
+        self.out.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+
+    /// Latency percentiles of the requests so far, by operation.
+    pub(crate) fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+
+    fn write(&self, record: &Value) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // Tracing must never fail the request it describes
+        match self.progress.get() {
+            Some(progress) => progress.suspend(|| {
+                let _ = writeln!(out, "{record}");
+            }),
+            None => {
+                let _ = writeln!(out, "{record}");
+            }
+        }
+    }
+}
+
+/// Flushes an HTTP trace when dropped, so that a search that fails or is
+/// interrupted still leaves a complete `--trace-file` behind.
+pub(crate) struct FlushOnDrop(pub(crate) Option<Arc<HttpTrace>>);
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        if let Some(trace) = &self.0 {
+            let _ = trace.flush();
+        }
+    }
+}
+
+/// An interceptor that logs every request for `--trace-http`.
 ///
-/// This interceptor records the start time before a request is sent and logs
-/// the response size and duration after the request completes.
-#[derive(Debug)]
-pub(crate) struct NetworkMonitoringInterceptor;
+/// Each operation is logged when it completes, with its key or prefix, final
+/// status, latency (including retries, and up to the response headers for
+/// streamed bodies), response size and number of attempts.
+pub(crate) struct NetworkMonitoringInterceptor {
+    pub(crate) trace: Arc<HttpTrace>,
+}
+
+impl fmt::Debug for NetworkMonitoringInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NetworkMonitoringInterceptor")
+    }
+}
 
-// Wrapper type for Instant
 /// Wrapper type for storing the start time of a request in the config bag.
 #[derive(Debug)]
 struct StartTime(Instant);
@@ -23,53 +105,186 @@ impl Storable for StartTime {
     type Storer = StoreReplace<Self>;
 }
 
+/// The key, or for listings the prefix, that an operation is about.
+#[derive(Debug)]
+struct Target {
+    field: &'static str,
+    value: Option<String>,
+}
+
+impl Storable for Target {
+    type Storer = StoreReplace<Self>;
+}
+
+/// Number of attempts made at an operation so far.
+#[derive(Debug)]
+struct Attempts(u32);
+
+impl Storable for Attempts {
+    type Storer = StoreReplace<Self>;
+}
+
+/// Finds the key or prefix in the input of the operations s3grep makes.
+fn target(input: &Input) -> Option<Target> {
+    let key = |value: Option<&str>| Target {
+        field: "key",
+        value: value.map(str::to_string),
+    };
+    if let Some(input) = input.downcast_ref::<GetObjectInput>() {
+        Some(key(input.key()))
+    } else if let Some(input) = input.downcast_ref::<HeadObjectInput>() {
+        Some(key(input.key()))
+    } else if let Some(input) = input.downcast_ref::<PutObjectInput>() {
+        Some(key(input.key()))
+    } else if let Some(input) = input.downcast_ref::<SelectObjectContentInput>() {
+        Some(key(input.key()))
+    } else {
+        input
+            .downcast_ref::<ListObjectsV2Input>()
+            .map(|input| Target {
+                field: "prefix",
+                value: input.prefix().map(str::to_string),
+            })
+    }
+}
+
 impl Intercept for NetworkMonitoringInterceptor {
     /// Returns the name of the interceptor.
     fn name(&self) -> &'static str {
         "NetworkMonitoringInterceptor"
     }
 
-    /// Called before the request is transmitted.
-    ///
-    /// Stores the current time in the config bag for later duration calculation.
+    /// Records the start time and target of the operation.
     fn read_before_execution(
         &self,
-        _context: &BeforeSerializationInterceptorContextRef<'_>,
+        context: &BeforeSerializationInterceptorContextRef<'_>,
         cfg: &mut ConfigBag,
     ) -> Result<(), BoxError> {
-        // let request = context.input();
-        // let size_hint = request.body().content_length();
-        // let estimated_size = size_hint.unwrap_or(0);
-        // println!("Request size: {} bytes (estimated)", estimated_size);
-
-        // Add timing information to the config bag
-        let start_time = StartTime(Instant::now());
-        cfg.interceptor_state().store_put(start_time);
+        if let Some(target) = target(context.input()) {
+            cfg.interceptor_state().store_put(target);
+        }
+        cfg.interceptor_state().store_put(StartTime(Instant::now()));
+        Ok(())
+    }
 
+    /// Counts attempts, which are more than one when requests are retried.
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let attempts = cfg.load::<Attempts>().map_or(0, |a| a.0);
+        cfg.interceptor_state().store_put(Attempts(attempts + 1));
         Ok(())
     }
 
-    /// Called after the response is received and deserialized.
-    ///
-    /// Logs the response size and the duration of the request.
+    /// Logs the completed operation.
     fn read_after_execution(
         &self,
         context: &FinalizerInterceptorContextRef<'_>,
         _runtime_components: &RuntimeComponents,
         cfg: &mut ConfigBag,
     ) -> Result<(), BoxError> {
-        if let Some(response) = context.response() {
-            let size_hint = response.body().content_length();
-            let estimated_size = size_hint.unwrap_or(0);
-            println!("Response size: {estimated_size} bytes (estimated)");
-
-            // Calculate elapsed time
-            if let Some(start_time) = cfg.interceptor_state().load::<StartTime>() {
-                let duration = start_time.0.elapsed();
-                println!("Request duration: {duration:?}");
-            }
+        let operation = cfg
+            .load::<Metadata>()
+            .map_or("unknown", |metadata| metadata.name());
+        let latency = cfg.load::<StartTime>().map(|start| start.0.elapsed());
+        if let Some(latency) = latency {
+            self.trace.latencies.record(operation, latency);
+        }
+
+        let mut record = json!({
+            "operation": operation,
+            "status": context.response().map(|r| r.status().as_u16()),
+            "latency_ms": latency.map(|l| l.as_micros() as f64 / 1000.0),
+            "bytes": context
+                .response()
+                .and_then(|r| r.headers().get("content-length"))
+                .and_then(|length| length.parse::<u64>().ok()),
+            "attempts": cfg.load::<Attempts>().map_or(0, |a| a.0),
+        });
+        if let Some(target) = cfg.load::<Target>() {
+            record[target.field] = json!(target.value);
         }
+        if let Some(Err(error)) = context.output_or_error() {
+            record["error"] = json!(DisplayErrorContext(error).to_string());
+        }
+        self.trace.write(&record);
+        Ok(())
+    }
+}
+
+/// An interceptor that feeds response latency and throttling to adaptive concurrency.
+///
+/// Latency is measured per attempt, from transmitting the request to receiving
+/// the response headers, so retried requests are each counted.
+#[derive(Debug)]
+pub(crate) struct ThrottleMonitoringInterceptor {
+    pub(crate) signals: Arc<Signals>,
+}
+
+/// Wrapper type for storing the transmit time of an attempt in the config bag.
+#[derive(Debug)]
+struct TransmitTime(Instant);
+
+impl Storable for TransmitTime {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for ThrottleMonitoringInterceptor {
+    fn name(&self) -> &'static str {
+        "ThrottleMonitoringInterceptor"
+    }
 
+    fn read_before_transmit(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        cfg.interceptor_state()
+            .store_put(TransmitTime(Instant::now()));
+        Ok(())
+    }
+
+    /// Records the time to headers and whether S3 asked us to slow down.
+    fn read_after_transmit(
+        &self,
+        context: &BeforeDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(start) = cfg.interceptor_state().load::<TransmitTime>() {
+            let throttled = context.response().status().as_u16() == 503;
+            self.signals.record(start.0.elapsed(), throttled);
+        }
+        Ok(())
+    }
+}
+
+/// An interceptor that counts requests by operation for `--stats`.
+///
+/// Every attempt is counted, since S3 bills retried requests too.
+#[derive(Debug)]
+pub(crate) struct RequestCountingInterceptor {
+    pub(crate) requests: Arc<RequestCounts>,
+}
+
+impl Intercept for RequestCountingInterceptor {
+    fn name(&self) -> &'static str {
+        "RequestCountingInterceptor"
+    }
+
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(metadata) = cfg.load::<Metadata>() {
+            self.requests.record(metadata.name());
+        }
         Ok(())
     }
 }
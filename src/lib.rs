@@ -1,3 +1,21 @@
+pub mod adaptive;
+pub mod avro;
+pub mod bloom;
+pub mod budget;
+pub mod cache;
+pub mod checkpoint;
+pub mod delimited;
+pub mod errors;
+pub mod formats;
+pub mod index;
+pub mod limits;
+pub mod parquet_search;
+pub mod resume;
+pub mod roles;
+pub mod scan;
+pub mod select;
+pub mod stats;
+
 /**
     Returns true if the given line contains the pattern, respecting case sensitivity.
 
@@ -24,6 +42,127 @@ pub fn line_matches(line: &str, pattern: &str, case_sensitive: bool) -> bool {
     }
 }
 
+/**
+    Parses a human-readable byte size such as `64MiB`, `50GB` or `1024`.
+
+    SI suffixes (`KB`, `MB`, `GB`, `TB`) are powers of 1000; binary suffixes
+    (`KiB`, `MiB`, `GiB`, `TiB`) and the single letters `K`, `M`, `G` and `T` are
+    powers of 1024. A bare number is a count of bytes.
+
+    # Examples
+
+    ```
+    use s3grep::parse_size;
+    assert_eq!(parse_size("1024"), Ok(1024));
+    assert_eq!(parse_size("64MiB"), Ok(64 * 1024 * 1024));
+    assert_eq!(parse_size("50GB"), Ok(50_000_000_000));
+    assert_eq!(parse_size("1.5K"), Ok(1536));
+    assert!(parse_size("ten").is_err());
+    ```
+*/
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{size}'"))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => return Err(format!("invalid size unit in '{size}'")),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/**
+    Parses a duration such as `500ms`, `30s`, `5m` or `1h`.
+
+    A bare number is a count of seconds; fractions are allowed.
+
+    # Examples
+
+    ```
+    use s3grep::parse_duration;
+    use std::time::Duration;
+    assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+    assert!(parse_duration("soon").is_err());
+    ```
+*/
+pub fn parse_duration(duration: &str) -> Result<std::time::Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{duration}'"))?;
+
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("invalid duration unit in '{duration}'")),
+    };
+
+    std::time::Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("invalid duration '{duration}'"))
+}
+
+/// A bucket and key prefix given as an `s3://bucket/prefix` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl std::str::FromStr for S3Location {
+    type Err = String;
+
+    /**
+        Parses an `s3://bucket/prefix` URL; the prefix may be empty.
+
+        # Examples
+
+        ```
+        use s3grep::S3Location;
+        let location: S3Location = "s3://my-bucket/logs/2024/".parse().unwrap();
+        assert_eq!(location.bucket, "my-bucket");
+        assert_eq!(location.prefix, "logs/2024/");
+        assert_eq!("s3://my-bucket".parse::<S3Location>().unwrap().prefix, "");
+        assert!("my-bucket/logs".parse::<S3Location>().is_err());
+        ```
+    */
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let path = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| format!("'{url}' is not an s3:// URL"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("'{url}' has no bucket"));
+        }
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
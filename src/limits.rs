@@ -0,0 +1,87 @@
+//! Hard limits on how much a search may read, for unattended runs.
+//!
+//! Limits are checked before each object is scheduled, so objects already
+//! being searched when a byte or object limit is reached still finish; the
+//! time limit also cancels them.
+
+use crate::stats::format_size;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Which limit stopped a search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    /// `--max-bytes` were downloaded
+    Bytes(u64),
+    /// `--max-objects` were scheduled
+    Objects(u64),
+    /// `--timeout` passed
+    Time(Duration),
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exhausted::Bytes(max) => write!(f, "--max-bytes {} reached", format_size(*max)),
+            Exhausted::Objects(max) => write!(f, "--max-objects {max} reached"),
+            Exhausted::Time(max) => write!(f, "--timeout {max:?} reached"),
+        }
+    }
+}
+
+/// The limits of a search, measured from when it started.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_bytes: Option<u64>,
+    pub max_objects: Option<u64>,
+    pub timeout: Option<Duration>,
+    started: Instant,
+}
+
+impl Limits {
+    /// Creates limits for a search starting now.
+    pub fn new(
+        max_bytes: Option<u64>,
+        max_objects: Option<u64>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Limits {
+            max_bytes,
+            max_objects,
+            timeout,
+            started: Instant::now(),
+        }
+    }
+
+    /// When the search must stop, if it has a time limit.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| self.started + timeout)
+    }
+
+    /**
+        Returns the limit that stops another object from being scheduled, given
+        the bytes downloaded and objects scheduled so far.
+
+        # Examples
+
+        ```
+        use s3grep::limits::{Exhausted, Limits};
+        let limits = Limits::new(Some(1000), Some(10), None);
+        assert_eq!(limits.check(999, 9), None);
+        assert_eq!(limits.check(1000, 9), Some(Exhausted::Bytes(1000)));
+        assert_eq!(limits.check(0, 10), Some(Exhausted::Objects(10)));
+        ```
+    */
+    pub fn check(&self, downloaded: u64, scheduled: u64) -> Option<Exhausted> {
+        if let Some(max) = self.max_bytes.filter(|&max| downloaded >= max) {
+            return Some(Exhausted::Bytes(max));
+        }
+        if let Some(max) = self.max_objects.filter(|&max| scheduled >= max) {
+            return Some(Exhausted::Objects(max));
+        }
+        match self.timeout {
+            Some(timeout) if self.started.elapsed() >= timeout => Some(Exhausted::Time(timeout)),
+            _ => None,
+        }
+    }
+}
@@ -5,41 +5,149 @@ A CLI tool for searching logs and unstructured content in AWS S3 buckets.
 */
 
 use async_compression::tokio::bufread::GzipDecoder;
+use aws_config::default_provider::region::DefaultRegionChain;
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::retry::RetryConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::timeout::TimeoutConfig;
 use aws_config::{BehaviorVersion, SdkConfig};
-use aws_sdk_s3::config::Region;
+use aws_sdk_s3::config::{Region, SharedCredentialsProvider};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    CompressionType, CsvInput, ExpressionType, FileHeaderInfo, InputSerialization, JsonOutput,
+    Object, OutputSerialization, ParquetInput, SelectObjectContentEventStream,
+};
 use aws_sdk_s3::Client;
+use bytes::Bytes;
 use colored::*;
+use futures::future;
 use futures::stream::{self, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use interceptors::NetworkMonitoringInterceptor;
-use s3grep::line_matches;
+use interceptors::{
+    FlushOnDrop, HttpTrace, NetworkMonitoringInterceptor, RequestCountingInterceptor,
+    ThrottleMonitoringInterceptor,
+};
+use memchr::memchr;
+use parquet::file::metadata::ParquetMetaDataReader;
+use parquet::file::properties::ReaderProperties;
+use parquet::file::serialized_reader::SerializedRowGroupReader;
+use parquet::record::reader::RowIter;
+use s3grep::adaptive::{Controller, Limiter, Sample, Signals};
+use s3grep::avro::{record_matches, ContainerDecoder, MAGIC as AVRO_MAGIC};
+use s3grep::bloom::{pattern_tokens, sidecar_key, Sidecar, SidecarBuilder, SIDECAR_SUFFIX};
+use s3grep::budget::MemoryBudget;
+use s3grep::cache::Cache;
+use s3grep::checkpoint::{Checkpoint, CheckpointFile};
+use s3grep::delimited::{Column, DelimitedQuery, RecordReader};
+use s3grep::errors::{classify, select_unsupported, ErrorCounts, ErrorKind};
+use s3grep::formats::{Filter, FormatQuery, LogFormat};
+use s3grep::index::{Index, IndexQuery, ObjectIndex, TrigramBuilder};
+use s3grep::limits::{Exhausted, Limits};
+use s3grep::parquet_search::{
+    column_ranges, projection, row_group_may_match, row_matches, SparseChunkReader, FOOTER_PREFETCH,
+};
+use s3grep::resume::{Reopen, ResumingReader};
+use s3grep::roles::{role_for, TargetRole};
+use s3grep::scan::{LineScanner, LongLines, Matcher, RangeScanner, READ_BUFFER};
+use s3grep::select::{
+    column_reference, expression, line_from_record, projected_expression, LINE_COLUMN,
+};
+use s3grep::stats::{
+    format_size, peak_rss, Counters, CountingReader, DryRun, RequestCounts, Stats,
+};
+use s3grep::{parse_duration, parse_size, S3Location};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use structopt::clap::{AppSettings, ArgGroup};
 use structopt::StructOpt;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::mpsc;
 
 mod interceptors;
 
+/// How often adaptive concurrency re-evaluates the limit.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Most bytes first fetched past the end of a range to finish its last line.
+const LINE_EXTENSION: u64 = 16 * 1024;
+
+/// Exit status when something matched (or a subcommand succeeded).
+const EXIT_MATCH: i32 = 0;
+
+/// Exit status when nothing matched.
+const EXIT_NO_MATCH: i32 = 1;
+
+/// Exit status when objects failed or the search could not run.
+const EXIT_ERROR: i32 = 2;
+
+/// Exit status when --max-bytes, --max-objects or --timeout stopped the search.
+const EXIT_LIMIT: i32 = 3;
+
+/// Exit status when interrupted by Ctrl-C or SIGTERM, like shells report SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Number of times a failed listing page is retried.
+const LIST_RETRIES: u32 = 3;
+
+/// Delay before the first retry of a listing page, doubled for each further retry.
+const LIST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How often checkpoints are saved while objects complete.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of output lines that can be queued for the writer before searches wait.
+const OUTPUT_BUFFER: usize = 1024;
+
 /// Output target for printing messages.
+#[derive(Debug, Clone, Copy)]
 enum OutputTarget {
+    /// A matching line
     Stdout,
     Stderr,
+    /// "Binary file X matches", printed to stdout in place of an object's lines
+    BinaryMatch,
 }
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "s3grep", about = "Fast parallel grep for S3 logs")]
+#[structopt(
+    name = "s3grep",
+    about = "Fast parallel grep for S3 logs",
+    group = ArgGroup::with_name("delimited"),
+    // Required through groups so that `s3grep index` can leave them out
+    group = ArgGroup::with_name("pattern-arg").required(true),
+    group = ArgGroup::with_name("bucket-arg").required(true).multiple(true),
+    // Clap would otherwise list every option with a default as required
+    usage = "s3grep [OPTIONS] --pattern <pattern> <--bucket <bucket>|<targets>...>\n    s3grep <SUBCOMMAND>",
+    setting = AppSettings::SubcommandsNegateReqs,
+    // A target named like a subcommand, such as `index`, is rejected as a
+    // target instead of starting the subcommand
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 struct Opt {
     /// Search pattern
-    #[structopt(short, long)]
-    pattern: String,
+    #[structopt(short, long, group = "pattern-arg")]
+    pattern: Option<String>,
 
     /// S3 bucket name
-    #[structopt(short, long)]
-    bucket: String,
+    #[structopt(short, long, group = "bucket-arg")]
+    bucket: Option<String>,
 
     /// S3 prefix to search in
     #[structopt(short = "z", long, default_value = "")]
     prefix: String,
 
+    /// More buckets and prefixes to search, as s3://bucket/prefix
+    #[structopt(group = "bucket-arg")]
+    targets: Vec<S3Location>,
+
     /// Number of concurrent tasks
     #[structopt(short, long, default_value = "8")]
     concurrent_tasks: usize,
@@ -55,26 +163,548 @@ struct Opt {
     /// Line numbers
     #[structopt(short = "n", long)]
     line_number: bool,
+
+    /// Parse lines as an AWS log format (alb, clb, cloudfront, s3-access, vpc-flow, waf)
+    #[structopt(long)]
+    format: Option<LogFormat>,
+
+    /// Only print lines whose parsed fields match, e.g. `elb_status_code>=500` (requires --format)
+    #[structopt(long = "where", number_of_values = 1, requires = "format")]
+    filters: Vec<Filter>,
+
+    /// Comma-separated list of fields to print instead of the whole line (requires --format)
+    #[structopt(long, use_delimiter = true, requires = "format")]
+    fields: Vec<String>,
+
+    /// Treat objects as CSV with a header row and print matching rows with column names
+    #[structopt(long, group = "delimited", conflicts_with = "format")]
+    csv: bool,
+
+    /// Treat objects as TSV with a header row and print matching rows with column names
+    #[structopt(long, group = "delimited", conflicts_with = "format")]
+    tsv: bool,
+
+    /// Only match in this CSV/TSV column, by header name or 1-based index
+    #[structopt(long, requires = "delimited")]
+    column: Option<Column>,
+
+    /// Comma-separated Parquet columns or Avro fields to search (default: all string columns / the whole record)
+    #[structopt(long, use_delimiter = true)]
+    columns: Vec<String>,
+
+    /// Filter objects server-side with S3 Select where possible, falling back to a full download
+    #[structopt(long = "s3-select", conflicts_with = "line-number")]
+    s3_select: bool,
+
+    /// Split uncompressed objects at least this large into concurrently fetched ranges (0 to disable)
+    #[structopt(long, default_value = "256MiB", parse(try_from_str = parse_size))]
+    range_threshold: u64,
+
+    /// Size of each range when splitting large objects
+    #[structopt(long, default_value = "64MiB", parse(try_from_str = parse_size))]
+    range_size: u64,
+
+    /// Print results in a fixed object order (path, modified) while still searching concurrently
+    #[structopt(long)]
+    sort: Option<SortOrder>,
+
+    /// Adjust concurrency to throughput and throttling, starting from --concurrent-tasks
+    #[structopt(long)]
+    adaptive: bool,
+
+    /// Upper limit on concurrency with --adaptive
+    #[structopt(long, default_value = "64")]
+    max_concurrent_tasks: usize,
+
+    /// Limit the memory held in buffers across all searches, e.g. 512MiB
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_memory: Option<u64>,
+
+    /// Longest line to keep in memory (0 for no limit)
+    #[structopt(long, default_value = "16MiB", parse(try_from_str = parse_size))]
+    max_line_length: u64,
+
+    /// What to do with lines longer than --max-line-length (truncate, skip)
+    #[structopt(long, default_value = "truncate")]
+    long_lines: LongLines,
+
+    /// Print statistics to stderr when the search completes
+    #[structopt(long)]
+    stats: bool,
+
+    /// Stop scheduling objects once this much has been downloaded, e.g. 50GB
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_bytes: Option<u64>,
+
+    /// Stop after scheduling this many objects
+    #[structopt(long)]
+    max_objects: Option<u64>,
+
+    /// Stop the search, cancelling objects in progress, after this long, e.g. 30m
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    timeout: Option<Duration>,
+
+    /// List what the search would read and estimate its cost and duration, without downloading
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Print --stats, or the --dry-run estimate, as text or json (implies --stats)
+    #[structopt(long)]
+    stats_format: Option<StatsFormat>,
+
+    /// Cache downloaded objects in this directory, keyed by bucket, key and ETag
+    #[structopt(long, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+
+    /// Largest total size of the cache; least recently used objects are evicted
+    #[structopt(long, default_value = "10GiB", parse(try_from_str = parse_size))]
+    cache_size: u64,
+
+    /// Skip objects that the index in this directory shows cannot match (see `s3grep index`)
+    #[structopt(long, parse(from_os_str))]
+    index_dir: Option<PathBuf>,
+
+    /// Skip objects whose bloom filter sidecar rules out the pattern's tokens (see `s3grep build-sidecars`)
+    #[structopt(long)]
+    sidecars: bool,
+
+    /// Save progress to this file so that an interrupted search can be resumed
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+
+    /// Resume the search saved in this checkpoint file, skipping finished objects
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Exit with 0 or 1 depending only on whether anything matched, even if some objects failed
+    #[structopt(long)]
+    no_fail_on_errors: bool,
+
+    /// Retry failed requests, and resume broken downloads, this many times
+    #[structopt(long, default_value = "2")]
+    retries: u32,
+
+    /// Give up connecting to S3 after this long, e.g. 5s
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    connect_timeout: Option<Duration>,
+
+    /// Give up on a request when no data arrives for this long, e.g. 30s
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    read_timeout: Option<Duration>,
+
+    /// Give up on an object whose search takes longer than this, e.g. 10m
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    object_timeout: Option<Duration>,
+
+    /// Log every S3 request as a line of JSON to stderr, and latency percentiles at the end
+    #[structopt(long)]
+    trace_http: bool,
+
+    /// Write the --trace-http log to this file instead of stderr
+    #[structopt(long, parse(from_os_str))]
+    trace_file: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    aws: AwsOpt,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Build or update a local trigram index used by --index-dir
+    Index {
+        #[structopt(subcommand)]
+        command: IndexCommand,
+    },
+    /// Write a bloom filter sidecar next to each object, used by --sidecars
+    BuildSidecars(SidecarOpt),
+}
+
+#[derive(StructOpt, Debug)]
+enum IndexCommand {
+    /// Index every object under a prefix
+    Build(IndexOpt),
+    /// Index only objects that are new or changed since they were last indexed
+    Update(IndexOpt),
+}
+
+#[derive(StructOpt, Debug)]
+struct SidecarOpt {
+    /// Objects to write sidecars for, as s3://bucket/prefix
+    location: S3Location,
+
+    #[structopt(flatten)]
+    aws: AwsOpt,
+
+    /// Number of concurrent tasks
+    #[structopt(short, long, default_value = "8")]
+    concurrent_tasks: usize,
+
+    /// Hide progress bar
+    #[structopt(short = "q", long)]
+    quiet: bool,
+}
+
+// Options for connecting to S3, shared by searches and subcommands. A doc
+// comment here would replace the app's description in --help.
+#[derive(StructOpt, Debug, Clone)]
+struct AwsOpt {
+    /// Use this profile from the AWS config and credentials files
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// Send requests to this region instead of looking up the bucket's region
+    #[structopt(long)]
+    region: Option<String>,
+
+    /// Send requests to this endpoint, e.g. http://localhost:9000 for MinIO
+    #[structopt(long)]
+    endpoint_url: Option<String>,
+
+    /// Put the bucket in the URL path instead of the host name, as many S3-compatible stores need
+    #[structopt(long)]
+    force_path_style: bool,
+
+    /// Send requests without credentials, for public buckets
+    #[structopt(long)]
+    no_sign_request: bool,
+
+    /// Assume this IAM role, using the credentials found as usual to call STS
+    #[structopt(long, conflicts_with = "no-sign-request")]
+    role_arn: Option<String>,
+
+    /// External ID to pass when assuming a role
+    #[structopt(long)]
+    external_id: Option<String>,
+
+    /// Session name of assumed roles, as shown in CloudTrail
+    #[structopt(long, default_value = "s3grep")]
+    role_session_name: String,
+
+    /// How long assumed role credentials last, e.g. 1h (15m to 12h)
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    /// Assume a role for targets at or under a location, as s3://bucket/prefix=ROLE_ARN;
+    /// can be given more than once
+    #[structopt(
+        long = "target-role",
+        number_of_values = 1,
+        conflicts_with = "no-sign-request"
+    )]
+    target_roles: Vec<TargetRole>,
+}
+
+#[derive(StructOpt, Debug)]
+struct IndexOpt {
+    /// Objects to index, as s3://bucket/prefix
+    location: S3Location,
+
+    /// Directory to store the index in
+    #[structopt(long, parse(from_os_str))]
+    index_dir: PathBuf,
+
+    #[structopt(flatten)]
+    aws: AwsOpt,
+
+    /// Number of concurrent tasks
+    #[structopt(short, long, default_value = "8")]
+    concurrent_tasks: usize,
+
+    /// Hide progress bar
+    #[structopt(short = "q", long)]
+    quiet: bool,
+}
+
+/// Order in which objects' results are printed with `--sort`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortOrder {
+    /// By key, the order S3 lists objects in
+    Path,
+    /// By LastModified, oldest first
+    Modified,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(SortOrder::Path),
+            "modified" => Ok(SortOrder::Modified),
+            _ => Err(format!(
+                "unknown sort order '{s}' (expected path or modified)"
+            )),
+        }
+    }
+}
+
+/// How `--stats` are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(StatsFormat::Text),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!(
+                "unknown stats format '{s}' (expected text or json)"
+            )),
+        }
+    }
 }
 
 use anyhow::Result;
 
+/// A bucket and prefix to search, with a client for its region and credentials.
+struct SearchTarget {
+    client: Client,
+    location: S3Location,
+}
+
+/// Options that control how each object is searched.
+#[derive(Debug, Clone)]
+struct SearchOptions {
+    pattern: String,
+    case_sensitive: bool,
+    /// The compiled pattern used to scan lines
+    matcher: Matcher,
+    /// Parse lines as an AWS log format and filter on their fields
+    query: Option<FormatQuery>,
+    /// Search CSV/TSV records instead of lines
+    delimited: Option<DelimitedQuery>,
+    /// Parquet columns or Avro fields to search
+    columns: Vec<String>,
+    /// Push the pattern down to S3 Select where possible
+    s3_select: bool,
+    /// Objects at least this large are fetched as concurrent ranges (0 disables)
+    range_threshold: u64,
+    /// Size of each range of a split object
+    range_size: u64,
+    /// Number of ranges of a split object fetched at once
+    range_concurrency: usize,
+    /// Print line numbers
+    line_numbers: bool,
+    /// Longest line kept in memory (0 for no limit)
+    max_line_length: usize,
+    /// What to do with longer lines
+    long_lines: LongLines,
+    /// Budget that buffers are reserved from
+    budget: Arc<MemoryBudget>,
+    /// Total number of long lines seen
+    long_line_count: Arc<AtomicU64>,
+    /// Objects, bytes and lines counted for --stats
+    counters: Arc<Counters>,
+    /// Local cache of object bodies
+    cache: Option<Arc<Cache>>,
+    /// Index used to skip objects that cannot match
+    index: Option<Arc<IndexQuery>>,
+    /// Token hashes looked up in bloom filter sidecars
+    sidecar_tokens: Option<Vec<u64>>,
+    /// Times a broken download is resumed from where it stopped
+    retries: u32,
+}
+
+/// Where in an object a match was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// 1-based line number
+    Line(usize),
+    /// 0-based row index in a Parquet file
+    Row(usize),
+    /// 0-based record index in an Avro file
+    Record(usize),
+    /// Not known, e.g. for records returned by S3 Select
+    Unknown,
+}
+
+/// Messages queued for the output writer task.
+type OutputSender = mpsc::Sender<(OutputTarget, String)>;
+
+/**
+    Delivers the matches of one object to the output writer.
+
+    Matches are formatted here and sent as whole lines over a bounded channel to
+    a single writer task, so lines from concurrent objects never interleave
+    mid-line, the matches of each object are printed in the order they were
+    found, and a slow terminal applies backpressure to the searches.
+*/
+struct MatchSink {
+    output: OutputSender,
+    bucket: String,
+    key: String,
+    pattern: String,
+    line_numbers: bool,
+    /// Number of matches sent so far
+    sent: AtomicUsize,
+}
+
+impl MatchSink {
+    fn new(output: OutputSender, bucket: &str, key: &str, options: &SearchOptions) -> Self {
+        MatchSink {
+            output,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            pattern: options.pattern.clone(),
+            line_numbers: options.line_numbers,
+            sent: AtomicUsize::new(0),
+        }
+    }
+
+    /// Formats a match with its location and queues it for printing.
+    async fn send(&self, position: Position, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (bucket, key) = (&self.bucket, &self.key);
+        let line = highlight_match(line, &self.pattern);
+        let msg = match position {
+            Position::Line(line_num) if self.line_numbers => {
+                format!("s3://{bucket}/{key}:{line_num}:{line}")
+            }
+            Position::Line(_) | Position::Unknown => format!("s3://{bucket}/{key}:{line}"),
+            Position::Row(row) => format!("s3://{bucket}/{key}:row={row}:{line}"),
+            Position::Record(record) => format!("s3://{bucket}/{key}:record={record}:{line}"),
+        };
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.print(OutputTarget::Stdout, msg).await
+    }
+
+    /// Queues a message about the object for printing.
+    async fn print(
+        &self,
+        target: OutputTarget,
+        msg: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.output
+            .send((target, msg))
+            .await
+            .map_err(|_| "output writer has stopped".into())
+    }
+
+    /// Returns the number of matches sent so far.
+    fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// A planned S3 Select request for one object.
+#[derive(Debug)]
+struct SelectRequest {
+    input: InputSerialization,
+    expression: String,
+    records: SelectRecords,
+}
+
+/// How records returned by S3 Select are turned back into matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SelectRecords {
+    /// Each record holds a whole line in its only column
+    Lines,
+    /// Each record is printed as JSON
+    Json,
+}
+
 /// Entry point for the s3grep CLI application.
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run().await {
-        // Print a user-friendly error message and exit with code 1
-        eprintln!("s3grep error: {e:?}");
-        std::process::exit(1);
+    match run().await {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            // Print a user-friendly error message and exit like grep does on errors
+            eprintln!("s3grep error: {e:?}");
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Loads the AWS configuration, with a default region to look up bucket regions from.
+async fn load_config(aws: &AwsOpt) -> SdkConfig {
+    let mut default_region = DefaultRegionChain::builder();
+    if let Some(profile) = &aws.profile {
+        default_region = default_region.profile_name(profile);
+    }
+    let region_provider = RegionProviderChain::first_try(aws.region.clone().map(Region::new))
+        .or_else(default_region.build())
+        .or_else(Region::new("us-east-1"));
+
+    let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region_provider);
+    if let Some(profile) = &aws.profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(endpoint_url) = &aws.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+    if aws.no_sign_request {
+        loader = loader.no_credentials();
+    }
+    loader.load().await
+}
+
+/**
+    Returns the configuration for a bucket and prefix, assuming the role mapped
+    to it with `--target-role`, or else the `--role-arn` role.
+
+    The role is assumed with the credentials of `config`, and its credentials
+    are refreshed before they expire.
+*/
+async fn with_role(config: &SdkConfig, aws: &AwsOpt, location: &S3Location) -> SdkConfig {
+    let Some(role_arn) = role_for(&aws.target_roles, location).or(aws.role_arn.as_deref()) else {
+        return config.clone();
+    };
+    let mut role = AssumeRoleProvider::builder(role_arn)
+        .session_name(&aws.role_session_name)
+        .configure(config);
+    if let Some(external_id) = &aws.external_id {
+        role = role.external_id(external_id);
     }
+    if let Some(duration) = aws.duration {
+        role = role.session_length(duration);
+    }
+    config
+        .to_builder()
+        .credentials_provider(SharedCredentialsProvider::new(role.build().await))
+        .build()
+}
+
+/// Creates an S3 client, applying the options the shared config cannot hold.
+fn s3_client(config: &SdkConfig, aws: &AwsOpt) -> Client {
+    let conf = aws_sdk_s3::config::Builder::from(config)
+        .force_path_style(aws.force_path_style)
+        .build();
+    Client::from_conf(conf)
 }
 
-pub async fn create_client_in_bucket_region_reuse_config(
+/// Applies the --retries and timeout options to the SDK's retry and timeout config.
+fn with_retries_and_timeouts(config: SdkConfig, opt: &Opt) -> SdkConfig {
+    let mut timeouts = TimeoutConfig::builder();
+    timeouts.set_connect_timeout(opt.connect_timeout);
+    timeouts.set_read_timeout(opt.read_timeout);
+    config
+        .to_builder()
+        .retry_config(RetryConfig::standard().with_max_attempts(opt.retries.saturating_add(1)))
+        .timeout_config(timeouts.build())
+        .build()
+}
+
+async fn create_client_in_bucket_region_reuse_config(
     config: &SdkConfig,
     bucket_name: &str,
+    aws: &AwsOpt,
 ) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
     // Create initial client with the default/current region
-    let initial_client = Client::new(config);
+    let initial_client = s3_client(config, aws);
+
+    // An explicit --region is used as is, and other S3-compatible stores seldom
+    // report bucket regions, so they use the configured or default region
+    if aws.region.is_some() || aws.endpoint_url.is_some() {
+        return Ok(initial_client);
+    }
 
     // Try to get bucket region using head_bucket
     let head_result = initial_client
@@ -85,13 +715,23 @@ pub async fn create_client_in_bucket_region_reuse_config(
 
     let bucket_region = match head_result {
         Ok(output) => output.bucket_region().map(str::to_owned),
-        Err(err) => err
-            .raw_response()
-            .and_then(|res| res.headers().get("x-amz-bucket-region"))
-            .map(str::to_owned),
+        Err(err) => match err.raw_response() {
+            Some(res) => res.headers().get("x-amz-bucket-region").map(str::to_owned),
+            // No response at all, e.g. because a role could not be assumed
+            None => {
+                return Err(format!(
+                    "Could not determine bucket region: {}",
+                    DisplayErrorContext(&err)
+                )
+                .into())
+            }
+        },
     };
 
-    let region = bucket_region.ok_or("Could not determine bucket region")?;
+    // Without a region in the response, stay in the configured or default region
+    let Some(region) = bucket_region else {
+        return Ok(initial_client);
+    };
 
     // If the region matches the current config region, return the initial client
     if let Some(current_region) = config.region() {
@@ -106,44 +746,246 @@ pub async fn create_client_in_bucket_region_reuse_config(
     let new_config = config_builder.build();
 
     // Create and return client with correct region
-    Ok(Client::new(&new_config))
+    Ok(s3_client(&new_config, aws))
 }
 
-/// Main application logic for s3grep.
-/// Returns Ok(()) on success, or an error on failure.
 /**
     Main application logic for s3grep.
 
-    Returns Ok(()) on success, or an error on failure.
+    Returns the exit status, like grep's: [`EXIT_MATCH`] if anything matched,
+    [`EXIT_NO_MATCH`] if nothing did and [`EXIT_ERROR`] if any object failed
+    (unless `--no-fail-on-errors`), [`EXIT_LIMIT`] if a limit stopped the
+    search early or [`EXIT_INTERRUPTED`] if it was interrupted. Errors that stop
+    the search are returned.
 */
-async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn run() -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
     let opt = Opt::from_args();
+    match opt.command {
+        Some(Command::Index { command }) => return run_index(command).await.map(|()| EXIT_MATCH),
+        Some(Command::BuildSidecars(sidecar_opt)) => {
+            return run_build_sidecars(sidecar_opt).await.map(|()| EXIT_MATCH)
+        }
+        None => {}
+    }
+    // Clap requires a pattern and a bucket or target unless a subcommand was given
+    let Some(pattern) = opt.pattern.clone() else {
+        return Err("--pattern is required".into());
+    };
+    let locations: Vec<S3Location> = opt
+        .bucket
+        .iter()
+        .map(|bucket| S3Location {
+            bucket: bucket.clone(),
+            prefix: opt.prefix.clone(),
+        })
+        .chain(opt.targets.iter().cloned())
+        .collect();
+    if locations.len() > 1 && (opt.checkpoint.is_some() || opt.resume.is_some()) {
+        return Err("--checkpoint and --resume can only be used with a single target".into());
+    }
 
-    // Get or set a default region, necessary to lookup the bucket region
-    // TODO: Add user opt for region: first_try("opt_region".map(Region::new))
-    let region_provider = RegionProviderChain::default_provider().or_else(Region::new("us-east-1"));
+    let started = Instant::now();
+    let limits = Limits::new(opt.max_bytes, opt.max_objects, opt.timeout);
+    let config = with_retries_and_timeouts(load_config(&opt.aws).await, &opt);
 
-    // Initialize AWS client
-    let config = aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
-        .load()
-        .await;
-    let _s3_conf = aws_sdk_s3::config::Builder::from(&config)
-        .interceptor(NetworkMonitoringInterceptor)
-        .build();
-    let client = create_client_in_bucket_region_reuse_config(&config, &opt.bucket).await?;
+    // Adaptive concurrency watches every response for latency and throttling
+    let signals = Arc::new(Signals::default());
 
-    // Create a progress bar that we'll update as we discover objects
-    let progress = if !opt.quiet {
-        let p = ProgressBar::new_spinner();
-        p.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.green} Processed {pos} files... ({per_sec} files/sec)")?,
-        );
-        Some(p)
-    } else {
-        None
+    // --trace-file implies --trace-http
+    let trace = match &opt.trace_file {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .map_err(|e| format!("cannot create trace file {}: {e}", path.display()))?;
+            Some(Arc::new(HttpTrace::new(Box::new(io::BufWriter::new(file)))))
+        }
+        None if opt.trace_http => Some(Arc::new(HttpTrace::new(Box::new(io::stderr())))),
+        None => None,
+    };
+    // Searches that return early with an error still flush the trace
+    let _flush_trace = FlushOnDrop(trace.clone());
+    let requests = Arc::new(RequestCounts::default());
+    let stats = opt.stats_format.or(opt.stats.then_some(StatsFormat::Text));
+    // Each target gets a client with its own region and credentials, and every
+    // client reports to the same monitors
+    let instrument = |client: Client| {
+        let mut conf = client.config().to_builder();
+        if opt.adaptive {
+            conf = conf.interceptor(ThrottleMonitoringInterceptor {
+                signals: signals.clone(),
+            });
+        }
+        if stats.is_some() {
+            conf = conf.interceptor(RequestCountingInterceptor {
+                requests: requests.clone(),
+            });
+        }
+        if let Some(trace) = &trace {
+            conf = conf.interceptor(NetworkMonitoringInterceptor {
+                trace: trace.clone(),
+            });
+        }
+        Client::from_conf(conf.build())
+    };
+    let mut targets = Vec::with_capacity(locations.len());
+    for location in locations {
+        let config = with_role(&config, &opt.aws, &location).await;
+        let client =
+            create_client_in_bucket_region_reuse_config(&config, &location.bucket, &opt.aws)
+                .await?;
+        targets.push(SearchTarget {
+            client: instrument(client),
+            location,
+        });
+    }
+
+    let options = SearchOptions {
+        pattern: pattern.clone(),
+        case_sensitive: opt.case_sensitive,
+        matcher: Matcher::new(&pattern, opt.case_sensitive),
+        query: opt.format.map(|format| FormatQuery {
+            format,
+            filters: opt.filters.clone(),
+            fields: opt.fields.clone(),
+        }),
+        delimited: (opt.csv || opt.tsv).then(|| DelimitedQuery {
+            delimiter: if opt.tsv { b'\t' } else { b',' },
+            column: opt.column.clone(),
+        }),
+        columns: opt.columns.clone(),
+        s3_select: opt.s3_select,
+        range_threshold: opt.range_threshold,
+        range_size: opt.range_size.max(1),
+        range_concurrency: opt.concurrent_tasks.max(1),
+        line_numbers: opt.line_number,
+        max_line_length: opt.max_line_length as usize,
+        long_lines: opt.long_lines,
+        budget: MemoryBudget::new(opt.max_memory),
+        long_line_count: Arc::new(AtomicU64::new(0)),
+        counters: Arc::new(Counters::default()),
+        cache: match &opt.cache_dir {
+            Some(dir) => Some(Arc::new(Cache::new(dir, opt.cache_size)?)),
+            None => None,
+        },
+        // CSV and TSV fields are unescaped before matching, so the raw text's
+        // trigrams cannot rule them out
+        index: match &opt.index_dir {
+            Some(dir) if !(opt.csv || opt.tsv) => {
+                IndexQuery::new(Index::open(dir)?, &pattern, opt.case_sensitive).map(Arc::new)
+            }
+            _ => None,
+        },
+        sidecar_tokens: opt
+            .sidecars
+            .then(|| sidecar_tokens(&pattern, opt.case_sensitive))
+            .flatten(),
+        retries: opt.retries,
+    };
+
+    // Progress is saved to --checkpoint, or back to the file being resumed
+    let checkpoint = match opt.checkpoint.as_ref().or(opt.resume.as_ref()) {
+        Some(path) => {
+            let options = match_options(&opt);
+            let state = match &opt.resume {
+                Some(resume) => {
+                    let state = Checkpoint::load(resume)?;
+                    let target = &targets[0].location;
+                    if (&state.bucket, &state.prefix, &state.pattern)
+                        != (&target.bucket, &target.prefix, &pattern)
+                    {
+                        return Err(format!(
+                            "{} is for a search for '{}' in s3://{}/{}",
+                            resume.display(),
+                            state.pattern,
+                            state.bucket,
+                            state.prefix
+                        )
+                        .into());
+                    }
+                    let different = state.different_options(&options);
+                    if !different.is_empty() {
+                        return Err(format!(
+                            "{} is for a search with different options: --{}",
+                            resume.display(),
+                            different.join(", --")
+                        )
+                        .into());
+                    }
+                    state
+                }
+                None => Checkpoint::new(
+                    &targets[0].location.bucket,
+                    &targets[0].location.prefix,
+                    &pattern,
+                )
+                .with_options(options),
+            };
+            Some(Arc::new(CheckpointFile::new(
+                path,
+                state,
+                CHECKPOINT_INTERVAL,
+            )))
+        }
+        None => None,
     };
+
+    // Stream objects and process them concurrently
+    let start_after = checkpoint
+        .as_ref()
+        .and_then(|checkpoint| checkpoint.with(|c| c.start_after().map(str::to_string)));
+    let start_after = start_after.as_deref();
+    let object_stream = stream::iter(&targets)
+        .flat_map(|target| {
+            let SearchTarget { client, location } = target;
+            list_objects_stream(
+                client,
+                &location.bucket,
+                &location.prefix,
+                start_after,
+                opt.sidecars,
+            )
+            .map(move |obj| obj.map(|object| (target, object)))
+        })
+        .filter(|obj| {
+            if obj.is_ok() {
+                Counters::add(&options.counters.listed, 1);
+            }
+            // Skip objects finished before a resume, and track the rest in listing order
+            let done = match (&checkpoint, obj) {
+                (Some(checkpoint), Ok((_, object))) => checkpoint.with(|c| {
+                    let key = object.key().unwrap_or_default();
+                    let done = c.is_completed(key);
+                    if !done {
+                        c.listed(key);
+                    }
+                    done
+                }),
+                _ => false,
+            };
+            if done {
+                Counters::add(&options.counters.filtered, 1);
+            }
+            future::ready(!done)
+        });
+    if opt.dry_run {
+        let dry_run = dry_run(object_stream, targets.len(), &options, opt.concurrent_tasks).await?;
+        match stats {
+            Some(StatsFormat::Json) => println!("{}", dry_run.to_json()),
+            _ => println!("{dry_run}"),
+        }
+        return Ok(EXIT_MATCH);
+    }
+
+    // Create a progress bar that we'll update as we discover objects
+    let progress =
+        if !opt.quiet {
+            let p = ProgressBar::new_spinner();
+            p.set_style(ProgressStyle::default_spinner().template(
+                "{spinner:.green} Processed {pos} files... ({per_sec} files/sec) {msg}",
+            )?);
+            Some(p)
+        } else {
+            None
+        };
     let byte_progress = ProgressBar::new_spinner();
     byte_progress.set_style(
         ProgressStyle::default_spinner()
@@ -155,28 +997,196 @@ async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         m.add(p.clone());
         m.insert_after(p, byte_progress.clone());
     }
+    if let (Some(trace), None) = (&trace, &opt.trace_file) {
+        trace.suspend(m.clone());
+    }
 
-    // Stream objects and process them concurrently
-    let object_stream = list_objects_stream(&client, &opt.bucket, &opt.prefix);
+    // The first Ctrl-C or SIGTERM stops scheduling objects and lets those in
+    // progress finish; a second one exits at once
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let interrupt_handler = {
+        let interrupted = interrupted.clone();
+        let m = m.clone();
+        let checkpoint = checkpoint.clone();
+        let trace = trace.clone();
+        tokio::spawn(async move {
+            if shutdown_signal().await.is_err() {
+                return;
+            }
+            interrupted.store(true, Ordering::Relaxed);
+            m.suspend(|| {
+                eprintln!("s3grep: interrupted, finishing objects in progress (press Ctrl-C again to stop now)")
+            });
+            if shutdown_signal().await.is_err() {
+                return;
+            }
+            let _ = m.clear();
+            // Objects in progress are not complete, so a resume searches them again
+            if let Some(Err(e)) = checkpoint.map(|checkpoint| checkpoint.save()) {
+                eprintln!("s3grep: cannot save checkpoint: {e}");
+            }
+            if let Some(Err(e)) = trace.map(|trace| trace.flush()) {
+                eprintln!("s3grep: cannot write HTTP trace: {e}");
+            }
+            std::process::exit(EXIT_INTERRUPTED);
+        })
+    };
+
+    // Searches take a permit first, so the limiter sets the effective concurrency
+    let max_tasks = if opt.adaptive {
+        opt.max_concurrent_tasks.max(opt.concurrent_tasks)
+    } else {
+        opt.concurrent_tasks
+    };
+    let limiter = Arc::new(Limiter::new(opt.concurrent_tasks.min(max_tasks)));
+    let controller = opt.adaptive.then(|| {
+        let limiter = limiter.clone();
+        let signals = signals.clone();
+        let progress = progress.clone();
+        let byte_progress = byte_progress.clone();
+        let mut controller = Controller::new(opt.concurrent_tasks, 1, max_tasks);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ADJUST_INTERVAL);
+            interval.tick().await;
+            let mut last_bytes = byte_progress.position();
+            loop {
+                interval.tick().await;
+                let bytes = byte_progress.position();
+                let (throttled, latency) = signals.take();
+                let limit = controller.adjust(Sample {
+                    bytes_per_sec: (bytes - last_bytes) as f64 / ADJUST_INTERVAL.as_secs_f64(),
+                    throttled,
+                    latency,
+                });
+                last_bytes = bytes;
+                limiter.set_limit(limit);
+                if let Some(p) = &progress {
+                    p.set_message(format!("[concurrency {limit}]"));
+                }
+            }
+        })
+    });
+    if let (true, Some(p)) = (opt.adaptive, &progress) {
+        p.set_message(format!("[concurrency {}]", opt.concurrent_tasks));
+    }
+
+    let errors = Arc::new(ErrorCounts::default());
+    let listing_failed = Arc::new(AtomicBool::new(false));
+
+    // A single writer prints all output so concurrent searches never interleave lines
+    let (output, mut output_rx) = mpsc::channel::<(OutputTarget, String)>(OUTPUT_BUFFER);
+    let writer = {
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            // Binary files match without printing any lines
+            let (mut matched, mut binary_matched) = (0u64, false);
+            while let Some((target, msg)) = output_rx.recv().await {
+                match target {
+                    OutputTarget::Stdout => matched += 1,
+                    OutputTarget::BinaryMatch => binary_matched = true,
+                    OutputTarget::Stderr => {}
+                }
+                print_with_target(progress.as_ref(), &msg, target);
+            }
+            (matched, binary_matched)
+        })
+    };
+
+    let exhausted = OnceLock::new();
+    let object_stream = if opt.sort == Some(SortOrder::Modified) {
+        // Ordering by modification time needs the whole listing first, and the
+        // time limit covers the listing too
+        let listing = object_stream.collect::<Vec<_>>();
+        let mut objects = match limits.deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), listing)
+                .await
+                .unwrap_or_else(|_| {
+                    let _ = exhausted.set(Exhausted::Time(limits.timeout.unwrap_or_default()));
+                    Vec::new()
+                }),
+            None => listing.await,
+        };
+        objects.sort_by_key(|obj| {
+            obj.as_ref()
+                .ok()
+                .map(|(_, o)| (o.last_modified, o.key.clone()))
+        });
+        stream::iter(objects).boxed_local()
+    } else {
+        object_stream.boxed_local()
+    };
+
+    // With --sort, each object gets its own bounded channel and a forwarder drains
+    // them in order; the searches ahead of it buffer at most OUTPUT_BUFFER lines each
+    let (order, forwarder) = if opt.sort.is_some() {
+        let (order, mut order_rx) = mpsc::unbounded_channel::<mpsc::Receiver<_>>();
+        let output = output.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(mut object_rx) = order_rx.recv().await {
+                while let Some(msg) = object_rx.recv().await {
+                    if output.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (Some(order), Some(forwarder))
+    } else {
+        (None, None)
+    };
+
+    // Limits are checked before each object is scheduled; directories are not
+    // searched, so they do not count towards --max-objects
+    let mut scheduled = 0;
+    let object_stream = object_stream.take_while(|obj| {
+        if interrupted.load(Ordering::Relaxed) {
+            return future::ready(false);
+        }
+        let downloaded = options.counters.downloaded.load(Ordering::Relaxed);
+        let limit = limits.check(downloaded, scheduled);
+        match limit {
+            Some(limit) => {
+                let _ = exhausted.set(limit);
+            }
+            None => {
+                if obj.as_ref().is_ok_and(|(_, object)| !is_directory(object)) {
+                    scheduled += 1;
+                }
+            }
+        }
+        future::ready(limit.is_none())
+    });
 
     let search_stream = futures::StreamExt::map(object_stream, |obj| {
-        let client = client.clone();
-        let pattern = opt.pattern.clone();
-        let bucket = opt.bucket.clone();
-        let case_sensitive = opt.case_sensitive;
+        let options = options.clone();
         let progress = progress.clone();
         let byte_progress = byte_progress.clone();
-        let line_numbers = opt.line_number;
+        let limiter = limiter.clone();
+        let checkpoint = checkpoint.clone();
+        let errors = errors.clone();
+        let object_timeout = opt.object_timeout;
+        let listing_failed = listing_failed.clone();
+        let output = match &order {
+            Some(order) => {
+                let (object_output, object_rx) = mpsc::channel(OUTPUT_BUFFER);
+                let _ = order.send(object_rx);
+                object_output
+            }
+            None => output.clone(),
+        };
 
         async move {
+            let _permit = limiter.acquire().await;
             match obj {
-                Ok(key) => {
-                    if key.ends_with('/') {
-                        print_with_target(
-                            progress.as_ref(),
-                            format!("{key}: Is a directory").as_str(),
-                            OutputTarget::Stderr,
-                        );
+                Ok((target, object)) => {
+                    let (client, bucket) = (&target.client, &target.location.bucket);
+                    let key = object.key().unwrap_or_default();
+                    if is_directory(&object) {
+                        let _ = output
+                            .send((OutputTarget::Stderr, format!("{key}: Is a directory")))
+                            .await;
+                        Counters::add(&options.counters.filtered, 1);
+                        complete_checkpoint(checkpoint.as_deref(), key, false, &output).await;
 
                         if let Some(p) = &progress {
                             p.inc(1);
@@ -184,80 +1194,527 @@ async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                         return;
                     }
 
-                    match search_object(
-                        &client,
-                        &bucket,
-                        &key,
-                        &pattern,
-                        case_sensitive,
-                        byte_progress,
-                    )
-                    .await
-                    {
-                        Ok(matches) => {
-                            for (line_num, line) in matches {
-                                let msg = if line_numbers {
-                                    format!(
-                                        "s3://{}/{}:{}:{}",
-                                        bucket,
-                                        key,
-                                        line_num,
-                                        highlight_match(&line, &pattern)
-                                    )
-                                } else {
-                                    format!(
-                                        "s3://{}/{}:{}",
-                                        bucket,
-                                        key,
-                                        highlight_match(&line, &pattern)
+                    // Reserve buffer memory in listing order, so --sort cannot starve
+                    // the object being printed
+                    let _memory = options
+                        .budget
+                        .reserve(object_memory(&object, &options))
+                        .await;
+                    let sink = MatchSink::new(output.clone(), bucket, key, &options);
+                    let search = async {
+                        if ruled_out(client, bucket, &object, &options).await {
+                            return Ok(false);
+                        }
+                        search_object(client, bucket, &object, &options, &sink, byte_progress)
+                            .await
+                            .map(|()| true)
+                    };
+                    let result =
+                        match object_timeout {
+                            Some(limit) => tokio::time::timeout(limit, search)
+                                .await
+                                .unwrap_or_else(|_| {
+                                    Err(io::Error::new(
+                                        io::ErrorKind::TimedOut,
+                                        format!("search timed out after {limit:?}"),
                                     )
-                                };
-                                print_with_target(progress.as_ref(), &msg, OutputTarget::Stdout);
-                            }
+                                    .into())
+                                }),
+                            None => search.await,
+                        };
+                    match result {
+                        Ok(searched) => {
+                            let counter = if searched {
+                                &options.counters.searched
+                            } else {
+                                &options.counters.skipped
+                            };
+                            Counters::add(counter, 1);
+                            complete_checkpoint(checkpoint.as_deref(), key, false, &output).await
+                        }
+                        Err(e) => {
+                            Counters::add(&options.counters.errored, 1);
+                            errors.record(classify(e.as_ref()));
+                            let _ = output
+                                .send((OutputTarget::Stderr, format!("{key}: {e}")))
+                                .await;
+                            // Recorded as failed so a resumed search retries it
+                            complete_checkpoint(checkpoint.as_deref(), key, true, &output).await
                         }
-                        Err(e) => print_with_target(
-                            progress.as_ref(),
-                            format!("{key}: {e}").as_str(),
-                            OutputTarget::Stderr,
-                        ),
                     }
                     if let Some(p) = &progress {
                         p.inc(1);
                     }
                 }
                 Err(e) => {
-                    print_with_target(
-                        progress.as_ref(),
-                        format!("Error searching objects: {e}").as_str(),
-                        OutputTarget::Stderr,
-                    );
+                    errors.record(classify(e.as_ref()));
+                    listing_failed.store(true, Ordering::Relaxed);
+                    let mut msg = format!("Error listing objects: {e}");
                     // Print the error source chain for more detail
                     let mut source = e.source();
                     while let Some(s) = source {
-                        print_with_target(
-                            progress.as_ref(),
-                            format!("  caused by: {s}").as_str(),
-                            OutputTarget::Stderr,
-                        );
+                        msg.push_str(&format!("\ns3grep:   caused by: {s}"));
                         source = s.source();
                     }
+                    let _ = output.send((OutputTarget::Stderr, msg)).await;
                 }
             }
         }
-    })
-    .buffer_unordered(opt.concurrent_tasks);
-
-    futures::StreamExt::collect::<Vec<_>>(search_stream).await;
+    });
+
+    let searches = async {
+        if opt.sort.is_some() {
+            // Ordered buffering keeps only `concurrent_tasks` objects ahead of the one being printed
+            search_stream.buffered(max_tasks).collect::<Vec<_>>().await;
+        } else {
+            search_stream
+                .buffer_unordered(max_tasks)
+                .collect::<Vec<_>>()
+                .await;
+        }
+    };
+    match limits.deadline() {
+        // Searches still running at the deadline are cancelled
+        Some(deadline) => {
+            if tokio::time::timeout_at(deadline.into(), searches)
+                .await
+                .is_err()
+            {
+                let _ = exhausted.set(Exhausted::Time(limits.timeout.unwrap_or_default()));
+            }
+        }
+        None => searches.await,
+    }
+    if let Some(controller) = controller {
+        controller.abort();
+    }
+    interrupt_handler.abort();
+    let interrupted = interrupted.load(Ordering::Relaxed);
+    drop(order);
+    if let Some(forwarder) = forwarder {
+        forwarder.await?;
+    }
+    drop(output);
+    let (matched, binary_matched) = writer.await?;
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.save()?;
+    }
     if let Some(p) = progress {
-        // p.finish_and_clear();
-        p.finish_with_message("Search complete");
-        byte_progress.finish();
+        if interrupted {
+            p.finish_and_clear();
+            byte_progress.finish_and_clear();
+        } else {
+            p.finish_with_message("Search complete");
+            byte_progress.finish();
+        }
     }
     // progress.finish_with_message("Search complete");
 
-    Ok(())
-}
-
+    if let Some(format) = stats {
+        let stats = Stats {
+            objects: options.counters.objects(),
+            bytes_downloaded: options.counters.downloaded.load(Ordering::Relaxed),
+            bytes_scanned: byte_progress.position(),
+            lines_scanned: options.counters.lines.load(Ordering::Relaxed),
+            matches: matched,
+            elapsed: started.elapsed(),
+            requests: requests.snapshot(),
+            peak_buffered: options.budget.peak(),
+            memory_budget: options.budget.limit(),
+            peak_rss: peak_rss(),
+            long_lines: options.long_line_count.load(Ordering::Relaxed),
+        };
+        match format {
+            StatsFormat::Text => eprintln!("{stats}"),
+            StatsFormat::Json => eprintln!("{}", stats.to_json()),
+        }
+    }
+
+    if let Some(trace) = &trace {
+        if let Err(e) = trace.flush() {
+            eprintln!("s3grep: cannot write HTTP trace: {e}");
+        }
+        eprintln!("{}", trace.latencies());
+    }
+
+    // Report how far a search that stopped early got
+    let stopped = if interrupted {
+        Some("interrupted".to_string())
+    } else {
+        exhausted
+            .get()
+            .map(|limit| format!("budget exhausted ({limit})"))
+    };
+    if let Some(reason) = stopped {
+        let objects = options.counters.objects();
+        eprintln!(
+            "s3grep: {reason} after searching {} objects and downloading {} in {:.1}s",
+            objects.searched + objects.skipped + objects.errored,
+            format_size(options.counters.downloaded.load(Ordering::Relaxed)),
+            started.elapsed().as_secs_f64()
+        );
+        if let Some(path) = opt.checkpoint.as_ref().or(opt.resume.as_ref()) {
+            eprintln!("s3grep: continue with --resume {}", path.display());
+        }
+    }
+
+    if errors.total() > 0 {
+        eprintln!("s3grep: {errors}");
+    }
+    // An incomplete listing means objects were never searched at all
+    if interrupted {
+        return Ok(EXIT_INTERRUPTED);
+    }
+    if listing_failed.load(Ordering::Relaxed) {
+        return Ok(EXIT_ERROR);
+    }
+    if exhausted.get().is_some() {
+        return Ok(EXIT_LIMIT);
+    }
+    if errors.total() > 0 && !opt.no_fail_on_errors {
+        return Ok(EXIT_ERROR);
+    }
+    Ok(if matched > 0 || binary_matched {
+        EXIT_MATCH
+    } else {
+        EXIT_NO_MATCH
+    })
+}
+
+/// Returns the tokens to look up in sidecars, warning if there are none, since
+/// --sidecars then skips nothing.
+fn sidecar_tokens(pattern: &str, case_sensitive: bool) -> Option<Vec<u64>> {
+    let tokens = pattern_tokens(pattern, case_sensitive);
+    if tokens.is_none() {
+        if !case_sensitive && !pattern.is_ascii() {
+            eprintln!(
+                "s3grep: --sidecars cannot rule out case-insensitive non-ASCII patterns, so every object will be searched"
+            );
+        } else {
+            eprintln!(
+                "s3grep: --sidecars has no effect because '{pattern}' has no token delimited on both sides, so every object will be searched; add the delimiters around it in the logs, e.g. '={pattern} '"
+            );
+        }
+    }
+    tokens
+}
+
+/// Waits for Ctrl-C, or on Unix SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// The options that decide what a search matches, keyed by flag name, so that
+/// a checkpoint is only resumed with the same ones.
+fn match_options(opt: &Opt) -> serde_json::Map<String, serde_json::Value> {
+    let options = serde_json::json!({
+        "case-sensitive": opt.case_sensitive,
+        "format": opt.format.map(|format| format.to_string()),
+        "where": opt.filters.iter().map(Filter::to_string).collect::<Vec<_>>(),
+        "csv": opt.csv,
+        "tsv": opt.tsv,
+        "column": opt.column.as_ref().map(Column::to_string),
+        "columns": opt.columns,
+        "max-line-length": opt.max_line_length,
+        "long-lines": opt.long_lines.to_string(),
+    });
+    options.as_object().cloned().unwrap_or_default()
+}
+
+/// Marks an object as searched in the checkpoint, if there is one.
+async fn complete_checkpoint(
+    checkpoint: Option<&CheckpointFile>,
+    key: &str,
+    failed: bool,
+    output: &OutputSender,
+) {
+    let update = |checkpoint: &CheckpointFile| {
+        if failed {
+            checkpoint.fail(key)
+        } else {
+            checkpoint.complete(key)
+        }
+    };
+    if let Some(Err(e)) = checkpoint.map(update) {
+        let _ = output
+            .send((OutputTarget::Stderr, format!("Cannot save checkpoint: {e}")))
+            .await;
+    }
+}
+
+/**
+    Builds or updates the trigram index of the objects under a prefix.
+
+    `index build` indexes every object, while `index update` only indexes
+    objects whose current version (by ETag) is not in the index yet.
+*/
+async fn run_index(command: IndexCommand) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (opt, rebuild) = match command {
+        IndexCommand::Build(opt) => (opt, true),
+        IndexCommand::Update(opt) => (opt, false),
+    };
+    let index = Index::open(&opt.index_dir)?;
+    let bucket = opt.location.bucket.as_str();
+    let config = with_role(&load_config(&opt.aws).await, &opt.aws, &opt.location).await;
+    let client = create_client_in_bucket_region_reuse_config(&config, bucket, &opt.aws).await?;
+
+    let (indexed, skipped, failed) = for_each_object(
+        &client,
+        &opt.location,
+        opt.concurrent_tasks,
+        opt.quiet,
+        |object| index_object(&client, bucket, object, &index, rebuild),
+    )
+    .await?;
+    eprintln!("s3grep: indexed {indexed} objects, {skipped} up to date, {failed} failed");
+
+    if failed > 0 {
+        return Err(format!("{failed} objects could not be indexed").into());
+    }
+    Ok(())
+}
+
+/// Writes a bloom filter sidecar next to every object under a prefix.
+async fn run_build_sidecars(
+    opt: SidecarOpt,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let bucket = opt.location.bucket.as_str();
+    let config = with_role(&load_config(&opt.aws).await, &opt.aws, &opt.location).await;
+    let client = create_client_in_bucket_region_reuse_config(&config, bucket, &opt.aws).await?;
+
+    let (built, skipped, failed) = for_each_object(
+        &client,
+        &opt.location,
+        opt.concurrent_tasks,
+        opt.quiet,
+        |object| build_sidecar(&client, bucket, object),
+    )
+    .await?;
+    eprintln!("s3grep: wrote {built} sidecars, skipped {skipped} objects, {failed} failed");
+
+    if failed > 0 {
+        return Err(format!("{failed} sidecars could not be written").into());
+    }
+    Ok(())
+}
+
+/**
+    Runs `task` on every object under a location concurrently, printing errors.
+
+    # Returns
+
+    The number of objects `task` processed (returned true), skipped (returned
+    false) and failed on.
+*/
+async fn for_each_object<F, Fut>(
+    client: &Client,
+    location: &S3Location,
+    concurrent_tasks: usize,
+    quiet: bool,
+    task: F,
+) -> Result<(usize, usize, usize), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(Object) -> Fut,
+    Fut: std::future::Future<Output = Result<bool, Box<dyn std::error::Error>>>,
+{
+    let progress = if !quiet {
+        let p = ProgressBar::new_spinner();
+        p.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} Processed {pos} files... ({per_sec} files/sec)")?,
+        );
+        Some(p)
+    } else {
+        None
+    };
+
+    let results = list_objects_stream(client, &location.bucket, &location.prefix, None, true)
+        .map(|obj| {
+            let progress = progress.clone();
+            let task = &task;
+            async move {
+                let result = match obj {
+                    Ok(object) => {
+                        let key = object.key().unwrap_or_default().to_string();
+                        task(object).await.map_err(|e| format!("{key}: {e}"))
+                    }
+                    Err(e) => Err(format!("Error listing objects: {e}")),
+                };
+                if let Some(p) = &progress {
+                    p.inc(1);
+                }
+                result
+            }
+        })
+        .buffer_unordered(concurrent_tasks.max(1));
+    futures::pin_mut!(results);
+
+    let (mut done, mut skipped, mut failed) = (0, 0, 0);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(true) => done += 1,
+            Ok(false) => skipped += 1,
+            Err(msg) => {
+                failed += 1;
+                print_with_target(progress.as_ref(), &msg, OutputTarget::Stderr);
+            }
+        }
+    }
+    if let Some(p) = &progress {
+        p.finish_and_clear();
+    }
+    Ok((done, skipped, failed))
+}
+
+/// Indexes one object, returning false if it is already indexed or has no ETag to key it by.
+async fn index_object(
+    client: &Client,
+    bucket: &str,
+    object: Object,
+    index: &Index,
+    rebuild: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let key = object.key().unwrap_or_default();
+    let Some(etag) = object.e_tag() else {
+        return Ok(false);
+    };
+    if key.ends_with('/') || (!rebuild && index.contains(bucket, key, etag)) {
+        return Ok(false);
+    }
+
+    // Parquet and Avro are searched by decoded values, not by their bytes
+    let entry = match text_reader(client, bucket, key, etag).await? {
+        Some(mut reader) => {
+            let mut builder = TrigramBuilder::default();
+            loop {
+                let buf = reader.fill_buf().await?;
+                if buf.is_empty() {
+                    break;
+                }
+                builder.feed(buf);
+                let len = buf.len();
+                reader.consume(len);
+            }
+            builder.finish()
+        }
+        None => ObjectIndex::Any,
+    };
+    index.insert(bucket, key, etag, &entry)?;
+    Ok(true)
+}
+
+/// Writes the sidecar for one object, returning false for objects that get no sidecar.
+async fn build_sidecar(
+    client: &Client,
+    bucket: &str,
+    object: Object,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let key = object.key().unwrap_or_default();
+    let Some(etag) = object.e_tag() else {
+        return Ok(false);
+    };
+    if key.ends_with('/') {
+        return Ok(false);
+    }
+    let Some(mut reader) = text_reader(client, bucket, key, etag).await? else {
+        return Ok(false);
+    };
+
+    let mut builder = SidecarBuilder::default();
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        builder.feed(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    client
+        .put_object()
+        .bucket(bucket)
+        .key(sidecar_key(key))
+        .body(ByteStream::from(builder.finish(etag).encode()))
+        .send()
+        .await?;
+    Ok(true)
+}
+
+/**
+    Opens this version of an object as text, decompressing `.gz` objects.
+
+    Returns `None` for Parquet and Avro objects, which are searched by their
+    decoded values rather than their bytes.
+*/
+async fn text_reader(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    etag: &str,
+) -> Result<Option<Box<dyn tokio::io::AsyncBufRead + Unpin>>, Box<dyn std::error::Error>> {
+    if key.ends_with(".parquet") {
+        return Ok(None);
+    }
+    let resp = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .if_match(etag)
+        .send()
+        .await?;
+    let body = resp.body.into_async_read();
+    let mut reader: Box<dyn tokio::io::AsyncBufRead + Unpin> = if key.ends_with(".gz") {
+        Box::new(BufReader::with_capacity(
+            READ_BUFFER,
+            GzipDecoder::new(body),
+        ))
+    } else {
+        Box::new(BufReader::with_capacity(READ_BUFFER, body))
+    };
+    if reader.fill_buf().await?.starts_with(AVRO_MAGIC) {
+        return Ok(None);
+    }
+    Ok(Some(reader))
+}
+
+/// Returns false if the object's sidecar is fresh and rules out one of `tokens`.
+///
+/// Missing, unreadable and stale sidecars never rule an object out.
+async fn sidecar_may_match(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    etag: &str,
+    tokens: &[u64],
+) -> bool {
+    let Ok(resp) = client
+        .get_object()
+        .bucket(bucket)
+        .key(sidecar_key(key))
+        .send()
+        .await
+    else {
+        return true;
+    };
+    let Ok(bytes) = resp.body.collect().await else {
+        return true;
+    };
+    match Sidecar::decode(&bytes.into_bytes()) {
+        Ok(sidecar) => !sidecar.is_fresh(etag) || sidecar.may_contain_all(tokens),
+        Err(_) => true,
+    }
+}
+
 /**
     Prints a message, suspending the progress bar if present.
 
@@ -289,7 +1746,9 @@ where
 */
 fn print_with_target(progress: Option<&ProgressBar>, msg: &str, target: OutputTarget) {
     match target {
-        OutputTarget::Stdout => print_message_with_progress(progress, msg, |m| println!("{m}")),
+        OutputTarget::Stdout | OutputTarget::BinaryMatch => {
+            print_message_with_progress(progress, msg, |m| println!("{m}"))
+        }
         OutputTarget::Stderr => {
             print_message_with_progress(progress, msg, |m| eprintln!("s3grep: {m}"))
         }
@@ -297,23 +1756,29 @@ fn print_with_target(progress: Option<&ProgressBar>, msg: &str, target: OutputTa
 }
 
 /**
-    Streams S3 object keys from the specified bucket and prefix.
+    Streams S3 objects from the specified bucket and prefix.
 
     # Arguments
 
     * `client` - AWS S3 client.
     * `bucket` - S3 bucket name.
     * `prefix` - S3 prefix to search in.
+    * `start_after` - Only list keys after this one.
+    * `skip_sidecars` - Leave out bloom filter sidecars, for searches that use
+      them and for building them.
 
     # Returns
 
-    A stream of object keys as `Result<String, Box<dyn std::error::Error>>`.
+    A stream of objects (with their key, size and other listing metadata) as
+    `Result<Object, Box<dyn std::error::Error>>`.
 */
 fn list_objects_stream<'a>(
     client: &'a Client,
     bucket: &'a str,
     prefix: &'a str,
-) -> impl futures::Stream<Item = Result<String, Box<dyn std::error::Error>>> + 'a {
+    start_after: Option<&'a str>,
+    skip_sidecars: bool,
+) -> impl futures::Stream<Item = Result<Object, Box<dyn std::error::Error>>> + 'a {
     stream::unfold(
         (
             client.clone(),
@@ -328,132 +1793,1111 @@ fn list_objects_stream<'a>(
                 None => return None,
             };
 
-            let mut req = client
-                .list_objects_v2()
-                .bucket(bucket.to_owned())
-                .prefix(&prefix);
-
-            // Only set continuation token if it's not empty
-            if !token.is_empty() {
-                req = req.continuation_token(token);
-            }
-
-            match req.send().await {
-                Ok(resp) => {
-                    let objects: Vec<_> = resp
-                        .contents()
-                        .iter()
-                        .filter_map(|obj| obj.key.clone())
-                        .collect();
+            let mut attempt = 0;
+            let resp = loop {
+                let mut req = client
+                    .list_objects_v2()
+                    .bucket(bucket.to_owned())
+                    .prefix(&prefix);
+
+                // Only set continuation token if it's not empty
+                if !token.is_empty() {
+                    req = req.continuation_token(&token);
+                } else if let Some(start_after) = start_after {
+                    req = req.start_after(start_after);
+                }
 
-                    if objects.is_empty() && resp.next_continuation_token().is_none() {
-                        return None;
+                match req.send().await {
+                    Ok(resp) => break resp,
+                    // Retry the page, unless the error will not go away
+                    Err(e) if attempt < LIST_RETRIES && classify(&e) != ErrorKind::AccessDenied => {
+                        tokio::time::sleep(LIST_RETRY_DELAY * 2u32.pow(attempt)).await;
+                        attempt += 1;
                     }
-
-                    let next_token = resp.next_continuation_token().map(|t| t.to_string());
-                    // If we have no objects and no next token, we're done
-                    if objects.is_empty() && next_token.is_none() {
-                        None
-                    } else {
-                        Some((
-                            futures::stream::iter(objects.into_iter().map(Ok)),
-                            (client, bucket, prefix, next_token),
-                        ))
+                    Err(e) => {
+                        // End the listing with the error, so it is not mistaken for the end
+                        let error: Box<dyn std::error::Error> = Box::new(e);
+                        return Some((
+                            stream::iter(vec![Err(error)]),
+                            (client, bucket, prefix, None),
+                        ));
                     }
                 }
-                Err(e) => {
-                    eprintln!("Error listing objects: {e}");
-                    let empty_vec: Vec<String> = vec![];
-                    let error_stream = empty_vec
-                        .into_iter()
-                        .map(Ok::<String, Box<dyn std::error::Error>>);
-                    Some((stream::iter(error_stream), (client, bucket, prefix, None)))
-                }
+            };
+
+            let objects: Vec<_> = resp
+                .contents()
+                .iter()
+                // Sidecars are s3grep's own metadata, not objects to search
+                .filter(|obj| {
+                    !skip_sidecars || obj.key().is_some_and(|key| !key.ends_with(SIDECAR_SUFFIX))
+                })
+                .cloned()
+                .map(Ok)
+                .collect();
+
+            let next_token = resp.next_continuation_token().map(|t| t.to_string());
+            // If we have no objects and no next token, we're done
+            if objects.is_empty() && next_token.is_none() {
+                None
+            } else {
+                Some((stream::iter(objects), (client, bucket, prefix, next_token)))
             }
         },
     )
     .flatten()
 }
 
-async fn search_object(
+/// A download body that is resumed with ranged GETs when it breaks, so no
+/// bytes are read twice. `etag` pins the resumed GETs to the version being read,
+/// and `end`, if given, is the offset the body stops before.
+fn resuming_body(
     client: &Client,
     bucket: &str,
     key: &str,
-    pattern: &str,
-    case_sensitive: bool,
+    etag: Option<String>,
+    (start, end): (u64, Option<u64>),
+    body: ByteStream,
+    options: &SearchOptions,
+) -> impl AsyncRead + Unpin + Send {
+    let (client, bucket, key) = (client.clone(), bucket.to_string(), key.to_string());
+    let body: Box<dyn AsyncRead + Unpin + Send> = Box::new(body.into_async_read());
+    let body = ResumingReader::new(body, start, options.retries, move |offset| -> Reopen<_> {
+        let range = match end {
+            Some(end) => format!("bytes={offset}-{}", end - 1),
+            None => format!("bytes={offset}-"),
+        };
+        let request = client
+            .get_object()
+            .bucket(&bucket)
+            .key(&key)
+            .range(range)
+            .set_if_match(etag.clone());
+        Box::pin(async move {
+            let resp = request.send().await.map_err(io::Error::other)?;
+            Ok(Box::new(resp.body.into_async_read()) as Box<dyn AsyncRead + Unpin + Send>)
+        })
+    });
+    CountingReader::downloaded(body, options.counters.clone())
+}
+
+/**
+    Estimates a search from its listing, for `--dry-run`.
+
+    Objects the checkpoint or the index rule out are left out, as the search
+    would skip them; sidecars are not fetched, so they are not applied.
+*/
+async fn dry_run(
+    objects: impl futures::Stream<Item = Result<(&SearchTarget, Object), Box<dyn std::error::Error>>>,
+    targets: usize,
+    options: &SearchOptions,
+    concurrency: usize,
+) -> Result<DryRun, Box<dyn std::error::Error + Send + Sync>> {
+    let mut dry_run = DryRun {
+        concurrency,
+        ..Default::default()
+    };
+    futures::pin_mut!(objects);
+    while let Some(object) = objects.next().await {
+        let (target, object) = object.map_err(|e| format!("Error listing objects: {e}"))?;
+        let key = object.key().unwrap_or_default();
+        let skipped = match (&options.index, object.e_tag()) {
+            (Some(index), Some(etag)) => !index.may_match(&target.location.bucket, key, etag),
+            _ => false,
+        };
+        if key.ends_with('/') || skipped {
+            continue;
+        }
+        let size = object.size().unwrap_or_default() as u64;
+        // Parquet needs the footer and then column chunks unless it is fetched whole
+        // for the cache; split objects need a probe and each range
+        let gets = if key.ends_with(".parquet") && !fits_in_cache(&object, options) {
+            2
+        } else if split_into_ranges(&object, options) {
+            1 + size.div_ceil(options.range_size)
+        } else {
+            1
+        };
+        let class = object
+            .storage_class()
+            .map_or("STANDARD", |class| class.as_str());
+        dry_run.add(class, size, gets);
+    }
+    // Each target is listed separately, so each may end with a partial page
+    let listed = options.counters.listed.load(Ordering::Relaxed);
+    let pages = listed.div_ceil(1000).max(1) + targets as u64 - 1;
+    dry_run.requests.insert("ListObjectsV2".to_string(), pages);
+    Ok(dry_run)
+}
+
+/// Returns true if the index or the object's sidecar shows it cannot match.
+async fn ruled_out(
+    client: &Client,
+    bucket: &str,
+    object: &Object,
+    options: &SearchOptions,
+) -> bool {
+    let key = object.key().unwrap_or_default();
+
+    // Objects the index rules out are skipped before any request
+    if let (Some(index), Some(etag)) = (&options.index, object.e_tag()) {
+        if !index.may_match(bucket, key, etag) {
+            return true;
+        }
+    }
+    if let (Some(tokens), Some(etag)) = (&options.sidecar_tokens, object.e_tag()) {
+        if !sidecar_may_match(client, bucket, key, etag, tokens).await {
+            return true;
+        }
+    }
+    false
+}
+
+async fn search_object(
+    client: &Client,
+    bucket: &str,
+    object: &Object,
+    options: &SearchOptions,
+    sink: &MatchSink,
     byte_progress: ProgressBar,
-) -> Result<Vec<(usize, String)>, Box<dyn std::error::Error>> {
-    let resp = client.get_object().bucket(bucket).key(key).send().await?;
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = object.key().unwrap_or_default();
+
+    // A cached copy is fresh if it has the ETag from the listing
+    let cached = match (&options.cache, object.e_tag()) {
+        (Some(cache), Some(etag)) => cache.get(bucket, key, etag).await,
+        _ => None,
+    };
+
+    if options.s3_select && cached.is_none() {
+        if let Some(request) = select_request(key, options) {
+            // Objects S3 Select cannot handle fall back to a regular GET, unless
+            // some matches were already printed; other errors are reported
+            match search_select(client, bucket, key, options, request, sink, &byte_progress).await {
+                Ok(()) => return Ok(()),
+                Err(e) if sink.sent() == 0 && select_unsupported(e.as_ref()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    if key.ends_with(".parquet") {
+        // Parquet objects that fit in the cache are fetched whole so that they can
+        // be stored, then read from disk
+        let cached = match cached {
+            Some(file) => Some(file),
+            None if fits_in_cache(object, options) => {
+                fetch_into_cache(client, bucket, key, options).await?
+            }
+            None => None,
+        };
+        let source = match cached {
+            Some(file) => ParquetSource::Cached(file),
+            None => ParquetSource::Remote {
+                client,
+                bucket,
+                key,
+                etag: None,
+            },
+        };
+        return search_parquet(source, options, sink, byte_progress).await;
+    }
+
+    if cached.is_none()
+        && split_into_ranges(object, options)
+        && !get_range(client, bucket, key, None, 0, AVRO_MAGIC.len() as u64)
+            .await?
+            .starts_with(AVRO_MAGIC)
+    {
+        return search_ranges(client, bucket, object, options, sink, byte_progress).await;
+    }
+
+    let body: Box<dyn AsyncRead + Unpin> = match cached {
+        Some(file) => Box::new(file),
+        None => {
+            let resp = client.get_object().bucket(bucket).key(key).send().await?;
+            // Resuming works on the stored bytes, below any decompression
+            let etag = resp.e_tag.clone();
+            let body = resuming_body(
+                client,
+                bucket,
+                key,
+                etag.clone(),
+                (0, None),
+                resp.body,
+                options,
+            );
+            // Keyed by the ETag of the bytes fetched, which differs from the listing's
+            // if the object was overwritten since
+            match (&options.cache, etag) {
+                (Some(cache), Some(etag))
+                    if resp.content_length.unwrap_or_default() as u64 <= cache.max_size() =>
+                {
+                    Box::new(cache.writer(bucket, key, &etag, body))
+                }
+                _ => Box::new(body),
+            }
+        }
+    };
 
     // Add support for .gz files
     let gz_compression = key.ends_with(".gz");
-    let body = resp.body.into_async_read();
     let mut reader: Box<dyn tokio::io::AsyncBufRead + Unpin> = if gz_compression {
-        Box::new(BufReader::new(GzipDecoder::new(body)))
+        Box::new(BufReader::with_capacity(
+            READ_BUFFER,
+            GzipDecoder::new(BufReader::new(body)),
+        ))
     } else {
-        Box::new(BufReader::new(body))
+        Box::new(BufReader::with_capacity(READ_BUFFER, body))
     };
 
+    if let Some(delimited) = &options.delimited {
+        return search_delimited(reader, options, delimited, sink, byte_progress).await;
+    }
+
+    // Avro container files are recognized by their magic bytes
+    if reader.fill_buf().await?.starts_with(AVRO_MAGIC) {
+        return search_avro(reader, options, sink, byte_progress).await;
+    }
+
     // Binary flag
     let mut is_binary = false; //is_binary(&mut reader).await?;
 
+    let mut scanner = LineScanner::new(options.matcher.clone())
+        .with_max_line_length(options.max_line_length, options.long_lines);
+    let mut found = Vec::new();
+
+    loop {
+        let bytes = reader.fill_buf().await?;
+        let done = bytes.is_empty();
+        if done {
+            // Handle last line if it doesn't end with a newline
+            scanner.finish(|line_num, line| {
+                found.extend(line_output(line, options).map(|o| (line_num, o)))
+            });
+        } else {
+            // Check for NUL bytes in current buffer
+            if !is_binary && memchr(0, bytes).is_some() {
+                is_binary = true;
+            }
+
+            scanner.feed(bytes, |line_num, line| {
+                found.extend(line_output(line, options).map(|o| (line_num, o)))
+            });
+
+            let length = bytes.len();
+            byte_progress.inc(length as u64);
+            reader.consume(length);
+        }
+
+        // Like grep, stop printing lines once the object turns out to be binary
+        if is_binary && !found.is_empty() {
+            Counters::add(&options.counters.lines, scanner.lines() as u64);
+            return sink
+                .print(
+                    OutputTarget::BinaryMatch,
+                    format!("Binary file {key} matches"),
+                )
+                .await;
+        }
+        for (line_num, output) in found.drain(..) {
+            sink.send(Position::Line(line_num), &output).await?;
+        }
+
+        if done {
+            Counters::add(&options.counters.lines, scanner.lines() as u64);
+            return report_long_lines(sink, options, scanner.long_lines()).await;
+        }
+    }
+}
+
+/// Returns true if an object is large enough to be searched as concurrent ranges.
+///
+/// Objects that fit in the cache are fetched whole instead, so that they can be
+/// stored. Avro files are only recognized once their first bytes are read, so
+/// they are not ruled out here.
+fn split_into_ranges(object: &Object, options: &SearchOptions) -> bool {
+    let key = object.key().unwrap_or_default();
+    let size = object.size().unwrap_or_default() as u64;
+    !fits_in_cache(object, options)
+        && options.range_threshold > 0
+        && size >= options.range_threshold
+        && options.delimited.is_none()
+        && !key.ends_with(".gz")
+        && !key.ends_with(".parquet")
+}
+
+/// Returns true if the object is small enough to be stored in the cache, if there is one.
+fn fits_in_cache(object: &Object, options: &SearchOptions) -> bool {
+    options
+        .cache
+        .as_ref()
+        .is_some_and(|cache| object.size().unwrap_or_default() as u64 <= cache.max_size())
+}
+
+/// Returns true for directory markers, which are listed but never searched.
+fn is_directory(object: &Object) -> bool {
+    object.key().unwrap_or_default().ends_with('/')
+}
+
+/**
+    Estimates the buffer memory a search of the object needs.
+
+    Line searches hold a read buffer and at most one partial line per stream; a
+    split object has up to `range_concurrency` streams. CSV records and Avro
+    blocks are held to the same limit as lines. Parquet searches hold their
+    footer and one row group's column chunks, which are never larger than the object.
+*/
+fn object_memory(object: &Object, options: &SearchOptions) -> u64 {
+    let stream = (READ_BUFFER + options.max_line_length) as u64;
+    if object.key().unwrap_or_default().ends_with(".parquet") {
+        // The footer and column chunks fetched are at most the whole object; the
+        // budget caps larger requests at its limit
+        object.size().unwrap_or_default() as u64
+    } else if split_into_ranges(object, options) {
+        let size = object.size().unwrap_or_default() as u64;
+        let ranges = size.div_ceil(options.range_size);
+        stream * ranges.min(options.range_concurrency as u64)
+    } else {
+        stream
+    }
+}
+
+/// Prints a warning about long lines in an object and adds them to the total.
+async fn report_long_lines(
+    sink: &MatchSink,
+    options: &SearchOptions,
+    long_lines: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if long_lines == 0 {
+        return Ok(());
+    }
+    options
+        .long_line_count
+        .fetch_add(long_lines as u64, Ordering::Relaxed);
+    let action = match options.long_lines {
+        LongLines::Truncate => "truncated",
+        LongLines::Skip => "skipped",
+    };
+    sink.print(
+        OutputTarget::Stderr,
+        format!(
+            "{}: {long_lines} lines longer than {} were {action}",
+            sink.key,
+            format_size(options.max_line_length as u64)
+        ),
+    )
+    .await
+}
+
+/// Lines and matches found in one range of a split object.
+struct RangeMatches {
+    /// Number of lines that start within the range
+    lines: usize,
+    /// Matches, numbered from the first line of the range
+    matches: Vec<(usize, String)>,
+    is_binary: bool,
+    /// Number of lines longer than the maximum line length
+    long_lines: usize,
+}
+
+/**
+    Searches a large uncompressed object as byte ranges fetched concurrently.
+
+    Each range is responsible for the lines that start within it: it skips the
+    tail of a line begun in the previous range and reads past its end to finish
+    its own last line. Line numbers are then offset by the line counts of the
+    preceding ranges, so the output is the same as a sequential scan.
+*/
+async fn search_ranges(
+    client: &Client,
+    bucket: &str,
+    object: &Object,
+    options: &SearchOptions,
+    sink: &MatchSink,
+    byte_progress: ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = object.key().unwrap_or_default();
+    let size = object.size().unwrap_or_default() as u64;
+    let ranges: Vec<(u64, u64)> = (0..size)
+        .step_by(options.range_size as usize)
+        .map(|start| (start, (start + options.range_size).min(size)))
+        .collect();
+
+    // Ranges complete in order, so each one's matches can be printed as soon as
+    // it and the ranges before it are done. Ranges still running are aborted if
+    // the object is abandoned, e.g. on an error or a timeout
+    let mut results = stream::iter(ranges)
+        .map(|(start, end)| {
+            AbortOnDrop(tokio::spawn(search_range(
+                client.clone(),
+                bucket.to_string(),
+                key.to_string(),
+                options.clone(),
+                (start, end, size),
+                byte_progress.clone(),
+            )))
+        })
+        .buffered(options.range_concurrency);
+
+    let mut first_line = 0;
+    let mut is_binary = false;
+    let mut long_lines = 0;
+    while let Some(result) = results.next().await {
+        let range = result?.map_err(|e| e as Box<dyn std::error::Error>)?;
+        is_binary |= range.is_binary;
+        if is_binary && !range.matches.is_empty() {
+            return sink
+                .print(
+                    OutputTarget::BinaryMatch,
+                    format!("Binary file {key} matches"),
+                )
+                .await;
+        }
+        for (line_num, output) in range.matches {
+            sink.send(Position::Line(first_line + line_num), &output)
+                .await?;
+        }
+        first_line += range.lines;
+        long_lines += range.long_lines;
+    }
+    Counters::add(&options.counters.lines, first_line as u64);
+    report_long_lines(sink, options, long_lines).await
+}
+
+/// A spawned task that is aborted if its handle is dropped before it finishes.
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/**
+    Searches the lines that start within `start..end` of an object of `size` bytes.
+
+    The range is fetched from the byte before it up to `end`. If its last line
+    runs past `end`, further ranges are fetched, each twice as long as the one
+    before and the first no longer than the range itself, until the line ends.
+*/
+async fn search_range(
+    client: Client,
+    bucket: String,
+    key: String,
+    options: SearchOptions,
+    (start, end, size): (u64, u64, u64),
+    byte_progress: ProgressBar,
+) -> Result<RangeMatches, Box<dyn std::error::Error + Send + Sync>> {
+    let mut range = RangeScanner::new(
+        LineScanner::new(options.matcher.clone())
+            .with_max_line_length(options.max_line_length, options.long_lines),
+        start,
+        end,
+    );
     let mut matches = Vec::new();
-    let mut line_buffer = Vec::new();
-    let mut line_num = 0;
+    let mut on_match = |line_num: usize, line: &[u8]| {
+        if let Some(output) = line_output(line, &options) {
+            matches.push((line_num, output));
+        }
+    };
+
+    let mut etag = None;
+    let mut fetch_end = end;
+    let mut extension = LINE_EXTENSION.min(end - start);
+    loop {
+        let fetch_start = range.offset();
+        let resp = client
+            .get_object()
+            .bucket(&bucket)
+            .key(&key)
+            .range(format!("bytes={fetch_start}-{}", fetch_end - 1))
+            .set_if_match(etag.clone())
+            .send()
+            .await?;
+        // Later fetches must read the same version of the object
+        etag = resp.e_tag.clone();
+        let body = resuming_body(
+            &client,
+            &bucket,
+            &key,
+            resp.e_tag,
+            (fetch_start, Some(fetch_end)),
+            resp.body,
+            &options,
+        );
+        let mut reader = BufReader::with_capacity(READ_BUFFER, body);
+        while !range.is_done() {
+            let bytes = reader.fill_buf().await?;
+            if bytes.is_empty() {
+                break;
+            }
+            let length = bytes.len();
+            byte_progress.inc(range.feed(bytes, &mut on_match) as u64);
+            reader.consume(length);
+        }
+
+        if range.is_done() {
+            break;
+        }
+        if fetch_end >= size {
+            // Handle last line of the object if it doesn't end with a newline
+            range.finish(&mut on_match);
+            break;
+        }
+        fetch_end = (fetch_end + extension).min(size);
+        extension *= 2;
+    }
+
+    Ok(RangeMatches {
+        lines: range.lines(),
+        matches,
+        is_binary: range.is_binary(),
+        long_lines: range.long_lines(),
+    })
+}
+
+/**
+    Searches a CSV/TSV object record by record.
+
+    The first record of the object is used as the header. Matching rows are
+    returned with the line number they start on, rendered with their column names.
+    Records are held to `--max-line-length` like lines, so an unclosed quote
+    cannot buffer the rest of the object.
+*/
+async fn search_delimited(
+    mut reader: Box<dyn tokio::io::AsyncBufRead + Unpin>,
+    options: &SearchOptions,
+    query: &DelimitedQuery,
+    sink: &MatchSink,
+    byte_progress: ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pattern = options.pattern.as_str();
+    let case_sensitive = options.case_sensitive;
+    let mut records = RecordReader::new(query.delimiter)
+        .with_max_record_length(options.max_line_length, options.long_lines);
+    // The header row and the position of the column to search in it
+    let mut header: Option<(Vec<String>, Option<usize>)> = None;
+    let mut rows = Vec::new();
 
     loop {
         let bytes = reader.fill_buf().await?;
-        if bytes.is_empty() {
+        let done = bytes.is_empty();
+        if done {
+            rows.extend(records.finish());
+        } else {
+            records.feed(bytes, &mut rows);
+            byte_progress.inc(bytes.len() as u64);
+            let length = bytes.len();
+            reader.consume(length);
+        }
+
+        for (line_num, row) in rows.drain(..) {
+            match &header {
+                None => {
+                    let column = query.resolve(&row)?;
+                    header = Some((row, column));
+                }
+                Some((header, column)) => {
+                    if let Some(output) =
+                        query.matches(header, &row, *column, pattern, case_sensitive)
+                    {
+                        sink.send(Position::Line(line_num), &output).await?;
+                    }
+                }
+            }
+        }
+
+        if done {
             break;
         }
+    }
+
+    report_long_lines(sink, options, records.long_records()).await
+}
+
+/**
+    Plans an S3 Select request for an object.
+
+    Returns `None` if the search cannot be expressed in S3 Select (Avro files, CSV
+    searches without a named column, and Parquet searches without `--columns`).
+*/
+fn select_request(key: &str, options: &SearchOptions) -> Option<SelectRequest> {
+    let compression = if key.ends_with(".gz") {
+        CompressionType::Gzip
+    } else if key.ends_with(".bz2") {
+        CompressionType::Bzip2
+    } else {
+        CompressionType::None
+    };
 
-        // Check for NUL bytes in current buffer
-        if !is_binary && bytes.contains(&0) {
-            is_binary = true;
+    if key.ends_with(".avro") {
+        return None;
+    }
+
+    if key.ends_with(".parquet") {
+        if options.columns.is_empty() {
+            return None;
         }
+        // Return the same columns that a search without S3 Select prints
+        let projection: Vec<String> = options
+            .columns
+            .iter()
+            .map(|column| column_reference(column))
+            .collect();
+        let columns: Vec<String> = projection
+            .iter()
+            .map(|column| format!("CAST({column} AS STRING)"))
+            .collect();
+        return Some(SelectRequest {
+            input: InputSerialization::builder()
+                .parquet(ParquetInput::builder().build())
+                .build(),
+            expression: projected_expression(
+                &projection,
+                &columns,
+                &options.pattern,
+                options.case_sensitive,
+            ),
+            records: SelectRecords::Json,
+        });
+    }
+
+    if let Some(delimited) = &options.delimited {
+        let Some(Column::Name(name)) = &delimited.column else {
+            return None;
+        };
+        let csv = CsvInput::builder()
+            .file_header_info(FileHeaderInfo::Use)
+            .field_delimiter((delimited.delimiter as char).to_string())
+            .allow_quoted_record_delimiter(true)
+            .build();
+        return Some(SelectRequest {
+            input: InputSerialization::builder()
+                .csv(csv)
+                .compression_type(compression)
+                .build(),
+            expression: expression(
+                &[column_reference(name)],
+                &options.pattern,
+                options.case_sensitive,
+            ),
+            records: SelectRecords::Json,
+        });
+    }
+
+    // Read each line as a single CSV column. Carriage return as the field
+    // delimiter only splits off the end of CRLF lines, and NEL (U+0085) stands in
+    // for the quote and comment markers so quotes and '#' in a line are kept.
+    // Both must be characters that are valid in the XML request body.
+    let csv = CsvInput::builder()
+        .file_header_info(FileHeaderInfo::None)
+        .record_delimiter("\n")
+        .field_delimiter("\r")
+        .quote_character("\u{85}")
+        .quote_escape_character("\u{85}")
+        .comments("\u{85}")
+        .build();
+    Some(SelectRequest {
+        input: InputSerialization::builder()
+            .csv(csv)
+            .compression_type(compression)
+            .build(),
+        expression: expression(
+            &[LINE_COLUMN.to_string()],
+            &options.pattern,
+            options.case_sensitive,
+        ),
+        records: SelectRecords::Lines,
+    })
+}
 
-        for &byte in bytes {
-            if byte == b'\n' {
-                line_num += 1;
-                let line = String::from_utf8_lossy(&line_buffer).to_string();
-                byte_progress.inc(line_buffer.len() as u64);
+/**
+    Searches an object with S3 Select, streaming the matching records back.
 
-                if line_matches(&line, pattern, case_sensitive) {
-                    if is_binary {
-                        break;
+    Returns an error if S3 Select rejects the object or the event stream fails.
+    Matches are sent as they arrive, so the caller can only retry the object
+    another way if none have been sent.
+*/
+async fn search_select(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    options: &SearchOptions,
+    request: SelectRequest,
+    sink: &MatchSink,
+    byte_progress: &ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut resp = client
+        .select_object_content()
+        .bucket(bucket)
+        .key(key)
+        .expression(request.expression)
+        .expression_type(ExpressionType::Sql)
+        .input_serialization(request.input)
+        .output_serialization(
+            OutputSerialization::builder()
+                .json(JsonOutput::builder().record_delimiter("\n").build())
+                .build(),
+        )
+        .send()
+        .await?;
+
+    let mut pending = Vec::new();
+    let mut complete = false;
+
+    while let Some(event) = resp.payload.recv().await? {
+        match event {
+            SelectObjectContentEventStream::Records(event) => {
+                if let Some(payload) = event.payload() {
+                    pending.extend_from_slice(payload.as_ref());
+                }
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    let record: Vec<u8> = pending.drain(..=end).collect();
+                    let record = &record[..end];
+                    let output = match request.records {
+                        SelectRecords::Lines => line_from_record(record)
+                            .and_then(|line| apply_query(options.query.as_ref(), line)),
+                        SelectRecords::Json => Some(String::from_utf8_lossy(record).to_string()),
+                    };
+                    if let Some(output) = output {
+                        sink.send(Position::Unknown, &output).await?;
                     }
-                    matches.push((line_num, line));
                 }
-                line_buffer.clear();
-            } else {
-                line_buffer.push(byte);
             }
+            SelectObjectContentEventStream::Stats(event) => {
+                let details = event.details();
+                if let Some(processed) = details.and_then(|d| d.bytes_processed()) {
+                    byte_progress.inc(processed as u64);
+                }
+                if let Some(returned) = details.and_then(|d| d.bytes_returned()) {
+                    Counters::add(&options.counters.downloaded, returned as u64);
+                }
+            }
+            SelectObjectContentEventStream::End(_) => complete = true,
+            _ => {}
         }
+    }
+
+    if !complete {
+        return Err("S3 Select response ended before the End event".into());
+    }
+    Ok(())
+}
 
+/**
+    Searches an Avro object container file record by record.
+
+    Records are decoded with the writer schema from the file header and matched
+    against their JSON rendering, or against the fields selected with `--columns`.
+*/
+async fn search_avro(
+    mut reader: Box<dyn tokio::io::AsyncBufRead + Unpin>,
+    options: &SearchOptions,
+    sink: &MatchSink,
+    byte_progress: ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut decoder = ContainerDecoder::new().with_max_block_size(options.max_line_length);
+
+    loop {
+        let bytes = reader.fill_buf().await?;
+        if bytes.is_empty() {
+            break;
+        }
+        decoder.feed(bytes);
+        byte_progress.inc(bytes.len() as u64);
         let length = bytes.len();
         reader.consume(length);
+
+        let records = decoder
+            .decode_available()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (index, record) in records {
+            if record_matches(
+                &record,
+                &options.columns,
+                &options.pattern,
+                options.case_sensitive,
+            ) {
+                sink.send(Position::Record(index), &record.to_string())
+                    .await?;
+            }
+        }
+    }
+    decoder
+        .finish()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let long_blocks = decoder.long_blocks();
+    if long_blocks > 0 {
+        options
+            .long_line_count
+            .fetch_add(long_blocks as u64, Ordering::Relaxed);
+        sink.print(
+            OutputTarget::Stderr,
+            format!(
+                "{}: {long_blocks} Avro blocks larger than {} were skipped",
+                sink.key,
+                format_size(options.max_line_length as u64)
+            ),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/**
+    Downloads a whole object into the cache, for searches that read it at
+    random offsets.
+
+    Returns the cached copy, or `None` if it could not be stored.
+*/
+async fn fetch_into_cache(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    options: &SearchOptions,
+) -> Result<Option<tokio::fs::File>, Box<dyn std::error::Error>> {
+    let Some(cache) = &options.cache else {
+        return Ok(None);
+    };
+    let resp = client.get_object().bucket(bucket).key(key).send().await?;
+    let Some(etag) = resp.e_tag.clone() else {
+        return Ok(None);
+    };
+    let body = resuming_body(
+        client,
+        bucket,
+        key,
+        Some(etag.clone()),
+        (0, None),
+        resp.body,
+        options,
+    );
+    tokio::io::copy(
+        &mut cache.writer(bucket, key, &etag, body),
+        &mut tokio::io::sink(),
+    )
+    .await?;
+    Ok(cache.get(bucket, key, &etag).await)
+}
+
+/// Where a Parquet search reads the footer and column chunks from.
+enum ParquetSource<'a> {
+    /// Ranged GETs, pinned to the version whose footer was read
+    Remote {
+        client: &'a Client,
+        bucket: &'a str,
+        key: &'a str,
+        etag: Option<String>,
+    },
+    /// A cached copy of the object
+    Cached(tokio::fs::File),
+}
+
+impl ParquetSource<'_> {
+    /// Reads up to `length` bytes from the end, returning them with the object's length.
+    async fn read_tail(&mut self, length: u64) -> Result<(Bytes, u64), Box<dyn std::error::Error>> {
+        match self {
+            ParquetSource::Remote {
+                client,
+                bucket,
+                key,
+                etag,
+            } => {
+                let tail = client
+                    .get_object()
+                    .bucket(*bucket)
+                    .key(*key)
+                    .range(format!("bytes=-{length}"))
+                    .send()
+                    .await?;
+                let total_len = tail
+                    .content_range()
+                    .and_then(|range| range.rsplit('/').next())
+                    .and_then(|len| len.parse::<u64>().ok())
+                    .ok_or("missing object length in Content-Range")?;
+                // Column chunks are read at offsets from this footer, so they must come
+                // from the same version
+                *etag = tail.e_tag.clone();
+                Ok((tail.body.collect().await?.into_bytes(), total_len))
+            }
+            ParquetSource::Cached(file) => {
+                let total_len = file.metadata().await?.len();
+                let offset = total_len.saturating_sub(length);
+                Ok((self.read(offset, total_len - offset).await?, total_len))
+            }
+        }
     }
 
-    // Handle last line if it doesn't end with a newline
-    if !line_buffer.is_empty() {
-        line_num += 1;
-        let line = String::from_utf8_lossy(&line_buffer).to_string();
-        byte_progress.inc(line_buffer.len() as u64);
+    /// Reads `length` bytes starting at `offset`.
+    async fn read(
+        &mut self,
+        offset: u64,
+        length: u64,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        match self {
+            ParquetSource::Remote {
+                client,
+                bucket,
+                key,
+                etag,
+            } => get_range(client, bucket, key, etag.as_deref(), offset, length).await,
+            ParquetSource::Cached(file) => {
+                if offset.saturating_add(length) > file.metadata().await?.len() {
+                    return Err("Parquet column chunk runs past the end of the object".into());
+                }
+                file.seek(io::SeekFrom::Start(offset)).await?;
+                let mut bytes = vec![0; length as usize];
+                file.read_exact(&mut bytes).await?;
+                Ok(bytes.into())
+            }
+        }
+    }
 
-        if line_matches(&line, pattern, case_sensitive) {
-            matches.push((line_num, line));
+    /// Counts bytes read towards the progress bar, and towards downloads if fetched.
+    fn count(&self, bytes: &Bytes, options: &SearchOptions, byte_progress: &ProgressBar) {
+        byte_progress.inc(bytes.len() as u64);
+        if let ParquetSource::Remote { .. } = self {
+            Counters::add(&options.counters.downloaded, bytes.len() as u64);
         }
     }
+}
 
-    if is_binary && !matches.is_empty() {
-        print_with_target(
-            Some(&byte_progress),
-            format!("Binary file {key} matches").as_str(),
-            OutputTarget::Stdout,
-        );
-        return Ok(Vec::new());
+/**
+    Searches a Parquet object row by row.
+
+    The footer is read from the end of the object, then only the column chunks
+    of the projected columns are read for each row group that its statistics do
+    not rule out. Matching rows are returned as JSON.
+*/
+async fn search_parquet(
+    mut source: ParquetSource<'_>,
+    options: &SearchOptions,
+    sink: &MatchSink,
+    byte_progress: ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (tail, total_len) = source.read_tail(FOOTER_PREFETCH).await?;
+    source.count(&tail, options, &byte_progress);
+
+    if tail.len() < 8 {
+        return Err("file is too small to be Parquet".into());
+    }
+    let footer: [u8; 8] = tail[tail.len() - 8..].try_into()?;
+    let metadata_len = ParquetMetaDataReader::decode_footer(&footer)?;
+    let metadata = if metadata_len + 8 <= tail.len() {
+        ParquetMetaDataReader::decode_metadata(
+            &tail[tail.len() - 8 - metadata_len..tail.len() - 8],
+        )?
+    } else {
+        let offset = total_len
+            .checked_sub(8 + metadata_len as u64)
+            .ok_or("corrupt Parquet footer")?;
+        let bytes = source.read(offset, metadata_len as u64).await?;
+        source.count(&bytes, options, &byte_progress);
+        ParquetMetaDataReader::decode_metadata(&bytes)?
+    };
+
+    // A file with no string columns cannot contain the pattern
+    let Some(projection) = projection(metadata.file_metadata().schema(), &options.columns)? else {
+        return Ok(());
+    };
+    let mut first_row = 0;
+
+    for row_group in metadata.row_groups() {
+        if row_group_may_match(
+            row_group,
+            &projection,
+            &options.pattern,
+            options.case_sensitive,
+        ) {
+            // Column chunks are covered by the object's reservation, taken in listing order
+            let ranges = column_ranges(row_group, &projection);
+            let mut chunks = SparseChunkReader::new(total_len);
+            for (offset, length) in ranges {
+                let bytes = source.read(offset, length).await?;
+                source.count(&bytes, options, &byte_progress);
+                chunks.insert(offset, bytes);
+            }
+
+            let reader = SerializedRowGroupReader::new(
+                Arc::new(chunks),
+                row_group,
+                None,
+                Arc::new(ReaderProperties::builder().build()),
+            )?;
+            let rows = RowIter::from_row_group(Some(projection.clone()), &reader)?;
+            for (i, row) in rows.enumerate() {
+                let row = row?;
+                if row_matches(&row, &options.pattern, options.case_sensitive) {
+                    sink.send(
+                        Position::Row(first_row + i),
+                        &row.to_json_value().to_string(),
+                    )
+                    .await?;
+                }
+            }
+        }
+        first_row += row_group.num_rows() as usize;
+    }
+
+    Ok(())
+}
+
+/// Fetches `length` bytes of an object starting at `offset` with a ranged GET,
+/// failing if the object no longer has `etag`.
+async fn get_range(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    etag: Option<&str>,
+    offset: u64,
+    length: u64,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if length == 0 {
+        return Ok(Bytes::new());
+    }
+    let resp = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(format!("bytes={}-{}", offset, offset + length - 1))
+        .set_if_match(etag.map(str::to_string))
+        .send()
+        .await?;
+    Ok(resp.body.collect().await?.into_bytes())
+}
+
+/**
+    Converts a line found by the scanner into the text to print, applying any
+    log format query.
+*/
+fn line_output(line: &[u8], options: &SearchOptions) -> Option<String> {
+    apply_query(
+        options.query.as_ref(),
+        String::from_utf8_lossy(line).into_owned(),
+    )
+}
+
+/**
+    Applies an optional log format query to a matching line.
+
+    Returns the text to print, or `None` if the line is filtered out.
+*/
+fn apply_query(query: Option<&FormatQuery>, line: String) -> Option<String> {
+    match query {
+        Some(query) => query.apply(&line),
+        None => Some(line),
     }
-    Ok(matches)
 }
 
 /**
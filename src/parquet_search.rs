@@ -0,0 +1,369 @@
+//! Row-by-row searching of Parquet files.
+//!
+//! Only the footer and the column chunks of the projected columns are fetched,
+//! using ranged reads. This module holds the format-specific pieces: choosing the
+//! projection, deciding from row-group statistics whether a group can be skipped,
+//! and serving the fetched byte ranges to the Parquet reader.
+
+use crate::line_matches;
+use bytes::{Buf, Bytes};
+use parquet::basic::{ConvertedType, LogicalType, Type as PhysicalType};
+use parquet::errors::{ParquetError, Result};
+use parquet::file::metadata::RowGroupMetaData;
+use parquet::file::reader::{ChunkReader, Length};
+use parquet::record::{Field, Row};
+use parquet::schema::types::Type;
+use std::sync::Arc;
+
+/// Number of bytes requested from the end of the object in the first ranged read.
+///
+/// Large enough that the footer of most files arrives in a single request.
+pub const FOOTER_PREFETCH: u64 = 64 * 1024;
+
+/// A [`ChunkReader`] backed by the byte ranges of a file that have been fetched.
+///
+/// Reads outside of the fetched ranges fail rather than block on I/O.
+#[derive(Debug, Default)]
+pub struct SparseChunkReader {
+    len: u64,
+    chunks: Vec<(u64, Bytes)>,
+}
+
+impl SparseChunkReader {
+    /// Creates an empty reader for a file of `len` bytes.
+    pub fn new(len: u64) -> Self {
+        SparseChunkReader {
+            len,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Adds a fetched range starting at `offset`.
+    pub fn insert(&mut self, offset: u64, bytes: Bytes) {
+        self.chunks.push((offset, bytes));
+    }
+
+    fn find(&self, start: u64, length: usize) -> Result<Bytes> {
+        self.chunks
+            .iter()
+            .find(|(offset, bytes)| {
+                start >= *offset && start + length as u64 <= *offset + bytes.len() as u64
+            })
+            .map(|(offset, bytes)| {
+                let begin = (start - offset) as usize;
+                bytes.slice(begin..begin + length)
+            })
+            .ok_or_else(|| {
+                ParquetError::General(format!(
+                    "bytes {start}..{} were not fetched",
+                    start + length as u64
+                ))
+            })
+    }
+}
+
+impl Length for SparseChunkReader {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl ChunkReader for SparseChunkReader {
+    type T = bytes::buf::Reader<Bytes>;
+
+    fn get_read(&self, start: u64) -> Result<Self::T> {
+        let (offset, bytes) = self
+            .chunks
+            .iter()
+            .find(|(offset, bytes)| start >= *offset && start < *offset + bytes.len() as u64)
+            .ok_or_else(|| ParquetError::General(format!("byte {start} was not fetched")))?;
+        Ok(bytes.slice((start - offset) as usize..).reader())
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> Result<Bytes> {
+        self.find(start, length)
+    }
+}
+
+/// Returns true if the field is a primitive column holding strings.
+pub fn is_string_field(field: &Type) -> bool {
+    if !field.is_primitive() || field.get_physical_type() != PhysicalType::BYTE_ARRAY {
+        return false;
+    }
+    let info = field.get_basic_info();
+    matches!(
+        info.logical_type(),
+        Some(LogicalType::String | LogicalType::Enum | LogicalType::Json)
+    ) || matches!(
+        info.converted_type(),
+        ConvertedType::UTF8 | ConvertedType::ENUM | ConvertedType::JSON
+    )
+}
+
+/**
+    Builds the projection of top-level columns to read.
+
+    # Arguments
+
+    * `schema` - The root schema of the file.
+    * `columns` - Column names to read; if empty, all string columns are read.
+
+    # Returns
+
+    A schema containing only the projected columns, `None` if no columns are
+    named and the file has no string columns to search, or an error if a named
+    column does not exist.
+*/
+pub fn projection(schema: &Type, columns: &[String]) -> std::result::Result<Option<Type>, String> {
+    let fields: Vec<Arc<Type>> = if columns.is_empty() {
+        schema
+            .get_fields()
+            .iter()
+            .filter(|field| is_string_field(field))
+            .cloned()
+            .collect()
+    } else {
+        columns
+            .iter()
+            .map(|name| {
+                schema
+                    .get_fields()
+                    .iter()
+                    .find(|field| field.name() == name)
+                    .cloned()
+                    .ok_or_else(|| format!("no such column: {name}"))
+            })
+            .collect::<std::result::Result<_, _>>()?
+    };
+
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    Type::group_type_builder(schema.name())
+        .with_fields(fields)
+        .build()
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the `(offset, length)` byte ranges of the projected column chunks,
+/// with adjacent ranges merged so they can be fetched together.
+pub fn column_ranges(row_group: &RowGroupMetaData, projection: &Type) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = row_group
+        .columns()
+        .iter()
+        .filter(|column| is_projected(column.column_path().parts(), projection))
+        .map(|column| column.byte_range())
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (offset, length) in ranges {
+        match merged.last_mut() {
+            Some((last_offset, last_length)) if *last_offset + *last_length >= offset => {
+                *last_length = (*last_length).max(offset + length - *last_offset);
+            }
+            _ => merged.push((offset, length)),
+        }
+    }
+    merged
+}
+
+fn is_projected(path: &[String], projection: &Type) -> bool {
+    path.first().is_some_and(|top| {
+        projection
+            .get_fields()
+            .iter()
+            .any(|field| field.name() == top)
+    })
+}
+
+/**
+    Decides from column statistics whether any row in the group could match.
+
+    A projected column rules itself out when it is entirely null, or when it is a
+    string column whose exact minimum and maximum are the same value and that
+    value does not contain the pattern. The group is skipped only if every
+    projected column is ruled out.
+*/
+pub fn row_group_may_match(
+    row_group: &RowGroupMetaData,
+    projection: &Type,
+    pattern: &str,
+    case_sensitive: bool,
+) -> bool {
+    if row_group.num_rows() == 0 {
+        return false;
+    }
+
+    projection.get_fields().iter().any(|field| {
+        if !field.is_primitive() {
+            return true;
+        }
+        let Some(column) = row_group
+            .columns()
+            .iter()
+            .find(|column| column.column_path().parts() == [field.name().to_string()])
+        else {
+            return true;
+        };
+        let Some(stats) = column.statistics() else {
+            return true;
+        };
+
+        if stats.null_count_opt() == Some(row_group.num_rows() as u64) {
+            return false;
+        }
+
+        if is_string_field(field) && stats.min_is_exact() && stats.max_is_exact() {
+            if let (Some(min), Some(max)) = (stats.min_bytes_opt(), stats.max_bytes_opt()) {
+                if min == max {
+                    return line_matches(&String::from_utf8_lossy(min), pattern, case_sensitive);
+                }
+            }
+        }
+        true
+    })
+}
+
+/// Returns true if any column value of the row contains the pattern.
+///
+/// String values are matched as-is; other values by their display form.
+pub fn row_matches(row: &Row, pattern: &str, case_sensitive: bool) -> bool {
+    row.get_column_iter().any(|(_, field)| match field {
+        Field::Null => false,
+        Field::Str(value) => line_matches(value, pattern, case_sensitive),
+        other => line_matches(&other.to_string(), pattern, case_sensitive),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::metadata::ParquetMetaDataReader;
+    use parquet::file::properties::{ReaderProperties, WriterProperties};
+    use parquet::file::reader::RowGroupReader;
+    use parquet::file::serialized_reader::SerializedRowGroupReader;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::record::reader::RowIter;
+    use parquet::schema::parser::parse_message_type;
+
+    fn write_file() -> Vec<u8> {
+        let schema = Arc::new(
+            parse_message_type(
+                "message test { required int64 id; required binary level (UTF8); required binary msg (UTF8); }",
+            )
+            .unwrap(),
+        );
+        let mut buf = Vec::new();
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(&mut buf, schema, props).unwrap();
+
+        for (levels, msgs) in [
+            (["INFO", "INFO"], ["started", "ready"]),
+            (["INFO", "ERROR"], ["working", "disk full"]),
+        ] {
+            let mut row_group = writer.next_row_group().unwrap();
+            let mut column = row_group.next_column().unwrap().unwrap();
+            column
+                .typed::<Int64Type>()
+                .write_batch(&[1, 2], None, None)
+                .unwrap();
+            column.close().unwrap();
+            for values in [levels, msgs] {
+                let values: Vec<ByteArray> = values.iter().map(|v| (*v).into()).collect();
+                let mut column = row_group.next_column().unwrap().unwrap();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)
+                    .unwrap();
+                column.close().unwrap();
+            }
+            row_group.close().unwrap();
+        }
+        writer.close().unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_search_fetched_ranges() {
+        let file = Bytes::from(write_file());
+        let len = file.len();
+        let footer: [u8; 8] = file[len - 8..].try_into().unwrap();
+        let metadata_len = ParquetMetaDataReader::decode_footer(&footer).unwrap();
+        let metadata =
+            ParquetMetaDataReader::decode_metadata(&file[len - 8 - metadata_len..len - 8]).unwrap();
+
+        let schema = metadata.file_metadata().schema();
+        let projection = projection(schema, &[]).unwrap().unwrap();
+        assert_eq!(projection.get_fields().len(), 2);
+
+        let mut matches = Vec::new();
+        for row_group in metadata.row_groups() {
+            if !row_group_may_match(row_group, &projection, "error", false) {
+                continue;
+            }
+            let mut reader = SparseChunkReader::new(len as u64);
+            for (offset, length) in column_ranges(row_group, &projection) {
+                reader.insert(
+                    offset,
+                    file.slice(offset as usize..(offset + length) as usize),
+                );
+            }
+            let reader = SerializedRowGroupReader::new(
+                Arc::new(reader),
+                row_group,
+                None,
+                Arc::new(ReaderProperties::builder().build()),
+            )
+            .unwrap();
+            let rows =
+                RowIter::from_row_group(Some(projection.clone()), &reader as &dyn RowGroupReader)
+                    .unwrap();
+            for row in rows {
+                let row = row.unwrap();
+                if row_matches(&row, "error", false) {
+                    matches.push(row.to_json_value().to_string());
+                }
+            }
+        }
+
+        assert_eq!(matches, vec![r#"{"level":"ERROR","msg":"disk full"}"#]);
+    }
+
+    #[test]
+    fn test_statistics_skip_constant_columns() {
+        let file = write_file();
+        let len = file.len();
+        let footer: [u8; 8] = file[len - 8..].try_into().unwrap();
+        let metadata_len = ParquetMetaDataReader::decode_footer(&footer).unwrap();
+        let metadata =
+            ParquetMetaDataReader::decode_metadata(&file[len - 8 - metadata_len..len - 8]).unwrap();
+        let schema = metadata.file_metadata().schema();
+        let level = projection(schema, &["level".to_string()]).unwrap().unwrap();
+
+        // The first row group only contains INFO
+        assert!(!row_group_may_match(
+            metadata.row_group(0),
+            &level,
+            "ERROR",
+            true
+        ));
+        assert!(row_group_may_match(
+            metadata.row_group(1),
+            &level,
+            "ERROR",
+            true
+        ));
+        assert!(projection(schema, &["nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_nothing_to_search_without_string_columns() {
+        let schema = parse_message_type("message m { required int64 id; }").unwrap();
+        assert_eq!(projection(&schema, &[]), Ok(None));
+        assert!(projection(&schema, &["id".to_string()]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,171 @@
+//! Resuming object downloads that break mid-body.
+//!
+//! A [`ResumingReader`] wraps a response body and counts the bytes read from
+//! it. When a read fails, it reopens the body from that offset (for S3, with a
+//! ranged GET) and carries on, so the reader above it sees one uninterrupted
+//! stream and no bytes are read twice.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// A pending reopen of the body at an offset.
+pub type Reopen<R> = Pin<Box<dyn Future<Output = io::Result<R>> + Send>>;
+
+enum State<R> {
+    Reading(R),
+    Reopening(Reopen<R>),
+}
+
+/// A reader that reopens its source after read errors and continues where it stopped.
+pub struct ResumingReader<R, F> {
+    state: State<R>,
+    reopen: F,
+    offset: u64,
+    retries: u32,
+    /// Failures since bytes were last read
+    failures: u32,
+}
+
+impl<R, F> ResumingReader<R, F>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(u64) -> Reopen<R> + Unpin,
+{
+    /**
+        Wraps `inner`, which starts at `offset` of the source.
+
+        After a read error, `reopen` is called with the offset of the first
+        byte not yet read. Up to `retries` failures in a row are retried; any
+        bytes read reset the count.
+    */
+    pub fn new(inner: R, offset: u64, retries: u32, reopen: F) -> Self {
+        ResumingReader {
+            state: State::Reading(inner),
+            reopen,
+            offset,
+            retries,
+            failures: 0,
+        }
+    }
+}
+
+impl<R, F> AsyncRead for ResumingReader<R, F>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(u64) -> Reopen<R> + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let error = match &mut this.state {
+                State::Reading(inner) => {
+                    let filled = buf.filled().len();
+                    match Pin::new(inner).poll_read(cx, buf) {
+                        Poll::Ready(Ok(())) => {
+                            let read = buf.filled().len() - filled;
+                            if read > 0 {
+                                this.offset += read as u64;
+                                this.failures = 0;
+                            }
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Err(e)) => e,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                State::Reopening(reopen) => match reopen.as_mut().poll(cx) {
+                    Poll::Ready(Ok(inner)) => {
+                        this.state = State::Reading(inner);
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => e,
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+            if this.failures >= this.retries {
+                return Poll::Ready(Err(error));
+            }
+            this.failures += 1;
+            this.state = State::Reopening((this.reopen)(this.offset));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// Reads `data` from an offset, failing after `fail_after` bytes.
+    struct Flaky {
+        data: &'static [u8],
+        offset: usize,
+        fail_after: usize,
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.fail_after == 0 {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let end = (self.offset + self.fail_after.min(3)).min(self.data.len());
+            buf.put_slice(&self.data[self.offset..end]);
+            self.fail_after -= end - self.offset;
+            self.offset = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const DATA: &[u8] = b"first line\nsecond line\nthird line\n";
+
+    fn flaky(offset: u64) -> Reopen<Flaky> {
+        Box::pin(async move {
+            Ok(Flaky {
+                data: DATA,
+                offset: offset as usize,
+                fail_after: 7,
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resumes_without_repeating_bytes() {
+        let inner = Flaky {
+            data: DATA,
+            offset: 0,
+            fail_after: 5,
+        };
+        let mut reader = ResumingReader::new(inner, 0, 1, flaky);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, DATA);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        let inner = Flaky {
+            data: DATA,
+            offset: 0,
+            fail_after: 5,
+        };
+        let reopen = |_| -> Reopen<Flaky> {
+            Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) })
+        };
+        let mut reader = ResumingReader::new(inner, 0, 2, reopen);
+        let mut out = Vec::new();
+        let error = reader.read_to_end(&mut out).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(out, &DATA[..5]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Roles assumed for the buckets and prefixes being searched.
+//!
+//! `--target-role` maps an `s3://bucket/prefix` to an IAM role, so one search
+//! can read buckets in several accounts, each with credentials for its account.
+
+use crate::S3Location;
+use std::str::FromStr;
+
+/// A role to assume for targets at or under a location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetRole {
+    pub location: S3Location,
+    pub role_arn: String,
+}
+
+impl FromStr for TargetRole {
+    type Err = String;
+
+    /**
+        Parses an `s3://bucket/prefix=ROLE_ARN` mapping.
+
+        The mapping is split before the role's `arn:`, so prefixes may contain
+        `=`, as Hive-style partitions do.
+
+        # Examples
+
+        ```
+        use s3grep::roles::TargetRole;
+        let role: TargetRole = "s3://logs/dt=2024-01-01/=arn:aws:iam::111122223333:role/reader"
+            .parse()
+            .unwrap();
+        assert_eq!(role.location.bucket, "logs");
+        assert_eq!(role.location.prefix, "dt=2024-01-01/");
+        assert_eq!(role.role_arn, "arn:aws:iam::111122223333:role/reader");
+        assert!("s3://logs=reader".parse::<TargetRole>().is_err());
+        ```
+    */
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let (location, role) = mapping
+            .split_once("=arn:")
+            .ok_or_else(|| format!("'{mapping}' is not s3://bucket/prefix=ROLE_ARN"))?;
+        Ok(TargetRole {
+            location: location.parse()?,
+            role_arn: format!("arn:{role}"),
+        })
+    }
+}
+
+/**
+    Returns the role mapped to a target, if any.
+
+    A mapping applies to targets in its bucket whose prefix starts with its
+    own; when several do, the one with the longest prefix wins.
+
+    # Examples
+
+    ```
+    use s3grep::roles::{role_for, TargetRole};
+    let roles: Vec<TargetRole> = vec![
+        "s3://logs=arn:aws:iam::111122223333:role/all".parse().unwrap(),
+        "s3://logs/prod/=arn:aws:iam::444455556666:role/prod".parse().unwrap(),
+    ];
+    let role = |url: &str| role_for(&roles, &url.parse().unwrap());
+    assert_eq!(role("s3://logs/prod/web/"), Some("arn:aws:iam::444455556666:role/prod"));
+    assert_eq!(role("s3://logs/dev/"), Some("arn:aws:iam::111122223333:role/all"));
+    assert_eq!(role("s3://other/prod/"), None);
+    ```
+*/
+pub fn role_for<'a>(roles: &'a [TargetRole], target: &S3Location) -> Option<&'a str> {
+    roles
+        .iter()
+        .filter(|role| {
+            role.location.bucket == target.bucket
+                && target.prefix.starts_with(&role.location.prefix)
+        })
+        .max_by_key(|role| role.location.prefix.len())
+        .map(|role| role.role_arn.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping_needs_location_and_role() {
+        assert!("arn:aws:iam::111122223333:role/reader"
+            .parse::<TargetRole>()
+            .is_err());
+        assert!("logs=arn:aws:iam::111122223333:role/reader"
+            .parse::<TargetRole>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_broader_target_does_not_use_narrower_mapping() {
+        let roles = vec!["s3://logs/prod/=arn:aws:iam::444455556666:role/prod"
+            .parse()
+            .unwrap()];
+        assert_eq!(role_for(&roles, &"s3://logs/".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,613 @@
+//! Buffer-at-a-time line scanning.
+//!
+//! Rather than splitting every buffer into lines and testing each one, the
+//! scanner searches the whole buffer for the pattern and only looks for the
+//! line boundaries around each hit. Lines are handed out as byte slices of the
+//! buffer, so nothing is copied or converted to UTF-8 unless it matches.
+
+use memchr::memmem::Finder;
+use memchr::{memchr, memchr2, memchr_iter, memrchr};
+use std::fmt;
+use std::str::FromStr;
+
+/// Size of the read buffer for each object or range stream, and so of the
+/// chunks fed to a scanner.
+pub const READ_BUFFER: usize = 64 * 1024;
+
+/// A compiled search pattern.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// Case-sensitive byte search.
+    Exact(Box<Finder<'static>>),
+    /// Case-insensitive search for an ASCII pattern, stored lowercased.
+    AsciiCaseless(Vec<u8>),
+    /// Case-insensitive search for a non-ASCII pattern, stored lowercased.
+    ///
+    /// Unicode case folding can change the length of the text, so this falls
+    /// back to lowercasing each line, as [`crate::line_matches`] does.
+    Unicode(String),
+}
+
+impl Matcher {
+    /**
+        Compiles a pattern.
+
+        # Arguments
+
+        * `pattern` - The pattern to search for.
+        * `case_sensitive` - If true, the search is case sensitive.
+
+        # Examples
+
+        ```
+        use s3grep::scan::Matcher;
+        let matcher = Matcher::new("error", false);
+        assert!(matcher.is_match(b"Disk ERROR"));
+        assert!(!Matcher::new("error", true).is_match(b"Disk ERROR"));
+        ```
+    */
+    pub fn new(pattern: &str, case_sensitive: bool) -> Self {
+        if case_sensitive {
+            Matcher::Exact(Box::new(Finder::new(pattern.as_bytes()).into_owned()))
+        } else if pattern.is_ascii() {
+            Matcher::AsciiCaseless(pattern.to_ascii_lowercase().into_bytes())
+        } else {
+            Matcher::Unicode(pattern.to_lowercase())
+        }
+    }
+
+    /// Returns the offset of the first hit in `haystack`.
+    ///
+    /// For [`Matcher::Unicode`] this is the start of the first matching line.
+    pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+        match self {
+            Matcher::Exact(finder) => finder.find(haystack),
+            Matcher::AsciiCaseless(needle) => find_ascii_caseless(haystack, needle),
+            Matcher::Unicode(needle) => {
+                let mut start = 0;
+                while start < haystack.len() {
+                    let end =
+                        memchr(b'\n', &haystack[start..]).map_or(haystack.len(), |i| start + i);
+                    if unicode_match(&haystack[start..end], needle) {
+                        return Some(start);
+                    }
+                    start = end + 1;
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns true if the line contains the pattern.
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        match self {
+            Matcher::Unicode(needle) => unicode_match(line, needle),
+            _ => self.find(line).is_some(),
+        }
+    }
+}
+
+fn find_ascii_caseless(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let Some(&first) = needle.first() else {
+        return Some(0);
+    };
+    let mut start = 0;
+    while let Some(i) = memchr2(first, first.to_ascii_uppercase(), &haystack[start..]) {
+        let at = start + i;
+        match haystack.get(at..at + needle.len()) {
+            Some(window) if window.eq_ignore_ascii_case(needle) => return Some(at),
+            Some(_) => start = at + 1,
+            None => return None,
+        }
+    }
+    None
+}
+
+fn unicode_match(line: &[u8], needle: &str) -> bool {
+    String::from_utf8_lossy(line)
+        .to_lowercase()
+        .contains(needle)
+}
+
+/// What to do with lines longer than the maximum line length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongLines {
+    /// Search and print only the start of the line
+    Truncate,
+    /// Ignore the line
+    Skip,
+}
+
+impl FromStr for LongLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(LongLines::Truncate),
+            "skip" => Ok(LongLines::Skip),
+            _ => Err(format!(
+                "unknown long line action '{s}' (expected truncate or skip)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LongLines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LongLines::Truncate => "truncate",
+            LongLines::Skip => "skip",
+        })
+    }
+}
+
+/// Streaming line scanner that reports matching lines and their line numbers.
+///
+/// Bytes are fed in arbitrary chunks. Only a line that spans two chunks is
+/// copied, and at most the maximum line length of it is kept; everything else
+/// is searched in place.
+#[derive(Debug)]
+pub struct LineScanner {
+    matcher: Matcher,
+    partial: Vec<u8>,
+    /// True if the partial line has grown past `max_line_length`
+    overflow: bool,
+    lines: usize,
+    max_line_length: usize,
+    long_line_action: LongLines,
+    long_lines: usize,
+}
+
+impl LineScanner {
+    /// Creates a scanner for the given matcher.
+    pub fn new(matcher: Matcher) -> Self {
+        LineScanner {
+            matcher,
+            partial: Vec::new(),
+            overflow: false,
+            lines: 0,
+            max_line_length: usize::MAX,
+            long_line_action: LongLines::Truncate,
+            long_lines: 0,
+        }
+    }
+
+    /**
+        Limits the bytes of each line that are kept and searched.
+
+        Lines longer than `max` are truncated to their first `max` bytes or
+        skipped, depending on `action`. A `max` of 0 means no limit.
+
+        # Examples
+
+        ```
+        use s3grep::scan::{LineScanner, LongLines, Matcher};
+        let mut scanner = LineScanner::new(Matcher::new("a", true))
+            .with_max_line_length(4, LongLines::Truncate);
+        let mut matches = Vec::new();
+        scanner.feed(b"abcdefg\nxxxxxa\nab\n", |n, line| matches.push((n, line.to_vec())));
+        assert_eq!(matches, vec![(1, b"abcd".to_vec()), (3, b"ab".to_vec())]);
+        assert_eq!(scanner.long_lines(), 2);
+        ```
+    */
+    pub fn with_max_line_length(mut self, max: usize, action: LongLines) -> Self {
+        self.max_line_length = if max == 0 { usize::MAX } else { max };
+        self.long_line_action = action;
+        self
+    }
+
+    /// Number of complete lines seen so far.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Number of lines seen so far that were longer than the maximum line length.
+    pub fn long_lines(&self) -> usize {
+        self.long_lines
+    }
+
+    /// Returns true if the data fed so far ends partway through a line.
+    pub fn in_line(&self) -> bool {
+        !self.partial.is_empty()
+    }
+
+    /**
+        Feeds a chunk of bytes, calling `on_match` with the (1-based) line number
+        and contents of each complete matching line, without its newline.
+
+        # Examples
+
+        ```
+        use s3grep::scan::{LineScanner, Matcher};
+        let mut scanner = LineScanner::new(Matcher::new("err", true));
+        let mut matches = Vec::new();
+        for chunk in [&b"ok\nerr"[..], b"or\nok\n", b"err"] {
+            scanner.feed(chunk, |n, line| matches.push((n, line.to_vec())));
+        }
+        scanner.finish(|n, line| matches.push((n, line.to_vec())));
+        assert_eq!(matches, vec![(2, b"error".to_vec()), (4, b"err".to_vec())]);
+        ```
+    */
+    pub fn feed<F: FnMut(usize, &[u8])>(&mut self, bytes: &[u8], mut on_match: F) {
+        let mut bytes = bytes;
+
+        // Complete a line carried over from the previous chunk
+        if !self.partial.is_empty() {
+            let Some(newline) = memchr(b'\n', bytes) else {
+                self.extend_partial(bytes);
+                return;
+            };
+            self.extend_partial(&bytes[..newline]);
+            self.lines += 1;
+            self.check_partial(&mut on_match);
+            bytes = &bytes[newline + 1..];
+        }
+
+        // Search the complete lines in place and keep the trailing partial line
+        let complete = memrchr(b'\n', bytes).map_or(0, |i| i + 1);
+        let (region, rest) = bytes.split_at(complete);
+        self.extend_partial(rest);
+
+        let mut counted = 0;
+        while counted < region.len() {
+            let Some(hit) = self.matcher.find(&region[counted..]) else {
+                break;
+            };
+            let hit = counted + hit;
+            let start = memrchr(b'\n', &region[counted..hit]).map_or(counted, |i| counted + i + 1);
+            let end = hit + memchr(b'\n', &region[hit..]).unwrap_or(region.len() - hit);
+
+            self.count_lines(&region[counted..start]);
+            self.lines += 1;
+            let mut line = Some(&region[start..end]);
+            if end - start > self.max_line_length {
+                self.long_lines += 1;
+                line = match self.long_line_action {
+                    LongLines::Truncate => line.map(|line| &line[..self.max_line_length]),
+                    LongLines::Skip => None,
+                };
+            }
+            // A hit can run across a newline if the pattern contains one, or lie
+            // past the end of a truncated line
+            if let Some(line) = line.filter(|line| self.matcher.is_match(line)) {
+                on_match(self.lines, line);
+            }
+            counted = end + 1;
+        }
+        if counted < region.len() {
+            self.count_lines(&region[counted..]);
+        }
+    }
+
+    /// Reports the final line if the data did not end with a newline.
+    pub fn finish<F: FnMut(usize, &[u8])>(&mut self, mut on_match: F) {
+        if self.partial.is_empty() {
+            return;
+        }
+        self.lines += 1;
+        self.check_partial(&mut on_match);
+    }
+
+    /// Counts the complete lines in `bytes`, which starts at the start of a line.
+    fn count_lines(&mut self, bytes: &[u8]) {
+        if self.max_line_length == usize::MAX {
+            self.lines += memchr_iter(b'\n', bytes).count();
+            return;
+        }
+        let mut start = 0;
+        for newline in memchr_iter(b'\n', bytes) {
+            if newline - start > self.max_line_length {
+                self.long_lines += 1;
+            }
+            self.lines += 1;
+            start = newline + 1;
+        }
+    }
+
+    /// Appends to the partial line, keeping at most the maximum line length.
+    fn extend_partial(&mut self, bytes: &[u8]) {
+        let room = self.max_line_length - self.partial.len();
+        if bytes.len() > room {
+            self.overflow = true;
+        }
+        self.partial
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    /// Tests the completed partial line and resets it.
+    fn check_partial<F: FnMut(usize, &[u8])>(&mut self, on_match: &mut F) {
+        let skip = self.overflow && self.long_line_action == LongLines::Skip;
+        if self.overflow {
+            self.long_lines += 1;
+        }
+        if !skip && self.matcher.is_match(&self.partial) {
+            on_match(self.lines, &self.partial);
+        }
+        self.partial.clear();
+        self.overflow = false;
+    }
+}
+
+/**
+    Scans the lines that start within `start..end` of a larger text, so the
+    text can be searched as ranges fetched concurrently.
+
+    Bytes are fed from [`RangeScanner::offset`], which starts at the byte before
+    the range to tell whether a line starts at `start`. The tail of a line begun
+    in an earlier range is skipped, and the range's last line is read past `end`
+    until it ends. Line numbers count from the range's first line, so adding the
+    line counts of the ranges before it gives the line number in the whole text.
+
+    # Examples
+
+    ```
+    use s3grep::scan::{LineScanner, Matcher, RangeScanner};
+    let text = b"one\ntwo three\nfour\n";
+    // The range 5..16 starts inside "two three", so its only line is "four"
+    let mut range = RangeScanner::new(LineScanner::new(Matcher::new("o", true)), 5, 16);
+    assert_eq!(range.offset(), 4);
+    let mut matches = Vec::new();
+    range.feed(&text[4..16], |n, line| matches.push((n, line.to_vec())));
+    assert!(!range.is_done());
+    // "four" runs past the end of the range, so it is read on to its newline
+    range.feed(&text[16..], |n, line| matches.push((n, line.to_vec())));
+    assert!(range.is_done());
+    assert_eq!(matches, vec![(1, b"four".to_vec())]);
+    ```
+*/
+#[derive(Debug)]
+pub struct RangeScanner {
+    scanner: LineScanner,
+    /// Offset of the next byte to be fed
+    offset: u64,
+    end: u64,
+    /// True once the first line starting in the range has been reached
+    started: bool,
+    /// True once every line starting in the range is complete
+    done: bool,
+    is_binary: bool,
+}
+
+impl RangeScanner {
+    /// Creates a scanner for the lines starting within `start..end`.
+    pub fn new(scanner: LineScanner, start: u64, end: u64) -> Self {
+        RangeScanner {
+            scanner,
+            offset: start.saturating_sub(1),
+            end,
+            started: start == 0,
+            done: false,
+            is_binary: false,
+        }
+    }
+
+    /// Offset in the text of the next byte to feed.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns true once every line starting in the range is complete, so no more bytes are needed.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns true if the range's lines contain a NUL byte.
+    pub fn is_binary(&self) -> bool {
+        self.is_binary
+    }
+
+    /// Number of complete lines starting in the range seen so far.
+    pub fn lines(&self) -> usize {
+        self.scanner.lines()
+    }
+
+    /// Number of those lines that were longer than the maximum line length.
+    pub fn long_lines(&self) -> usize {
+        self.scanner.long_lines()
+    }
+
+    /**
+        Feeds the next bytes of the text, calling `on_match` like
+        [`LineScanner::feed`] does.
+
+        Returns how many of the bytes belong to the range's lines; bytes fed
+        once the range is done are ignored.
+    */
+    pub fn feed<F: FnMut(usize, &[u8])>(&mut self, bytes: &[u8], mut on_match: F) -> usize {
+        if self.done {
+            return 0;
+        }
+        let length = bytes.len();
+        let mut chunk = bytes;
+
+        if !self.started {
+            // Skip the rest of a line that belongs to the previous range
+            match memchr(b'\n', chunk) {
+                Some(newline) => {
+                    chunk = &chunk[newline + 1..];
+                    self.started = true;
+                }
+                None => chunk = &[],
+            }
+        }
+
+        // Only lines starting before `end` belong to this range; past it, read
+        // just far enough to finish the line in progress
+        let chunk_start = self.offset + (length - chunk.len()) as u64;
+        let in_range = self.end.saturating_sub(chunk_start).min(chunk.len() as u64) as usize;
+        let mut fed = &chunk[..in_range];
+        self.scanner.feed(fed, &mut on_match);
+        if in_range < chunk.len() && self.scanner.in_line() {
+            let tail = &chunk[in_range..];
+            let finish = memchr(b'\n', tail).map_or(tail.len(), |i| i + 1);
+            self.scanner.feed(&tail[..finish], &mut on_match);
+            fed = &chunk[..in_range + finish];
+        }
+
+        if !self.is_binary && memchr(0, fed).is_some() {
+            self.is_binary = true;
+        }
+        self.offset += length as u64;
+        self.done = self.offset >= self.end && !self.scanner.in_line();
+        fed.len()
+    }
+
+    /// Reports the range's last line at the end of the text, if it did not end with a newline.
+    pub fn finish<F: FnMut(usize, &[u8])>(&mut self, on_match: F) {
+        self.scanner.finish(on_match);
+        self.done = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line_matches;
+
+    fn scan(data: &[u8], pattern: &str, case_sensitive: bool, chunk: usize) -> Vec<usize> {
+        let mut scanner = LineScanner::new(Matcher::new(pattern, case_sensitive));
+        let mut found = Vec::new();
+        for bytes in data.chunks(chunk) {
+            scanner.feed(bytes, |n, _| found.push(n));
+        }
+        scanner.finish(|n, _| found.push(n));
+        found
+    }
+
+    #[test]
+    fn test_scanner_matches_line_by_line_search() {
+        let data = "Error one\nfine\n\nERROR two and error three\nnothing\nÉCHEC total\nerr\nor\nlast error";
+        for (pattern, case_sensitive) in [
+            ("error", false),
+            ("Error", true),
+            ("", true),
+            ("échec", false),
+            ("or\ner", true),
+            ("z", false),
+        ] {
+            let expected: Vec<usize> = data
+                .split('\n')
+                .enumerate()
+                .filter(|(_, line)| line_matches(line, pattern, case_sensitive))
+                .map(|(i, _)| i + 1)
+                .collect();
+            for chunk in [1, 2, 7, data.len()] {
+                assert_eq!(
+                    scan(data.as_bytes(), pattern, case_sensitive, chunk),
+                    expected,
+                    "pattern {pattern:?}, chunk {chunk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_lines_across_chunks() {
+        let data = b"short a\nlong line with a\nanother long a line\na";
+        for (action, expected) in [
+            (
+                LongLines::Truncate,
+                vec![(1, &b"short a"[..]), (3, b"another l"), (4, b"a")],
+            ),
+            (LongLines::Skip, vec![(1, &b"short a"[..]), (4, b"a")]),
+        ] {
+            for chunk in [1, 5, data.len()] {
+                let mut scanner =
+                    LineScanner::new(Matcher::new("a", true)).with_max_line_length(9, action);
+                let mut found = Vec::new();
+                for bytes in data.chunks(chunk) {
+                    scanner.feed(bytes, |n, line| found.push((n, line.to_vec())));
+                }
+                scanner.finish(|n, line| found.push((n, line.to_vec())));
+                let expected: Vec<_> = expected.iter().map(|(n, l)| (*n, l.to_vec())).collect();
+                assert_eq!(found, expected, "{action:?}, chunk {chunk}");
+                assert_eq!(scanner.long_lines(), 2);
+                assert_eq!(scanner.lines(), 4);
+            }
+        }
+    }
+
+    /// Searches `data` as ranges of `range` bytes, feeding each `chunk` bytes at
+    /// a time, and numbers the matches as a sequential scan would.
+    fn scan_ranges(
+        data: &[u8],
+        pattern: &str,
+        range: usize,
+        chunk: usize,
+    ) -> Vec<(usize, Vec<u8>)> {
+        let mut found = Vec::new();
+        let mut first_line = 0;
+        for start in (0..data.len()).step_by(range) {
+            let end = (start + range).min(data.len());
+            let mut scanner = RangeScanner::new(
+                LineScanner::new(Matcher::new(pattern, true)),
+                start as u64,
+                end as u64,
+            );
+            let mut matches = Vec::new();
+            while !scanner.is_done() {
+                let offset = scanner.offset() as usize;
+                if offset == data.len() {
+                    scanner.finish(|n, line| matches.push((n, line.to_vec())));
+                    break;
+                }
+                let bytes = &data[offset..(offset + chunk).min(data.len())];
+                scanner.feed(bytes, |n, line| matches.push((n, line.to_vec())));
+            }
+            found.extend(matches.into_iter().map(|(n, line)| (first_line + n, line)));
+            first_line += scanner.lines();
+        }
+        found
+    }
+
+    #[test]
+    fn test_ranges_match_sequential_scan() {
+        // Lines split across range boundaries, a line spanning several ranges,
+        // empty lines and no final newline
+        let data =
+            b"error one\nok\n\nan error that spans a good few ranges of the text\nerror\nok\nerror";
+        let expected: Vec<(usize, Vec<u8>)> = data
+            .split(|&b| b == b'\n')
+            .enumerate()
+            .filter(|(_, line)| memchr::memmem::find(line, b"error").is_some())
+            .map(|(i, line)| (i + 1, line.to_vec()))
+            .collect();
+        for range in 1..=data.len() {
+            for chunk in [1, 3, data.len()] {
+                assert_eq!(
+                    scan_ranges(data, "error", range, chunk),
+                    expected,
+                    "range {range}, chunk {chunk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_stops_after_its_last_line() {
+        let data = b"aaaa\nbbbbbbbb\ncccc\n";
+        let mut scanner = RangeScanner::new(LineScanner::new(Matcher::new("b", true)), 3, 7);
+        scanner.feed(&data[2..7], |_, _| {});
+        assert!(!scanner.is_done());
+        // Only the bytes up to the end of "bbbbbbbb" are used
+        assert_eq!(scanner.feed(&data[7..], |_, _| {}), 7);
+        assert!(scanner.is_done());
+        assert_eq!(scanner.lines(), 1);
+
+        // A range inside a line that started earlier has no lines of its own
+        let mut scanner = RangeScanner::new(LineScanner::new(Matcher::new("b", true)), 7, 10);
+        scanner.feed(&data[6..10], |_, _| panic!("no lines start in 7..10"));
+        assert!(scanner.is_done());
+        assert_eq!(scanner.lines(), 0);
+    }
+
+    #[test]
+    fn test_line_count() {
+        let mut scanner = LineScanner::new(Matcher::new("x", true));
+        scanner.feed(b"a\nb\nc", |_, _| {});
+        assert_eq!(scanner.lines(), 2);
+        assert!(scanner.in_line());
+        scanner.finish(|_, _| {});
+        assert_eq!(scanner.lines(), 3);
+    }
+}
@@ -0,0 +1,133 @@
+//! SQL expressions for S3 Select push-down.
+//!
+//! The search pattern is translated into a `LIKE` predicate over one or more
+//! columns so that S3 only returns the records that can match.
+
+/// Column holding the whole line when an object is read as single-column CSV.
+pub const LINE_COLUMN: &str = "s._1";
+
+/// Quotes a column name for use in an S3 Select expression, e.g. `s."user id"`.
+pub fn column_reference(name: &str) -> String {
+    format!("s.\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Builds a `'%pattern%'` literal, escaping `LIKE` wildcards with `\` and
+/// doubling single quotes.
+fn like_literal(pattern: &str) -> String {
+    let mut literal = String::with_capacity(pattern.len() + 4);
+    literal.push_str("'%");
+    for c in pattern.chars() {
+        match c {
+            '\\' | '%' | '_' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            '\'' => literal.push_str("''"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push_str("%'");
+    literal
+}
+
+/**
+    Builds an S3 Select expression returning records where any of the given
+    columns contains the pattern.
+
+    # Arguments
+
+    * `columns` - Column references, such as [`LINE_COLUMN`] or the output of
+      [`column_reference`].
+    * `pattern` - The pattern to search for.
+    * `case_sensitive` - If true, the search is case sensitive.
+
+    # Examples
+
+    ```
+    use s3grep::select::{expression, LINE_COLUMN};
+    assert_eq!(
+        expression(&[LINE_COLUMN.to_string()], "ERROR", true),
+        r"SELECT * FROM S3Object s WHERE s._1 LIKE '%ERROR%' ESCAPE '\'"
+    );
+    ```
+*/
+pub fn expression(columns: &[String], pattern: &str, case_sensitive: bool) -> String {
+    projected_expression(&[], columns, pattern, case_sensitive)
+}
+
+/**
+    Builds an S3 Select expression like [`expression`] that returns only the
+    `projection` columns of each record, or all of them if it is empty.
+
+    # Examples
+
+    ```
+    use s3grep::select::{column_reference, projected_expression};
+    let level = column_reference("level");
+    assert_eq!(
+        projected_expression(&[level.clone()], &[format!("CAST({level} AS STRING)")], "ERROR", true),
+        r#"SELECT s."level" FROM S3Object s WHERE CAST(s."level" AS STRING) LIKE '%ERROR%' ESCAPE '\'"#
+    );
+    ```
+*/
+pub fn projected_expression(
+    projection: &[String],
+    columns: &[String],
+    pattern: &str,
+    case_sensitive: bool,
+) -> String {
+    let predicates: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            if case_sensitive {
+                format!("{column} LIKE {} ESCAPE '\\'", like_literal(pattern))
+            } else {
+                format!(
+                    "LOWER({column}) LIKE {} ESCAPE '\\'",
+                    like_literal(&pattern.to_lowercase())
+                )
+            }
+        })
+        .collect();
+    let projection = if projection.is_empty() {
+        "*".to_string()
+    } else {
+        projection.join(", ")
+    };
+    format!(
+        "SELECT {projection} FROM S3Object s WHERE {}",
+        predicates.join(" OR ")
+    )
+}
+
+/// Extracts the line from a JSON record returned for a single-column CSV read.
+pub fn line_from_record(record: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(record).ok()?;
+    value.get("_1")?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression_escaping() {
+        assert_eq!(
+            expression(&[column_reference("say \"hi\"")], "50%_it's", false),
+            r#"SELECT * FROM S3Object s WHERE LOWER(s."say ""hi""") LIKE '%50\%\_it''s%' ESCAPE '\'"#
+        );
+        assert_eq!(
+            expression(&["s.a".to_string(), "s.b".to_string()], "x", true),
+            r"SELECT * FROM S3Object s WHERE s.a LIKE '%x%' ESCAPE '\' OR s.b LIKE '%x%' ESCAPE '\'"
+        );
+    }
+
+    #[test]
+    fn test_line_from_record() {
+        assert_eq!(
+            line_from_record(br#"{"_1":"ERROR: \"quoted\""}"#).as_deref(),
+            Some(r#"ERROR: "quoted""#)
+        );
+        assert_eq!(line_from_record(b"not json"), None);
+    }
+}
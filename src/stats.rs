@@ -0,0 +1,612 @@
+//! Summary statistics printed with `--stats`.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// S3 Standard price of PUT, COPY, POST and LIST requests in us-east-1, in USD per 1000.
+pub const LIST_REQUEST_PRICE: f64 = 0.005;
+
+/// S3 Standard price of GET, SELECT and all other requests in us-east-1, in USD per 1000.
+pub const GET_REQUEST_PRICE: f64 = 0.0004;
+
+/// What happened to the objects of a search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ObjectStats {
+    /// Objects returned by the listing
+    pub listed: u64,
+    /// Listed objects left out before searching (directories, objects done before a resume)
+    pub filtered: u64,
+    /// Objects searched to the end
+    pub searched: u64,
+    /// Objects the index or sidecars showed cannot match
+    pub skipped: u64,
+    /// Objects whose search failed
+    pub errored: u64,
+}
+
+/// Counts updated by concurrent searches.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub listed: AtomicU64,
+    pub filtered: AtomicU64,
+    pub searched: AtomicU64,
+    pub skipped: AtomicU64,
+    pub errored: AtomicU64,
+    /// Bytes received from S3, before decompression
+    pub downloaded: AtomicU64,
+    /// Lines of text scanned
+    pub lines: AtomicU64,
+}
+
+impl Counters {
+    /// Adds `n` to a counter.
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The object counts so far.
+    pub fn objects(&self) -> ObjectStats {
+        ObjectStats {
+            listed: self.listed.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            searched: self.searched.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            errored: self.errored.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A reader that adds the number of bytes read to a counter.
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    counters: Arc<Counters>,
+}
+
+impl<R> CountingReader<R> {
+    /// Counts the bytes read from `inner` as downloaded.
+    pub fn downloaded(inner: R, counters: Arc<Counters>) -> Self {
+        CountingReader { inner, counters }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - filled) as u64;
+        Counters::add(&self.counters.downloaded, read);
+        result
+    }
+}
+
+/// Numbers of requests made, by operation.
+#[derive(Debug, Default)]
+pub struct RequestCounts {
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl RequestCounts {
+    /// Counts a request, including each retry, since S3 bills every attempt.
+    pub fn record(&self, operation: &str) {
+        *self
+            .counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(operation.to_string())
+            .or_default() += 1;
+    }
+
+    /// The counts so far.
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/**
+    Estimates the S3 Standard request charges, in USD, for requests by operation.
+
+    Data transfer and S3 Select scan charges are not included.
+
+    # Examples
+
+    ```
+    use s3grep::stats::request_cost;
+    use std::collections::BTreeMap;
+    let requests = BTreeMap::from([
+        ("ListObjectsV2".to_string(), 1000),
+        ("GetObject".to_string(), 10_000),
+    ]);
+    assert!((request_cost(&requests) - 0.009).abs() < 1e-9);
+    ```
+*/
+pub fn request_cost(requests: &BTreeMap<String, u64>) -> f64 {
+    requests
+        .iter()
+        .map(|(operation, &count)| {
+            let price = match operation.as_str() {
+                "ListObjectsV2"
+                | "ListObjects"
+                | "ListBuckets"
+                | "PutObject"
+                | "CopyObject"
+                | "CreateMultipartUpload"
+                | "UploadPart"
+                | "CompleteMultipartUpload" => LIST_REQUEST_PRICE,
+                _ => GET_REQUEST_PRICE,
+            };
+            count as f64 * price / 1000.0
+        })
+        .sum()
+}
+
+/// S3 price of data transfer out to the internet, in USD per GB (first 10 TB a month).
+///
+/// Transfer to compute in the bucket's region is free.
+pub const TRANSFER_PRICE_PER_GB: f64 = 0.09;
+
+/// Download rate assumed for each concurrent search when estimating durations, in bytes per second.
+pub const TASK_THROUGHPUT: f64 = 40.0 * 1024.0 * 1024.0;
+
+/// Time to first byte assumed for each request when estimating durations.
+pub const REQUEST_LATENCY: Duration = Duration::from_millis(30);
+
+/// What a search would read, estimated from the listing alone for `--dry-run`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRun {
+    /// Objects that would be searched
+    pub objects: u64,
+    /// Their total size
+    pub bytes: u64,
+    /// Objects and bytes by storage class
+    pub storage_classes: BTreeMap<String, (u64, u64)>,
+    /// Requests made for the listing, and estimated for the search, by operation
+    pub requests: BTreeMap<String, u64>,
+    /// Number of objects searched at once
+    pub concurrency: usize,
+}
+
+impl DryRun {
+    /// Adds an object that would be searched with `gets` GET requests.
+    pub fn add(&mut self, storage_class: &str, size: u64, gets: u64) {
+        self.objects += 1;
+        self.bytes += size;
+        let class = self
+            .storage_classes
+            .entry(storage_class.to_string())
+            .or_default();
+        class.0 += 1;
+        class.1 += size;
+        *self.requests.entry("GetObject".to_string()).or_default() += gets;
+    }
+
+    /// Cost of transferring every object out to the internet, in USD.
+    pub fn transfer_cost(&self) -> f64 {
+        self.bytes as f64 / 1e9 * TRANSFER_PRICE_PER_GB
+    }
+
+    /**
+        Estimates how long the search would take, assuming every concurrent
+        search downloads at [`TASK_THROUGHPUT`] after [`REQUEST_LATENCY`].
+
+        # Examples
+
+        ```
+        use s3grep::stats::DryRun;
+        use std::time::Duration;
+        let mut dry_run = DryRun { concurrency: 2, ..Default::default() };
+        for _ in 0..4 {
+            dry_run.add("STANDARD", 40 * 1024 * 1024, 1);
+        }
+        assert_eq!(dry_run.estimated_duration(), Duration::from_millis(2060));
+        ```
+    */
+    pub fn estimated_duration(&self) -> Duration {
+        let gets = self.requests.get("GetObject").copied().unwrap_or_default();
+        let concurrency = self.concurrency.max(1) as f64;
+        let seconds = (self.bytes as f64 / TASK_THROUGHPUT
+            + gets as f64 * REQUEST_LATENCY.as_secs_f64())
+            / concurrency;
+        Duration::from_secs_f64(seconds)
+    }
+
+    /// The estimates as a JSON object.
+    pub fn to_json(&self) -> Value {
+        let storage_classes: BTreeMap<_, _> = self
+            .storage_classes
+            .iter()
+            .map(|(class, (objects, bytes))| (class, json!({"objects": objects, "bytes": bytes})))
+            .collect();
+        json!({
+            "objects": self.objects,
+            "bytes": self.bytes,
+            "storage_classes": storage_classes,
+            "requests": self.requests,
+            "estimated_request_cost_usd": request_cost(&self.requests),
+            "estimated_transfer_cost_usd": self.transfer_cost(),
+            "concurrency": self.concurrency,
+            "estimated_duration_secs": self.estimated_duration().as_secs_f64(),
+        })
+    }
+}
+
+impl fmt::Display for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Objects:        {} ({})",
+            self.objects,
+            format_size(self.bytes)
+        )?;
+        for (class, (objects, bytes)) in &self.storage_classes {
+            writeln!(f, "  {class:<22}{objects} ({})", format_size(*bytes))?;
+        }
+        let requests: Vec<_> = self
+            .requests
+            .iter()
+            .map(|(operation, count)| format!("{count} {operation}"))
+            .collect();
+        writeln!(f, "Requests:       {}", requests.join(", "))?;
+        writeln!(
+            f,
+            "Request cost:   ~${:.6} (S3 Standard, us-east-1)",
+            request_cost(&self.requests)
+        )?;
+        writeln!(
+            f,
+            "Transfer cost:  ~${:.2} if downloaded to the internet, free within the region",
+            self.transfer_cost()
+        )?;
+        write!(
+            f,
+            "Duration:       ~{:.0}s at concurrency {}",
+            self.estimated_duration().as_secs_f64().ceil(),
+            self.concurrency
+        )
+    }
+}
+
+/// Statistics collected over a search.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// What happened to the listed objects
+    pub objects: ObjectStats,
+    /// Bytes received from S3, before decompression
+    pub bytes_downloaded: u64,
+    /// Bytes searched, after decompression
+    pub bytes_scanned: u64,
+    /// Lines of text scanned
+    pub lines_scanned: u64,
+    /// Lines printed
+    pub matches: u64,
+    /// Wall time of the search
+    pub elapsed: Duration,
+    /// Requests made, by operation
+    pub requests: BTreeMap<String, u64>,
+    /// Most memory reserved for buffers at any one time
+    pub peak_buffered: u64,
+    /// The `--max-memory` budget, if any
+    pub memory_budget: Option<u64>,
+    /// Peak resident set size of the process, where the platform reports it
+    pub peak_rss: Option<u64>,
+    /// Lines longer than `--max-line-length`
+    pub long_lines: u64,
+}
+
+impl Stats {
+    /// Bytes scanned per second of wall time.
+    pub fn throughput(&self) -> f64 {
+        self.bytes_scanned as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The statistics as a JSON object.
+    pub fn to_json(&self) -> Value {
+        let objects = &self.objects;
+        json!({
+            "objects": {
+                "listed": objects.listed,
+                "filtered": objects.filtered,
+                "searched": objects.searched,
+                "skipped": objects.skipped,
+                "errored": objects.errored,
+            },
+            "bytes_downloaded": self.bytes_downloaded,
+            "bytes_scanned": self.bytes_scanned,
+            "lines_scanned": self.lines_scanned,
+            "matches": self.matches,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "throughput_bytes_per_sec": self.throughput(),
+            "requests": self.requests,
+            "estimated_request_cost_usd": request_cost(&self.requests),
+            "peak_buffered": self.peak_buffered,
+            "memory_budget": self.memory_budget,
+            "peak_rss": self.peak_rss,
+            "long_lines": self.long_lines,
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let objects = &self.objects;
+        writeln!(
+            f,
+            "Objects:        {} listed, {} filtered, {} searched, {} skipped, {} errored",
+            objects.listed, objects.filtered, objects.searched, objects.skipped, objects.errored
+        )?;
+        writeln!(f, "Downloaded:     {}", format_size(self.bytes_downloaded))?;
+        writeln!(
+            f,
+            "Scanned:        {} ({} lines)",
+            format_size(self.bytes_scanned),
+            self.lines_scanned
+        )?;
+        writeln!(f, "Matches:        {}", self.matches)?;
+        writeln!(
+            f,
+            "Elapsed:        {:.2}s ({}/s)",
+            self.elapsed.as_secs_f64(),
+            format_size(self.throughput() as u64)
+        )?;
+        let requests: Vec<_> = self
+            .requests
+            .iter()
+            .map(|(operation, count)| format!("{count} {operation}"))
+            .collect();
+        if !requests.is_empty() {
+            writeln!(f, "Requests:       {}", requests.join(", "))?;
+        }
+        writeln!(
+            f,
+            "Request cost:   ~${:.6} (S3 Standard, us-east-1)",
+            request_cost(&self.requests)
+        )?;
+        write!(f, "Peak buffered:  {}", format_size(self.peak_buffered))?;
+        if let Some(budget) = self.memory_budget {
+            write!(f, " (budget {})", format_size(budget))?;
+        }
+        writeln!(f)?;
+        if let Some(rss) = self.peak_rss {
+            writeln!(f, "Peak RSS:       {}", format_size(rss))?;
+        }
+        write!(f, "Long lines:     {}", self.long_lines)
+    }
+}
+
+/**
+    Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+
+    # Examples
+
+    ```
+    use s3grep::stats::format_size;
+    assert_eq!(format_size(512), "512 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(64 * 1024 * 1024), "64.0 MiB");
+    ```
+*/
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/**
+    Returns the `p`th percentile (0 to 100) of sorted values, by nearest rank.
+
+    # Examples
+
+    ```
+    use s3grep::stats::percentile;
+    let values: Vec<u32> = (1..=100).collect();
+    assert_eq!(percentile(&values, 50.0), Some(50));
+    assert_eq!(percentile(&values, 99.0), Some(99));
+    assert_eq!(percentile(&values, 100.0), Some(100));
+    assert_eq!(percentile::<u32>(&[], 50.0), None);
+    ```
+*/
+pub fn percentile<T: Copy>(sorted: &[T], p: f64) -> Option<T> {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+}
+
+/// Request latencies by operation, summarized as percentiles.
+#[derive(Debug, Default)]
+pub struct Latencies {
+    by_operation: Mutex<BTreeMap<String, Vec<Duration>>>,
+}
+
+impl Latencies {
+    /// Records the latency of one request.
+    pub fn record(&self, operation: &str, latency: Duration) {
+        self.by_operation
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(operation.to_string())
+            .or_default()
+            .push(latency);
+    }
+}
+
+impl fmt::Display for Latencies {
+    /// Formats a line per operation, e.g.
+    /// `GetObject             12 requests  p50 35ms  p90 80ms  p99 120ms  max 130ms`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut by_operation = self.by_operation.lock().unwrap_or_else(|e| e.into_inner());
+        for (i, (operation, latencies)) in by_operation.iter_mut().enumerate() {
+            latencies.sort_unstable();
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{operation:<20} {:>6} requests", latencies.len())?;
+            for (label, p) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("max", 100.0)] {
+                if let Some(latency) = percentile(latencies, p) {
+                    write!(f, "  {label} {}ms", latency.as_millis())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the peak resident set size of this process, on Linux.
+pub fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn stats() -> Stats {
+        Stats {
+            objects: ObjectStats {
+                listed: 12,
+                filtered: 1,
+                searched: 9,
+                skipped: 1,
+                errored: 1,
+            },
+            bytes_downloaded: 1024,
+            bytes_scanned: 4096,
+            lines_scanned: 100,
+            matches: 7,
+            elapsed: Duration::from_secs(2),
+            requests: BTreeMap::from([
+                ("GetObject".to_string(), 10),
+                ("ListObjectsV2".to_string(), 1),
+            ]),
+            peak_buffered: 2 * 1024 * 1024,
+            memory_budget: Some(8 * 1024 * 1024),
+            peak_rss: None,
+            long_lines: 3,
+        }
+    }
+
+    #[test]
+    fn test_stats_text() {
+        assert_eq!(
+            stats().to_string(),
+            "Objects:        12 listed, 1 filtered, 9 searched, 1 skipped, 1 errored\n\
+             Downloaded:     1.0 KiB\n\
+             Scanned:        4.0 KiB (100 lines)\n\
+             Matches:        7\n\
+             Elapsed:        2.00s (2.0 KiB/s)\n\
+             Requests:       10 GetObject, 1 ListObjectsV2\n\
+             Request cost:   ~$0.000009 (S3 Standard, us-east-1)\n\
+             Peak buffered:  2.0 MiB (budget 8.0 MiB)\n\
+             Long lines:     3"
+        );
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let json = stats().to_json();
+        assert_eq!(json["objects"]["searched"], 9);
+        assert_eq!(json["throughput_bytes_per_sec"], 2048.0);
+        assert_eq!(json["requests"]["GetObject"], 10);
+        assert_eq!(json["memory_budget"], 8 * 1024 * 1024);
+        assert!(json["peak_rss"].is_null());
+        let cost = json["estimated_request_cost_usd"].as_f64().unwrap();
+        assert!((cost - 0.000009).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn test_counting_reader_counts_downloaded_bytes() {
+        let counters = Arc::new(Counters::default());
+        let mut reader = CountingReader::downloaded(&b"hello world"[..], counters.clone());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(counters.downloaded.load(Ordering::Relaxed), 11);
+
+        Counters::add(&counters.searched, 2);
+        Counters::add(&counters.errored, 1);
+        assert_eq!(
+            counters.objects(),
+            ObjectStats {
+                searched: 2,
+                errored: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_requests_are_counted_and_priced_by_operation() {
+        let counts = RequestCounts::default();
+        for operation in [
+            "GetObject",
+            "GetObject",
+            "ListObjectsV2",
+            "SelectObjectContent",
+        ] {
+            counts.record(operation);
+        }
+        let requests = counts.snapshot();
+        assert_eq!(requests["GetObject"], 2);
+        // LIST is priced like PUT; SELECT like GET
+        let expected = (3.0 * GET_REQUEST_PRICE + LIST_REQUEST_PRICE) / 1000.0;
+        assert!((request_cost(&requests) - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_dry_run_totals_by_storage_class() {
+        let mut dry_run = DryRun {
+            concurrency: 4,
+            ..Default::default()
+        };
+        dry_run.add("STANDARD", 1_000_000_000, 1);
+        dry_run.add("STANDARD", 500, 2);
+        dry_run.add("GLACIER_IR", 1_000_000_000, 1);
+        assert_eq!(dry_run.objects, 3);
+        assert_eq!(dry_run.storage_classes["STANDARD"], (2, 1_000_000_500));
+        assert_eq!(dry_run.requests["GetObject"], 4);
+        assert!((dry_run.transfer_cost() - 0.18).abs() < 1e-6);
+        assert_eq!(
+            dry_run.to_json()["storage_classes"]["GLACIER_IR"]["objects"],
+            1
+        );
+    }
+
+    #[test]
+    fn test_latencies_by_operation() {
+        let latencies = Latencies::default();
+        assert_eq!(latencies.to_string(), "");
+        for ms in [30, 10, 20] {
+            latencies.record("GetObject", Duration::from_millis(ms));
+        }
+        latencies.record("HeadObject", Duration::from_millis(5));
+        assert_eq!(
+            latencies.to_string(),
+            "GetObject                 3 requests  p50 20ms  p90 30ms  p99 30ms  max 30ms\n\
+             HeadObject                1 requests  p50 5ms  p90 5ms  p99 5ms  max 5ms"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Searches with `--cache-dir` against a local S3 stand-in.
+//!
+//! The listing holds a Parquet object and a text object above
+//! `--range-threshold`, both small enough for the cache.
+
+mod stand_in;
+
+use stand_in::{listing_objects, parquet_file, s3grep, start, Request, Requests};
+
+const TEXT: &str = "fine\nERROR in text\nfine\n";
+
+fn parquet() -> Vec<u8> {
+    parquet_file(&["fine", "ERROR in parquet"])
+}
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    match request.key() {
+        "" => {
+            let prefix = request.param("prefix").unwrap_or_default();
+            let parquet_key = format!("{prefix}a.parquet");
+            let text_key = format!("{prefix}b.log");
+            let objects = [
+                (parquet_key.as_str(), parquet().len()),
+                (text_key.as_str(), TEXT.len()),
+            ];
+            (
+                "200 OK",
+                listing_objects(request.bucket(), &prefix, &objects),
+            )
+        }
+        key if key.ends_with(".parquet") => ("200 OK", parquet()),
+        _ => ("200 OK", TEXT.as_bytes().to_vec()),
+    }
+}
+
+fn object_gets(requests: &Requests) -> usize {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.method == "GET" && !request.key().is_empty())
+        .count()
+}
+
+#[tokio::test]
+async fn test_ranged_and_parquet_objects_are_cached() {
+    let (endpoint, requests) = start(respond).await;
+    let dir = std::env::temp_dir().join(format!("s3grep-cache-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let args = [
+        "-p",
+        "ERROR",
+        "-b",
+        "bucket",
+        "-z",
+        "logs/",
+        "--sort",
+        "path",
+        "--range-threshold",
+        "8",
+        "--range-size",
+        "8",
+        "--cache-dir",
+        dir.to_str().unwrap(),
+    ];
+
+    let expected = "s3://bucket/logs/a.parquet:row=1:{\"msg\":\"ERROR in parquet\"}\n\
+                    s3://bucket/logs/b.log:ERROR in text\n";
+    let first = s3grep(&endpoint, &args).await;
+    assert_eq!(String::from_utf8_lossy(&first.stdout), expected);
+    // Each object is fetched with a single GET so that it can be stored
+    assert_eq!(object_gets(&requests), 2);
+    assert!(requests.lock().unwrap().iter().all(|r| r.range.is_none()));
+
+    let second = s3grep(&endpoint, &args).await;
+    assert_eq!(String::from_utf8_lossy(&second.stdout), expected);
+    assert_eq!(object_gets(&requests), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,39 @@
+//! Dry runs against a local S3 stand-in, which lists one object.
+
+mod stand_in;
+
+use stand_in::{listing, s3grep, start, Request};
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    let prefix = request.param("prefix").unwrap_or_default();
+    let key = format!("{prefix}object.log");
+    ("200 OK", listing(request.bucket(), &prefix, &key, 2048))
+}
+
+#[tokio::test]
+async fn test_dry_run_prints_json_estimate() {
+    let (endpoint, requests) = start(respond).await;
+    let output = s3grep(
+        &endpoint,
+        &[
+            "-p",
+            "ERROR",
+            "-b",
+            "bucket",
+            "-z",
+            "logs/",
+            "--dry-run",
+            "--stats-format",
+            "json",
+        ],
+    )
+    .await;
+    assert_eq!(output.status.code(), Some(0));
+    let estimate: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(estimate["objects"], 1);
+    assert_eq!(estimate["bytes"], 2048);
+    assert_eq!(estimate["requests"]["GetObject"], 1);
+    assert_eq!(estimate["requests"]["ListObjectsV2"], 1);
+    // Nothing is downloaded
+    assert!(requests.lock().unwrap().iter().all(|r| r.key().is_empty()));
+}
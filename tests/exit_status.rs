@@ -0,0 +1,129 @@
+//! Exit statuses of searches against a local S3 stand-in.
+//!
+//! Keys under `denied/` cannot be read, keys under `clean/` never match, and
+//! listing `broken/` always fails with an internal error. `two/` lists two objects.
+
+mod stand_in;
+
+use stand_in::{error, listing, listing_objects, s3grep, start, Request, Requests};
+use std::process::Output;
+
+const BODY: &str = "fine\nERROR found\n";
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    let key = request.key();
+    if key.is_empty() {
+        if request.param("prefix").as_deref() == Some("broken/") {
+            return ("500 Internal Server Error", error("InternalError"));
+        }
+        let prefix = request.param("prefix").unwrap_or_default();
+        if prefix == "two/" {
+            let objects = [("two/a.log", BODY.len()), ("two/b.log", BODY.len())];
+            return (
+                "200 OK",
+                listing_objects(request.bucket(), &prefix, &objects),
+            );
+        }
+        let key = format!("{prefix}object.log");
+        return (
+            "200 OK",
+            listing(request.bucket(), &prefix, &key, BODY.len()),
+        );
+    }
+    if key.starts_with("denied/") {
+        ("403 Forbidden", error("AccessDenied"))
+    } else if key.starts_with("clean/") {
+        ("200 OK", b"fine\n".to_vec())
+    } else {
+        ("200 OK", BODY.as_bytes().to_vec())
+    }
+}
+
+async fn search(endpoint: &str, prefix: &str, args: &[&str]) -> Output {
+    let mut all = vec!["-p", "ERROR", "-b", "bucket", "-z", prefix];
+    all.extend_from_slice(args);
+    s3grep(endpoint, &all).await
+}
+
+#[tokio::test]
+async fn test_match_and_no_match() {
+    let (endpoint, _) = start(respond).await;
+    let output = search(&endpoint, "logs/", &[]).await;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "s3://bucket/logs/object.log:ERROR found\n"
+    );
+
+    let output = search(&endpoint, "clean/", &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+}
+
+#[tokio::test]
+async fn test_object_errors_are_summarized() {
+    let (endpoint, _) = start(respond).await;
+    let output = search(&endpoint, "denied/", &[]).await;
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("s3grep: 1 error (1 AccessDenied)"),
+        "{stderr}"
+    );
+
+    // Errors are still reported, but only matches decide the status
+    let output = search(&endpoint, "denied/", &["--no-fail-on-errors"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("s3grep: 1 error (1 AccessDenied)"),
+        "{stderr}"
+    );
+}
+
+fn listings(requests: &Requests) -> usize {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.method == "GET" && request.key().is_empty())
+        .count()
+}
+
+#[tokio::test]
+async fn test_listing_failure_is_retried_then_reported() {
+    let (endpoint, requests) = start(respond).await;
+    let output = search(&endpoint, "broken/", &[]).await;
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error listing objects"), "{stderr}");
+    // The page is retried LIST_RETRIES (3) times before the listing fails
+    assert_eq!(listings(&requests), 4);
+}
+
+#[tokio::test]
+async fn test_max_objects_stops_the_search() {
+    let (endpoint, requests) = start(respond).await;
+    let output = search(&endpoint, "two/", &["--max-objects", "1"]).await;
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "s3://bucket/two/a.log:ERROR found\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "s3grep: budget exhausted (--max-objects 1 reached) after searching 1 objects"
+        ),
+        "{stderr}"
+    );
+    let gets: Vec<String> = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.method == "GET" && !request.key().is_empty())
+        .map(|request| request.key().to_string())
+        .collect();
+    assert_eq!(gets, ["two/a.log"]);
+}
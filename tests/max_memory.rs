@@ -0,0 +1,87 @@
+//! Searches with `--max-memory` against a local S3 stand-in.
+//!
+//! The listing holds a Parquet object with one matching row, followed by a
+//! text object with more matching lines than a sorted search buffers per object.
+
+mod stand_in;
+
+use stand_in::{listing_objects, parquet_file, s3grep, start, Request};
+
+const LINES: usize = 5000;
+
+fn text_file() -> Vec<u8> {
+    (0..LINES)
+        .map(|i| format!("ERROR {i}\n"))
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    match request.key() {
+        "" => {
+            let prefix = request.param("prefix").unwrap_or_default();
+            let parquet = format!("{prefix}a.parquet");
+            let text = format!("{prefix}b.log");
+            let objects = [
+                (parquet.as_str(), parquet_file(&["ERROR in parquet"]).len()),
+                (text.as_str(), text_file().len()),
+            ];
+            (
+                "200 OK",
+                listing_objects(request.bucket(), &prefix, &objects),
+            )
+        }
+        key if key.ends_with(".parquet") => ("200 OK", parquet_file(&["ERROR in parquet"])),
+        _ => ("200 OK", text_file()),
+    }
+}
+
+#[tokio::test]
+async fn test_sorted_parquet_ahead_of_text_stays_within_budget() {
+    let (endpoint, _) = start(respond).await;
+    let output = s3grep(
+        &endpoint,
+        &[
+            "-p",
+            "ERROR",
+            "-b",
+            "bucket",
+            "-z",
+            "logs/",
+            "--sort",
+            "path",
+            "--max-memory",
+            "1KiB",
+            "--timeout",
+            "30s",
+            "--stats-format",
+            "json",
+        ],
+    )
+    .await;
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), LINES + 1);
+    assert!(lines[0].starts_with("s3://bucket/logs/a.parquet:row=0:"));
+    assert!(lines[0].contains("ERROR in parquet"));
+    assert_eq!(
+        lines[LINES],
+        format!("s3://bucket/logs/b.log:ERROR {}", LINES - 1)
+    );
+
+    // Every search, the Parquet one included, stays within the budget
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stats: serde_json::Value = stderr
+        .lines()
+        .find_map(|line| serde_json::from_str(line).ok())
+        .unwrap();
+    assert_eq!(stats["memory_budget"], 1024);
+    let peak = stats["peak_buffered"].as_u64().unwrap();
+    assert!(peak > 0 && peak <= 1024, "peak buffered {peak}");
+}
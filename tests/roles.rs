@@ -0,0 +1,94 @@
+//! Searches of several targets with `--role-arn` and `--target-role` against
+//! a local S3 and STS stand-in.
+//!
+//! AssumeRole hands out credentials whose access key is the upper-cased role
+//! name, so each S3 request shows which role it was signed with.
+
+mod stand_in;
+
+use stand_in::{listing, s3grep, start, Request};
+use std::collections::BTreeMap;
+
+const BODY: &str = "ERROR found\n";
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    let body = String::from_utf8_lossy(&request.body);
+    if body.starts_with("Action=AssumeRole") {
+        let role = body
+            .split('&')
+            .find_map(|param| param.strip_prefix("RoleArn="))
+            .and_then(|arn| arn.rsplit("%2F").next())
+            .unwrap()
+            .to_uppercase();
+        let response = format!(
+            r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/"><AssumeRoleResult><Credentials><AccessKeyId>{role}</AccessKeyId><SecretAccessKey>secret</SecretAccessKey><SessionToken>token</SessionToken><Expiration>2099-01-01T00:00:00Z</Expiration></Credentials><AssumedRoleUser><Arn>arn:aws:sts::111122223333:assumed-role/{role}/s3grep</Arn><AssumedRoleId>AROA:s3grep</AssumedRoleId></AssumedRoleUser></AssumeRoleResult><ResponseMetadata><RequestId>test</RequestId></ResponseMetadata></AssumeRoleResponse>"#
+        );
+        return ("200 OK", response.into_bytes());
+    }
+    if request.key().is_empty() {
+        let prefix = request.param("prefix").unwrap_or_default();
+        let key = format!("{prefix}object.log");
+        return (
+            "200 OK",
+            listing(request.bucket(), &prefix, &key, BODY.len()),
+        );
+    }
+    ("200 OK", BODY.as_bytes().to_vec())
+}
+
+#[tokio::test]
+async fn test_each_target_uses_its_role() {
+    let (endpoint, requests) = start(respond).await;
+    let output = s3grep(
+        &endpoint,
+        &[
+            "-p",
+            "ERROR",
+            "s3://alpha/",
+            "s3://beta/logs/",
+            "s3://gamma/",
+            "--target-role",
+            "s3://alpha=arn:aws:iam::111111111111:role/alpha",
+            "--target-role",
+            "s3://beta=arn:aws:iam::222222222222:role/wide",
+            "--target-role",
+            "s3://beta/logs/=arn:aws:iam::222222222222:role/beta",
+            "--role-arn",
+            "arn:aws:iam::333333333333:role/default",
+        ],
+    )
+    .await;
+    assert_eq!(output.status.code(), Some(0));
+    let mut lines: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "s3://alpha/object.log:ERROR found",
+            "s3://beta/logs/object.log:ERROR found",
+            "s3://gamma/object.log:ERROR found",
+        ]
+    );
+
+    // Roles are assumed with the base credentials, and each bucket is read
+    // only with the credentials of its own role
+    let mut keys: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for request in requests.lock().unwrap().iter() {
+        let target = if request.body.starts_with(b"Action=AssumeRole") {
+            "sts".to_string()
+        } else {
+            request.bucket().to_string()
+        };
+        let access_keys = keys.entry(target).or_default();
+        if !access_keys.iter().any(|key| key == request.access_key()) {
+            access_keys.push(request.access_key().to_string());
+        }
+    }
+    assert_eq!(keys["sts"], ["test"]);
+    assert_eq!(keys["alpha"], ["ALPHA"]);
+    assert_eq!(keys["beta"], ["BETA"]);
+    assert_eq!(keys["gamma"], ["DEFAULT"]);
+}
@@ -0,0 +1,111 @@
+//! Searches with `--s3-select` against a local S3 stand-in.
+//!
+//! Keys under `select/` are searched by S3 Select, S3 Select rejects keys
+//! under `unsupported/` with `InvalidRequest`, and keys under `denied/` with
+//! `AccessDenied`. Every key can also be downloaded with a GET.
+
+mod stand_in;
+
+use aws_smithy_eventstream::frame::write_message_to;
+use aws_smithy_types::event_stream::{Header, HeaderValue, Message};
+use stand_in::{error, listing, s3grep, start, Request, Requests};
+use std::process::Output;
+
+const BODY: &str = "fine\nERROR from get\n";
+
+fn respond(request: &Request) -> (&'static str, Vec<u8>) {
+    let key = request.key();
+    match request.method.as_str() {
+        "GET" if key.is_empty() => {
+            let prefix = request.param("prefix").unwrap_or_default();
+            let key = format!("{prefix}object.log");
+            (
+                "200 OK",
+                listing(request.bucket(), &prefix, &key, BODY.len()),
+            )
+        }
+        "GET" => ("200 OK", BODY.as_bytes().to_vec()),
+        "POST" if key.starts_with("select/") => ("200 OK", select_events()),
+        "POST" if key.starts_with("unsupported/") => ("400 Bad Request", error("InvalidRequest")),
+        "POST" => ("403 Forbidden", error("AccessDenied")),
+        _ => ("405 Method Not Allowed", Vec::new()),
+    }
+}
+
+/// The event stream of a Select request that matched one line.
+fn select_events() -> Vec<u8> {
+    let event = |event_type: &'static str, payload: &'static str| {
+        Message::new(payload)
+            .add_header(Header::new(
+                ":message-type",
+                HeaderValue::String("event".into()),
+            ))
+            .add_header(Header::new(
+                ":event-type",
+                HeaderValue::String(event_type.into()),
+            ))
+            .add_header(Header::new(
+                ":content-type",
+                HeaderValue::String("application/octet-stream".into()),
+            ))
+    };
+    let mut events = Vec::new();
+    for message in [
+        event("Records", "{\"_1\":\"ERROR from select\"}\n"),
+        event("End", ""),
+    ] {
+        write_message_to(&message, &mut events).unwrap();
+    }
+    events
+}
+
+async fn search(endpoint: &str, prefix: &str) -> Output {
+    s3grep(
+        endpoint,
+        &["-p", "ERROR", "-b", "bucket", "-z", prefix, "--s3-select"],
+    )
+    .await
+}
+
+fn gets(requests: &Requests, key: &str) -> usize {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.method == "GET" && request.key() == key)
+        .count()
+}
+
+#[tokio::test]
+async fn test_select_returns_matches_without_download() {
+    let (endpoint, requests) = start(respond).await;
+    let output = search(&endpoint, "select/").await;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "s3://bucket/select/object.log:ERROR from select\n"
+    );
+    assert_eq!(gets(&requests, "select/object.log"), 0);
+}
+
+#[tokio::test]
+async fn test_unsupported_object_falls_back_to_download() {
+    let (endpoint, requests) = start(respond).await;
+    let output = search(&endpoint, "unsupported/").await;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "s3://bucket/unsupported/object.log:ERROR from get\n"
+    );
+    assert_eq!(gets(&requests, "unsupported/object.log"), 1);
+}
+
+#[tokio::test]
+async fn test_other_select_errors_are_reported() {
+    let (endpoint, requests) = start(respond).await;
+    let output = search(&endpoint, "denied/").await;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("AccessDenied"));
+    assert_eq!(gets(&requests, "denied/object.log"), 0);
+}
@@ -0,0 +1,217 @@
+//! A minimal local S3 stand-in for integration tests.
+//!
+//! It answers plain HTTP/1.1 requests with a response chosen by each test and
+//! records them, so tests can check what s3grep sent and with which credentials.
+//! Ranged GETs are answered with the requested bytes of the chosen body, and
+//! every object has the ETag `"etag"`, as in listings.
+
+#![allow(dead_code)]
+
+use parquet::data_type::{ByteArray, ByteArrayType};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request received by the stand-in.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path and query, e.g. `/bucket?list-type=2&prefix=logs%2F`
+    pub target: String,
+    pub authorization: String,
+    /// The Range header, e.g. `bytes=0-99` or `bytes=-100`
+    pub range: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The bucket of a path-style S3 request.
+    pub fn bucket(&self) -> &str {
+        self.path()
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap()
+    }
+
+    /// The key of a path-style S3 request, empty for bucket requests.
+    pub fn key(&self) -> &str {
+        let path = self.path().trim_start_matches('/');
+        path.split_once('/').map_or("", |(_, key)| key)
+    }
+
+    /// A query parameter, with `%2F` decoded.
+    pub fn param(&self, name: &str) -> Option<String> {
+        let query = self.target.split_once('?')?.1;
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+            .map(|value| value.replace("%2F", "/"))
+    }
+
+    /// The access key the request was signed with.
+    pub fn access_key(&self) -> &str {
+        let credential = self.authorization.split("Credential=").nth(1).unwrap_or("");
+        credential.split('/').next().unwrap()
+    }
+
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap()
+    }
+}
+
+/// Requests received so far.
+pub type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// Chooses the status line and body of the response to a request.
+pub type Respond = fn(&Request) -> (&'static str, Vec<u8>);
+
+/// Starts the stand-in and returns its endpoint URL.
+pub async fn start(respond: Respond) -> (String, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Requests::default();
+    let log = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve(stream, log.clone(), respond));
+        }
+    });
+    (endpoint, requests)
+}
+
+/// Answers the requests of one keep-alive connection.
+async fn serve(stream: TcpStream, requests: Requests, respond: Respond) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        let mut authorization = String::new();
+        let mut range = None;
+        loop {
+            let mut header = String::new();
+            stream.read_line(&mut header).await.unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').unwrap();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = value.trim().to_string();
+            } else if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let mut parts = request_line.split_whitespace();
+        let request = Request {
+            method: parts.next().unwrap().to_string(),
+            target: parts.next().unwrap().to_string(),
+            authorization,
+            range,
+            body,
+        };
+        let (mut status, mut content) = respond(&request);
+        let mut content_range = String::new();
+        if let (Some(range), "200 OK") = (&request.range, status) {
+            let (start, end) = byte_range(range, content.len());
+            content_range = format!("Content-Range: bytes {start}-{end}/{}\r\n", content.len());
+            content = content[start..=end].to_vec();
+            status = "206 Partial Content";
+        }
+        requests.lock().unwrap().push(request);
+        let head = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{content_range}ETag: \"etag\"\r\nx-amz-request-id: test\r\n\r\n",
+            content.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&content).await.unwrap();
+    }
+}
+
+/// The first and last byte of a Range header's range in a body of `len` bytes.
+fn byte_range(range: &str, len: usize) -> (usize, usize) {
+    let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+    match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) => (start, end.min(len - 1)),
+        (Ok(start), Err(_)) => (start, len - 1),
+        (Err(_), Ok(suffix)) => (len.saturating_sub(suffix), len - 1),
+        _ => panic!("invalid range {range}"),
+    }
+}
+
+/// A ListObjectsV2 response listing one object.
+pub fn listing(bucket: &str, prefix: &str, key: &str, size: usize) -> Vec<u8> {
+    listing_objects(bucket, prefix, &[(key, size)])
+}
+
+/// A ListObjectsV2 response listing objects, given as keys and sizes.
+pub fn listing_objects(bucket: &str, prefix: &str, objects: &[(&str, usize)]) -> Vec<u8> {
+    let contents: String = objects
+        .iter()
+        .map(|(key, size)| {
+            format!(
+                r#"<Contents><Key>{key}</Key><Size>{size}</Size><ETag>"etag"</ETag><StorageClass>STANDARD</StorageClass></Contents>"#
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Name>{bucket}</Name><Prefix>{prefix}</Prefix><KeyCount>{}</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"#,
+        objects.len()
+    )
+    .into_bytes()
+}
+
+/// A Parquet file with one row group of strings in the column `msg`.
+pub fn parquet_file(rows: &[&str]) -> Vec<u8> {
+    let schema = parse_message_type("message event { REQUIRED BYTE_ARRAY msg (UTF8); }").unwrap();
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut file = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut file, Arc::new(schema), properties).unwrap();
+    let mut row_group = writer.next_row_group().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    let values: Vec<ByteArray> = rows.iter().map(|&row| ByteArray::from(row)).collect();
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&values, None, None)
+        .unwrap();
+    column.close().unwrap();
+    row_group.close().unwrap();
+    writer.close().unwrap();
+    file
+}
+
+/// An S3 error response.
+pub fn error(code: &str) -> Vec<u8> {
+    format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>").into_bytes()
+}
+
+/// Runs s3grep against the stand-in with test credentials.
+pub async fn s3grep(endpoint: &str, args: &[&str]) -> Output {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_s3grep"))
+        .args(args)
+        .args(["--endpoint-url", endpoint, "--force-path-style"])
+        .args(["--region", "us-east-1", "--retries", "0", "-q"])
+        .env_remove("AWS_ENDPOINT_URL")
+        .env_remove("AWS_PROFILE")
+        .env_remove("AWS_SESSION_TOKEN")
+        .env("AWS_ACCESS_KEY_ID", "test")
+        .env("AWS_SECRET_ACCESS_KEY", "test")
+        .env("AWS_CONFIG_FILE", "/nonexistent")
+        .env("AWS_SHARED_CREDENTIALS_FILE", "/nonexistent")
+        .output()
+        .await
+        .unwrap()
+}